@@ -1,20 +1,22 @@
-// TODO: maybe add also a deriver for BinProtSize?
 use ::proc_macro;
 
 use proc_macro::TokenStream;
-use quote::{format_ident, quote};
+use quote::{format_ident, quote, ToTokens};
 use syn::{
-    parse_quote, DataEnum, DataUnion, DeriveInput, FieldsNamed, FieldsUnnamed, GenericParam,
+    parse_macro_input, parse_quote, DataEnum, DataUnion, DeriveInput, FieldsNamed, FieldsUnnamed,
+    GenericParam,
 };
 
+fn polymorphic_variant_attr(ast: &DeriveInput) -> Option<&syn::Attribute> {
+    ast.attrs.iter().find(|attr| attr.path.is_ident("polymorphic_variant"))
+}
+
 fn has_polymorphic_variant_attr(ast: &DeriveInput) -> bool {
-    let DeriveInput { attrs, .. } = ast;
-    attrs.iter().any(|attr| {
-        attr.path.segments.len() == 1 && attr.path.segments[0].ident == "polymorphic_variant"
-    })
+    polymorphic_variant_attr(ast).is_some()
 }
 
-// The hash function used to get the identifier for polymorphic variants in OCaml
+// The hash function used to get the identifier for polymorphic variants in OCaml,
+// this has to match binprot::hash_variant which is checked in the derive tests.
 fn hash_variant(str: &str) -> i32 {
     let mut accu = std::num::Wrapping(0i64);
     for &v in str.as_bytes().iter() {
@@ -35,74 +37,564 @@ fn variant_int(str: &str) -> i32 {
     (v << 1) | 1
 }
 
-#[proc_macro_derive(BinProtWrite, attributes(polymorphic_variant))]
+// The list of options in the #[binprot(...)] attributes.
+fn binprot_attrs(attrs: &[syn::Attribute]) -> syn::Result<Vec<syn::NestedMeta>> {
+    let mut res = vec![];
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("binprot")) {
+        match attr.parse_meta()? {
+            syn::Meta::List(list) => res.extend(list.nested),
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[binprot(...)]")),
+        }
+    }
+    Ok(res)
+}
+
+fn str_value(nv: &syn::MetaNameValue) -> syn::Result<syn::LitStr> {
+    match &nv.lit {
+        syn::Lit::Str(lit) => Ok(lit.clone()),
+        lit => Err(syn::Error::new_spanned(lit, "expected a string")),
+    }
+}
+
+fn path_value(nv: &syn::MetaNameValue) -> syn::Result<syn::Path> {
+    str_value(nv)?.parse()
+}
+
+// The case conventions supported by rename_all, using the same names as serde.
+#[derive(Clone, Copy)]
+enum RenameRule {
+    Lower,
+    Upper,
+    Pascal,
+    Camel,
+    Snake,
+    ScreamingSnake,
+}
+
+// Applies [f] to the first character of [s]. Case conversions can map a
+// single character to several ones, e.g. 'ß' is uppercased to "SS".
+fn map_first_char<I: Iterator<Item = char>>(s: &str, f: impl FnOnce(char) -> I) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => f(c).chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+impl RenameRule {
+    fn parse(lit: &syn::LitStr) -> syn::Result<Self> {
+        let rule = match lit.value().as_str() {
+            "lowercase" => RenameRule::Lower,
+            "UPPERCASE" => RenameRule::Upper,
+            "PascalCase" => RenameRule::Pascal,
+            "camelCase" => RenameRule::Camel,
+            "snake_case" => RenameRule::Snake,
+            "SCREAMING_SNAKE_CASE" => RenameRule::ScreamingSnake,
+            _ => return Err(syn::Error::new_spanned(lit, "unknown rename_all rule")),
+        };
+        Ok(rule)
+    }
+
+    // Variant identifiers are expected to be in PascalCase.
+    fn apply_to_variant(self, variant: &str) -> String {
+        match self {
+            RenameRule::Lower => variant.to_lowercase(),
+            RenameRule::Upper => variant.to_uppercase(),
+            RenameRule::Pascal => variant.to_string(),
+            RenameRule::Camel => map_first_char(variant, char::to_lowercase),
+            RenameRule::Snake | RenameRule::ScreamingSnake => {
+                let mut snake = String::new();
+                for (i, c) in variant.char_indices() {
+                    if i > 0 && c.is_uppercase() {
+                        snake.push('_')
+                    }
+                    snake.extend(c.to_lowercase())
+                }
+                if let RenameRule::ScreamingSnake = self {
+                    snake = snake.to_uppercase()
+                }
+                snake
+            }
+        }
+    }
+
+    // Field identifiers are expected to be in snake_case.
+    fn apply_to_field(self, field: &str) -> String {
+        match self {
+            RenameRule::Lower | RenameRule::Snake => field.to_string(),
+            RenameRule::Upper | RenameRule::ScreamingSnake => field.to_uppercase(),
+            RenameRule::Pascal | RenameRule::Camel => {
+                let mut res = String::new();
+                for word in field.split('_').filter(|w| !w.is_empty()) {
+                    res.push_str(&map_first_char(word, char::to_uppercase))
+                }
+                if let RenameRule::Camel = self {
+                    res = map_first_char(&res, char::to_lowercase)
+                }
+                res
+            }
+        }
+    }
+}
+
+// Options set on fields via #[binprot(...)] attributes.
+#[derive(Default)]
+struct FieldAttrs {
+    // Skipped fields are not serialized and do not appear in the shape.
+    skip: bool,
+    // Function used to initialize a skipped field when reading, Default::default
+    // is used when not specified.
+    default: Option<syn::Path>,
+    // Module providing the binprot_write, binprot_size, binprot_read (or
+    // binprot_read_borrowed) and binprot_shape functions used instead of the
+    // trait implementations.
+    with: Option<syn::Path>,
+    // The OCaml name of the field, used in the shape.
+    name: Option<syn::LitStr>,
+}
+
+impl FieldAttrs {
+    fn parse(field: &syn::Field) -> syn::Result<Self> {
+        let mut res = FieldAttrs::default();
+        for nested in binprot_attrs(&field.attrs)? {
+            match &nested {
+                syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.is_ident("skip") => {
+                    res.skip = true
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) if nv.path.is_ident("default") => {
+                    res.default = Some(path_value(nv)?)
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) if nv.path.is_ident("with") => {
+                    res.with = Some(path_value(nv)?)
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) if nv.path.is_ident("name") => {
+                    res.name = Some(str_value(nv)?)
+                }
+                nested => return Err(syn::Error::new_spanned(nested, "unknown binprot attribute")),
+            }
+        }
+        if let (false, Some(default)) = (res.skip, &res.default) {
+            return Err(syn::Error::new_spanned(default, "default requires skip"));
+        }
+        if let (true, Some(with)) = (res.skip, &res.with) {
+            return Err(syn::Error::new_spanned(with, "with cannot be used on skipped fields"));
+        }
+        Ok(res)
+    }
+
+    // The attributes are validated by check_attrs before generating code.
+    fn of(field: &syn::Field) -> Self {
+        Self::parse(field).unwrap_or_default()
+    }
+
+    fn default_value(&self) -> proc_macro2::TokenStream {
+        match &self.default {
+            Some(path) => quote! { #path() },
+            None => quote! { std::default::Default::default() },
+        }
+    }
+}
+
+// Options set on the type via #[binprot(...)] attributes.
+#[derive(Default)]
+struct ContainerAttrs {
+    // The shape gets annotated with this uuid, similar to Bin_shape.annotate.
+    uuid: Option<syn::LitStr>,
+    // The shape is a base type with this uuid and the type parameters as
+    // arguments, similar to Bin_shape.basetype.
+    opaque_uuid: Option<syn::LitStr>,
+    // Renames the fields of a struct or the variants of an enum.
+    rename_all: Option<RenameRule>,
+}
+
+impl ContainerAttrs {
+    fn parse(ast: &DeriveInput) -> syn::Result<Self> {
+        let mut res = ContainerAttrs::default();
+        for nested in binprot_attrs(&ast.attrs)? {
+            let nv = match &nested {
+                syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) => nv,
+                nested => return Err(syn::Error::new_spanned(nested, "unknown binprot attribute")),
+            };
+            let lit = match &nv.lit {
+                syn::Lit::Str(lit) => Some(lit.clone()),
+                lit => return Err(syn::Error::new_spanned(lit, "expected a string")),
+            };
+            if nv.path.is_ident("uuid") {
+                res.uuid = lit
+            } else if nv.path.is_ident("opaque_uuid") {
+                res.opaque_uuid = lit
+            } else if nv.path.is_ident("rename_all") {
+                res.rename_all = lit.as_ref().map(RenameRule::parse).transpose()?
+            } else {
+                return Err(syn::Error::new_spanned(nv, "unknown binprot attribute"));
+            }
+        }
+        if let (Some(_), Some(opaque_uuid)) = (&res.uuid, &res.opaque_uuid) {
+            let msg = "uuid and opaque_uuid cannot be used together";
+            return Err(syn::Error::new_spanned(opaque_uuid, msg));
+        }
+        Ok(res)
+    }
+
+    // The attributes are validated by check_attrs before generating code.
+    fn of(ast: &DeriveInput) -> Self {
+        Self::parse(ast).unwrap_or_default()
+    }
+}
+
+// Options set on enum variants via #[binprot(...)] attributes.
+#[derive(Default)]
+struct VariantAttrs {
+    // Custom codec for the single field of the variant, see FieldAttrs.
+    with: Option<syn::MetaNameValue>,
+    // The OCaml name of the variant, used in the shape and to compute the hash
+    // of polymorphic variants.
+    name: Option<syn::LitStr>,
+    // Set for variants holding a polymorphic variant whose tags are included in
+    // the enclosing one, similar to [ t | `A ] in OCaml.
+    flatten: Option<syn::Path>,
+}
+
+impl VariantAttrs {
+    fn parse(variant: &syn::Variant) -> syn::Result<Self> {
+        let mut res = VariantAttrs::default();
+        for nested in binprot_attrs(&variant.attrs)? {
+            match &nested {
+                syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) if nv.path.is_ident("with") => {
+                    path_value(nv)?;
+                    res.with = Some(nv.clone())
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(nv)) if nv.path.is_ident("name") => {
+                    res.name = Some(str_value(nv)?)
+                }
+                syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.is_ident("flatten") => {
+                    res.flatten = Some(path.clone())
+                }
+                nested => return Err(syn::Error::new_spanned(nested, "unknown binprot attribute")),
+            }
+        }
+        match &res.flatten {
+            Some(flatten) if res.name.is_some() || res.with.is_some() => {
+                let msg = "flatten cannot be used with name or with";
+                Err(syn::Error::new_spanned(flatten, msg))
+            }
+            _ => Ok(res),
+        }
+    }
+
+    // The attributes are validated by check_attrs before generating code.
+    fn of(variant: &syn::Variant) -> Self {
+        Self::parse(variant).unwrap_or_default()
+    }
+}
+
+fn is_flattened(variant: &syn::Variant) -> bool {
+    VariantAttrs::of(variant).flatten.is_some()
+}
+
+// The OCaml name of a variant.
+fn variant_name(variant: &syn::Variant, rename_all: Option<RenameRule>) -> String {
+    match (VariantAttrs::of(variant).name, rename_all) {
+        (Some(name), _) => name.value(),
+        (None, Some(rule)) => rule.apply_to_variant(&variant.ident.to_string()),
+        (None, None) => variant.ident.to_string(),
+    }
+}
+
+// The OCaml name of a named field, rename_all only applies to struct fields.
+fn field_name(field: &syn::Field, rename_all: Option<RenameRule>) -> String {
+    let ident = field.ident.as_ref().unwrap().to_string();
+    match (FieldAttrs::of(field).name, rename_all) {
+        (Some(name), _) => name.value(),
+        (None, Some(rule)) => rule.apply_to_field(&ident),
+        (None, None) => ident,
+    }
+}
+
+// Writes the field behind the reference [value].
+fn write_field(
+    field: &syn::Field,
+    value: proc_macro2::TokenStream,
+    fns: &WriteFns,
+) -> proc_macro2::TokenStream {
+    let WriteFns { write_trait, write_method, .. } = fns;
+    let await_ = fns.await_();
+    match FieldAttrs::of(field).with {
+        Some(with) => quote! { #with::#write_method(#value, __binprot_w) #await_?; },
+        None => quote! { #write_trait::#write_method(#value, __binprot_w) #await_?; },
+    }
+}
+
+fn size_field(field: &syn::Field, value: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    match FieldAttrs::of(field).with {
+        Some(with) => quote! { + #with::binprot_size(#value) },
+        None => quote! { + binprot::BinProtSize::binprot_size(#value) },
+    }
+}
+
+// Patterns used to match on enum variants, skipped fields are ignored.
+fn named_pattern(field: &syn::Field) -> proc_macro2::TokenStream {
+    let name = field.ident.as_ref().unwrap();
+    if FieldAttrs::of(field).skip {
+        quote! { #name: _ }
+    } else {
+        quote! { #name }
+    }
+}
+
+fn unnamed_pattern((index, field): (usize, &syn::Field)) -> proc_macro2::TokenStream {
+    if FieldAttrs::of(field).skip {
+        quote! { _ }
+    } else {
+        let arg = format_ident!("arg{}", index);
+        quote! { #arg }
+    }
+}
+
+// Validates the binprot attributes, the `with` attribute of a variant is moved to
+// its single field so that the code generation only has to look at fields.
+fn check_attrs(ast: &DeriveInput) -> syn::Result<DeriveInput> {
+    let container_attrs = ContainerAttrs::parse(ast)?;
+    if let Some(attr) = polymorphic_variant_attr(ast) {
+        if !attr.tokens.is_empty() {
+            let msg = "polymorphic_variant does not take arguments";
+            return Err(syn::Error::new_spanned(&attr.tokens, msg));
+        }
+        let msg = "polymorphic_variant is only allowed on enum";
+        match &ast.data {
+            syn::Data::Enum(e) => {
+                // Similarly to OCaml, polymorphic variants cannot have inline records.
+                let named = e.variants.iter().find(|v| matches!(v.fields, syn::Fields::Named(_)));
+                if let Some(variant) = named {
+                    let msg = "polymorphic variants cannot have named fields";
+                    return Err(syn::Error::new_spanned(&variant.fields, msg));
+                }
+                // The tags are identified by their hash on the wire, collisions with
+                // the tags of flattened variants are detected at compile time via
+                // BinProtShapeTagged::BINPROT_TAG_HASHES.
+                let mut hashes = std::collections::BTreeMap::new();
+                for variant in e.variants.iter() {
+                    if let Some(flatten) = VariantAttrs::parse(variant)?.flatten {
+                        let field = match &variant.fields {
+                            syn::Fields::Unnamed(f) if f.unnamed.len() == 1 => &f.unnamed[0],
+                            _ => {
+                                let msg =
+                                    "flatten can only be used on variants with a single field";
+                                return Err(syn::Error::new_spanned(flatten, msg));
+                            }
+                        };
+                        if let Some(attr) = field.attrs.iter().find(|a| a.path.is_ident("binprot"))
+                        {
+                            let msg = "binprot attributes cannot be used on flattened fields";
+                            return Err(syn::Error::new_spanned(attr, msg));
+                        }
+                        continue;
+                    }
+                    let name = variant_name(variant, container_attrs.rename_all);
+                    if let Some(other) = hashes.insert(hash_variant(&name), name.clone()) {
+                        let msg = if other == name {
+                            format!("duplicate polymorphic variant tag {name}")
+                        } else {
+                            format!(
+                                "polymorphic variant tags {other} and {name} have the same hash"
+                            )
+                        };
+                        return Err(syn::Error::new_spanned(&variant.ident, msg));
+                    }
+                }
+            }
+            syn::Data::Struct(_) | syn::Data::Union(_) => {
+                return Err(syn::Error::new_spanned(attr, msg))
+            }
+        }
+    }
+    let has_polymorphic_variant_attr = has_polymorphic_variant_attr(ast);
+    let mut ast = ast.clone();
+    match &mut ast.data {
+        syn::Data::Struct(s) => {
+            for field in s.fields.iter() {
+                FieldAttrs::parse(field)?;
+            }
+        }
+        syn::Data::Enum(e) => {
+            for variant in e.variants.iter_mut() {
+                let attrs = VariantAttrs::parse(variant)?;
+                if let (Some(flatten), false) = (&attrs.flatten, has_polymorphic_variant_attr) {
+                    let msg = "flatten can only be used in polymorphic variants";
+                    return Err(syn::Error::new_spanned(flatten, msg));
+                }
+                if let Some(nv) = attrs.with {
+                    if variant.fields.len() != 1 {
+                        let msg = "with can only be used on variants with a single field";
+                        return Err(syn::Error::new_spanned(nv, msg));
+                    }
+                    let field = variant.fields.iter_mut().next().unwrap();
+                    field.attrs.push(parse_quote! { #[binprot(#nv)] })
+                }
+                for field in variant.fields.iter() {
+                    FieldAttrs::parse(field)?;
+                }
+            }
+        }
+        syn::Data::Union(_) => {}
+    }
+    Ok(ast)
+}
+
+#[proc_macro_derive(BinProtWrite, attributes(polymorphic_variant, binprot))]
 pub fn binprot_write_derive(input: TokenStream) -> TokenStream {
-    let ast = syn::parse(input).unwrap();
-    impl_binprot_write(&ast)
+    let ast = parse_macro_input!(input as DeriveInput);
+    impl_binprot_write(&ast).unwrap_or_else(|err| err.to_compile_error()).into()
 }
 
-fn impl_binprot_write(ast: &DeriveInput) -> TokenStream {
-    let DeriveInput { ident, data, generics, .. } = ast;
+fn impl_binprot_write(ast: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let DeriveInput { ident, generics, .. } = ast;
     let mut generics = generics.clone();
     for param in &mut generics.params {
         if let GenericParam::Type(type_param) = param {
-            type_param.bounds.push(parse_quote!(BinProtWrite))
+            type_param.bounds.push(parse_quote!(binprot::BinProtWrite));
         }
     }
+    add_with_len_bounds(&mut generics, ast);
     let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let fns = WriteFns {
+        write_trait: quote! { binprot::BinProtWrite },
+        write_method: format_ident!("binprot_write"),
+        is_async: false,
+    };
+    let impl_fn = binprot_write_fn(ast, &fns)?;
+
+    let output = quote! {
+        impl #impl_generics binprot::BinProtWrite for #ident #ty_generics #where_clause {
+            fn binprot_write<__BinProtW: std::io::Write>(&self, __binprot_w: &mut __BinProtW) -> std::io::Result<()> {
+                #impl_fn
+                Ok(())
+            }
+        }
+    };
+
+    Ok(output)
+}
+
+#[cfg(feature = "async")]
+#[proc_macro_derive(BinProtWriteAsync, attributes(polymorphic_variant, binprot))]
+pub fn binprot_write_async_derive(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    impl_binprot_write_async(&ast).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+#[cfg(feature = "async")]
+fn impl_binprot_write_async(ast: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let DeriveInput { ident, generics, .. } = ast;
+    let mut generics = generics.clone();
+    for param in &mut generics.params {
+        if let GenericParam::Type(type_param) = param {
+            type_param.bounds.push(parse_quote!(binprot::BinProtWriteAsync));
+        }
+    }
+    add_with_len_bounds(&mut generics, ast);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let fns = WriteFns {
+        write_trait: quote! { binprot::BinProtWriteAsync },
+        write_method: format_ident!("binprot_write_async"),
+        is_async: true,
+    };
+    let impl_fn = binprot_write_fn(ast, &fns)?;
+
+    let output = quote! {
+        #[binprot::async_trait::async_trait]
+        impl #impl_generics binprot::BinProtWriteAsync for #ident #ty_generics #where_clause {
+            async fn binprot_write_async<__BinProtW: binprot::tokio::io::AsyncWriteExt + Unpin + Send>(&self, __binprot_w: &mut __BinProtW) -> std::io::Result<()> {
+                #impl_fn
+                Ok(())
+            }
+        }
+    };
+
+    Ok(output)
+}
+
+// The trait and method used by the generated write functions.
+struct WriteFns {
+    write_trait: proc_macro2::TokenStream,
+    write_method: syn::Ident,
+    is_async: bool,
+}
+
+impl WriteFns {
+    fn await_(&self) -> proc_macro2::TokenStream {
+        if self.is_async {
+            quote! { .await }
+        } else {
+            quote! {}
+        }
+    }
+}
+
+// Generates the body of a write function using the methods from [fns].
+fn binprot_write_fn(ast: &DeriveInput, fns: &WriteFns) -> syn::Result<proc_macro2::TokenStream> {
+    let ast = &check_attrs(ast)?;
+    let DeriveInput { ident, data, .. } = ast;
+    let WriteFns { write_trait, write_method, .. } = fns;
+    let await_ = fns.await_();
     let has_polymorphic_variant_attr = has_polymorphic_variant_attr(ast);
+    let rename_all = ContainerAttrs::of(ast).rename_all;
 
     let impl_fn = match data {
         syn::Data::Struct(s) => {
-            if has_polymorphic_variant_attr {
-                panic!("polymorphic_variant is only allowed on enum")
-            }
             match &s.fields {
                 syn::Fields::Named(FieldsNamed { named, .. }) => {
-                    let fields = named.iter().map(|field| {
-                        let name = field.ident.as_ref().unwrap();
-                        quote! { self.#name.binprot_write(__binprot_w)?; }
-                    });
+                    let fields =
+                        named.iter().filter(|field| !FieldAttrs::of(field).skip).map(|field| {
+                            let name = field.ident.as_ref().unwrap();
+                            write_field(field, quote! { &self.#name }, fns)
+                        });
                     quote! {#(#fields)*}
                 }
                 syn::Fields::Unnamed(FieldsUnnamed { unnamed, .. }) => {
-                    let num_fields = unnamed.len();
-                    let fields = (0..num_fields).map(|index| {
-                        let index = syn::Index::from(index);
-                        quote! { self.#index.binprot_write(__binprot_w)?; }
-                    });
+                    let fields = unnamed
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, field)| !FieldAttrs::of(field).skip)
+                        .map(|(index, field)| {
+                            let index = syn::Index::from(index);
+                            write_field(field, quote! { &self.#index }, fns)
+                        });
                     quote! {#(#fields)*}
                 }
                 syn::Fields::Unit => {
-                    unimplemented!()
+                    // Unit structs are serialized as unit.
+                    quote! { #write_trait::#write_method(&(), __binprot_w) #await_?; }
                 }
             }
         }
         syn::Data::Enum(DataEnum { enum_token, variants, .. }) => {
             if variants.len() > 256 {
-                return syn::Error::new_spanned(enum_token, "enum with to many cases")
-                    .to_compile_error()
-                    .into();
+                return Err(syn::Error::new_spanned(enum_token, "enum with too many cases"));
             }
             let cases = variants.iter().enumerate().map(|(variant_index, variant)| {
                 let variant_ident = &variant.ident;
                 let (pattern, actions) = match &variant.fields {
                     syn::Fields::Named(FieldsNamed { named, .. }) => {
-                        let args = named.iter().map(|field| field.ident.as_ref().unwrap());
-                        let fields = named.iter().map(|field| {
-                            let name = field.ident.as_ref().unwrap();
-                            quote! { #name.binprot_write(__binprot_w)?; }
-                        });
+                        let args = named.iter().map(named_pattern);
+                        let fields =
+                            named.iter().filter(|field| !FieldAttrs::of(field).skip).map(|field| {
+                                let name = field.ident.as_ref().unwrap();
+                                write_field(field, quote! { #name }, fns)
+                            });
                         (quote! { { #(#args),* } }, quote! { #(#fields)* })
                     }
                     syn::Fields::Unnamed(FieldsUnnamed { unnamed, .. }) => {
-                        let num_fields = unnamed.len();
-                        let args = (0..num_fields).map(|index| format_ident!("arg{}", index));
-                        let write_args = {
-                            let args = args.clone();
-                            quote! { #(#args.binprot_write(__binprot_w)?;)* }
-                        };
-                        (quote! { (#(#args),*) }, write_args)
+                        let args = unnamed.iter().enumerate().map(unnamed_pattern);
+                        let write_args = unnamed
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, field)| !FieldAttrs::of(field).skip)
+                            .map(|(index, field)| {
+                                let arg = format_ident!("arg{}", index);
+                                write_field(field, quote! { #arg }, fns)
+                            });
+                        (quote! { (#(#args),*) }, quote! { #(#write_args)* })
                     }
                     syn::Fields::Unit => (quote! {}, quote! {}),
                 };
@@ -110,12 +602,16 @@ fn impl_binprot_write(ast: &DeriveInput) -> TokenStream {
                     let variant_index = variant_index as u8;
                     quote! { [#variant_index] }
                 } else {
-                    let variant_index: i32 = variant_int(&variant_ident.to_string());
+                    let variant_index: i32 = variant_int(&variant_name(variant, rename_all));
                     quote! { (#variant_index).to_le_bytes() }
                 };
+                if is_flattened(variant) {
+                    // The inner polymorphic variant writes its own tag.
+                    return quote! { #ident::#variant_ident #pattern => { #actions } };
+                }
                 quote! {
                     #ident::#variant_ident #pattern => {
-                        __binprot_w.write_all(&#variant_index)?;
+                        __binprot_w.write_all(&#variant_index) #await_?;
                         #actions
                     }
                 }
@@ -127,87 +623,472 @@ fn impl_binprot_write(ast: &DeriveInput) -> TokenStream {
             }
         }
         syn::Data::Union(DataUnion { union_token, .. }) => {
-            return syn::Error::new_spanned(union_token, "union is not supported")
-                .to_compile_error()
-                .into();
+            return Err(syn::Error::new_spanned(union_token, "union is not supported"));
+        }
+    };
+    Ok(impl_fn)
+}
+
+#[proc_macro_derive(BinProtSize, attributes(polymorphic_variant, binprot))]
+pub fn binprot_size_derive(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    impl_binprot_size(&ast).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+fn impl_binprot_size(ast: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let ast = &check_attrs(ast)?;
+    let DeriveInput { ident, data, generics, .. } = ast;
+    let mut generics = generics.clone();
+    for param in &mut generics.params {
+        if let GenericParam::Type(type_param) = param {
+            type_param.bounds.push(parse_quote!(binprot::BinProtSize))
+        }
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let has_polymorphic_variant_attr = has_polymorphic_variant_attr(ast);
+
+    let impl_fn = match data {
+        syn::Data::Struct(s) => match &s.fields {
+            syn::Fields::Named(FieldsNamed { named, .. }) => {
+                let fields =
+                    named.iter().filter(|field| !FieldAttrs::of(field).skip).map(|field| {
+                        let name = field.ident.as_ref().unwrap();
+                        size_field(field, quote! { &self.#name })
+                    });
+                quote! { 0 #(#fields)* }
+            }
+            syn::Fields::Unnamed(FieldsUnnamed { unnamed, .. }) => {
+                let fields = unnamed
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, field)| !FieldAttrs::of(field).skip)
+                    .map(|(index, field)| {
+                        let index = syn::Index::from(index);
+                        size_field(field, quote! { &self.#index })
+                    });
+                quote! { 0 #(#fields)* }
+            }
+            syn::Fields::Unit => {
+                quote! { binprot::BinProtSize::binprot_size(&()) }
+            }
+        },
+        syn::Data::Enum(DataEnum { enum_token, variants, .. }) => {
+            if variants.len() > 256 {
+                return Err(syn::Error::new_spanned(enum_token, "enum with too many cases"));
+            }
+            let variant_index_size: usize = if has_polymorphic_variant_attr { 4 } else { 1 };
+            let cases = variants.iter().map(|variant| {
+                let variant_ident = &variant.ident;
+                let (pattern, sizes) = match &variant.fields {
+                    syn::Fields::Named(FieldsNamed { named, .. }) => {
+                        let args = named.iter().map(named_pattern);
+                        let sizes =
+                            named.iter().filter(|field| !FieldAttrs::of(field).skip).map(|field| {
+                                let name = field.ident.as_ref().unwrap();
+                                size_field(field, quote! { #name })
+                            });
+                        (quote! { { #(#args),* } }, quote! { #(#sizes)* })
+                    }
+                    syn::Fields::Unnamed(FieldsUnnamed { unnamed, .. }) => {
+                        let args = unnamed.iter().enumerate().map(unnamed_pattern);
+                        let sizes = unnamed
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, field)| !FieldAttrs::of(field).skip)
+                            .map(|(index, field)| {
+                                let arg = format_ident!("arg{}", index);
+                                size_field(field, quote! { #arg })
+                            });
+                        (quote! { (#(#args),*) }, quote! { #(#sizes)* })
+                    }
+                    syn::Fields::Unit => (quote! {}, quote! {}),
+                };
+                let variant_index_size = if is_flattened(variant) { 0 } else { variant_index_size };
+                quote! {
+                    #ident::#variant_ident #pattern => #variant_index_size #sizes,
+                }
+            });
+            quote! {
+                match self {
+                    #(#cases)*
+                }
+            }
+        }
+        syn::Data::Union(DataUnion { union_token, .. }) => {
+            return Err(syn::Error::new_spanned(union_token, "union is not supported"));
         }
     };
 
     let output = quote! {
-        impl #impl_generics binprot::BinProtWrite for #ident #ty_generics #where_clause {
-            fn binprot_write<__BinProtW: std::io::Write>(&self, __binprot_w: &mut __BinProtW) -> std::io::Result<()> {
+        impl #impl_generics binprot::BinProtSize for #ident #ty_generics #where_clause {
+            fn binprot_size(&self) -> usize {
                 #impl_fn
-                Ok(())
             }
         }
     };
 
-    output.into()
+    Ok(output)
 }
 
-#[proc_macro_derive(BinProtRead, attributes(polymorphic_variant))]
+#[proc_macro_derive(BinProtRead, attributes(polymorphic_variant, binprot))]
 pub fn binprot_read_derive(input: TokenStream) -> TokenStream {
-    let ast = syn::parse(input).unwrap();
-    impl_binprot_read(&ast)
+    let ast = parse_macro_input!(input as DeriveInput);
+    impl_binprot_read(&ast).unwrap_or_else(|err| err.to_compile_error()).into()
 }
 
-fn impl_binprot_read(ast: &DeriveInput) -> TokenStream {
-    let DeriveInput { ident, data, generics, .. } = ast;
+fn impl_binprot_read(ast: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let DeriveInput { ident, generics, .. } = ast;
     let mut generics = generics.clone();
     for param in &mut generics.params {
         if let GenericParam::Type(type_param) = param {
-            type_param.bounds.push(parse_quote!(BinProtRead))
+            type_param.bounds.push(parse_quote!(binprot::BinProtRead))
         }
     }
+    let fns = ReadFns {
+        read_trait: quote! { binprot::BinProtRead },
+        read_method: format_ident!("binprot_read"),
+        tagged_trait: quote! { binprot::BinProtReadTagged },
+        tagged_method: format_ident!("binprot_read_tagged"),
+        is_async: false,
+    };
+    add_flatten_bounds(&mut generics, ast, &fns.tagged_trait);
     let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
-    let has_polymorphic_variant_attr = has_polymorphic_variant_attr(ast);
+    let (read_fn, tagged_fn) = binprot_read_fn(ast, &fns)?;
+    let tagged_impl = tagged_fn.map(|tagged_fn| {
+        quote! {
+            impl #impl_generics binprot::BinProtReadTagged for #ident #ty_generics #where_clause {
+                fn binprot_read_tagged<__BinProtR: std::io::Read + ?Sized>(__binprot_tag: i32, __binprot_r: &mut __BinProtR) -> std::result::Result<Option<Self>, binprot::Error> {
+                    #tagged_fn
+                }
+            }
+        }
+    });
 
-    let read_fn = match data {
-        syn::Data::Struct(s) => {
-            if has_polymorphic_variant_attr {
-                panic!("polymorphic_variant is only allowed on enum")
+    let output = quote! {
+        impl #impl_generics binprot::BinProtRead for #ident #ty_generics #where_clause {
+            fn binprot_read<__BinProtR: std::io::Read + ?Sized>(__binprot_r: &mut __BinProtR) -> std::result::Result<Self, binprot::Error> {
+                #read_fn
             }
+        }
+        #tagged_impl
+    };
 
-            match &s.fields {
-                syn::Fields::Named(FieldsNamed { named, .. }) => {
-                    let fields = named.iter().map(|field| field.ident.as_ref().unwrap());
-                    let mk_fields = named.iter().map(|field| {
-                        let name = field.ident.as_ref().unwrap();
-                        quote! { let #name = binprot::BinProtRead::binprot_read(__binprot_r)?; }
-                    });
-                    quote! {
-                        #(#mk_fields)*
-                        Ok(#ident { #(#fields),* })
-                    }
+    Ok(output)
+}
+
+#[proc_macro_derive(BinProtReadBorrowed, attributes(polymorphic_variant, binprot))]
+pub fn binprot_read_borrowed_derive(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    impl_binprot_read_borrowed(&ast).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+fn impl_binprot_read_borrowed(ast: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let DeriveInput { ident, generics, .. } = ast;
+    let (_, ty_generics, _) = generics.split_for_impl();
+    // The data is borrowed for '__binprot_de which has to outlive all the
+    // lifetime parameters of the type.
+    let mut impl_generics = generics.clone();
+    let lifetimes = generics.lifetimes().map(|l| l.lifetime.clone()).collect::<Vec<_>>();
+    let de_lifetime = if lifetimes.is_empty() {
+        parse_quote!('__binprot_de)
+    } else {
+        parse_quote!('__binprot_de: #(#lifetimes)+*)
+    };
+    impl_generics.params.insert(0, de_lifetime);
+    for param in &mut impl_generics.params {
+        if let GenericParam::Type(type_param) = param {
+            type_param.bounds.push(parse_quote!(binprot::BinProtReadBorrowed<'__binprot_de>))
+        }
+    }
+    let fns = ReadFns {
+        read_trait: quote! { binprot::BinProtReadBorrowed },
+        read_method: format_ident!("binprot_read_borrowed"),
+        tagged_trait: quote! { binprot::BinProtReadBorrowedTagged<'__binprot_de> },
+        tagged_method: format_ident!("binprot_read_borrowed_tagged"),
+        is_async: false,
+    };
+    add_flatten_bounds(&mut impl_generics, ast, &fns.tagged_trait);
+    let (impl_generics, _, where_clause) = impl_generics.split_for_impl();
+    let (read_fn, tagged_fn) = binprot_read_fn(ast, &fns)?;
+    let tagged_impl = tagged_fn.map(|tagged_fn| {
+        quote! {
+            impl #impl_generics binprot::BinProtReadBorrowedTagged<'__binprot_de> for #ident #ty_generics #where_clause {
+                fn binprot_read_borrowed_tagged(__binprot_tag: i32, __binprot_r: &mut &'__binprot_de [u8]) -> std::result::Result<Option<Self>, binprot::Error> {
+                    #tagged_fn
                 }
-                syn::Fields::Unnamed(FieldsUnnamed { unnamed, .. }) => {
-                    let num_fields = unnamed.len();
-                    let fields = (0..num_fields).map(|index| format_ident!("__field{}", index));
-                    let mk_fields = (0..num_fields).map(|index| {
-                        let ident = format_ident!("__field{}", index);
-                        quote! { let #ident = binprot::BinProtRead::binprot_read(__binprot_r)?; }
-                    });
-                    quote! {
-                        #(#mk_fields)*
-                        Ok(#ident(#(#fields),*))
+            }
+        }
+    });
+
+    let output = quote! {
+        impl #impl_generics binprot::BinProtReadBorrowed<'__binprot_de> for #ident #ty_generics #where_clause {
+            fn binprot_read_borrowed(__binprot_r: &mut &'__binprot_de [u8]) -> std::result::Result<Self, binprot::Error> {
+                #read_fn
+            }
+        }
+        #tagged_impl
+    };
+
+    Ok(output)
+}
+
+#[cfg(feature = "async")]
+#[proc_macro_derive(BinProtReadAsync, attributes(polymorphic_variant, binprot))]
+pub fn binprot_read_async_derive(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    impl_binprot_read_async(&ast).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+#[cfg(feature = "async")]
+fn impl_binprot_read_async(ast: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let DeriveInput { ident, generics, .. } = ast;
+    let mut generics = generics.clone();
+    for param in &mut generics.params {
+        if let GenericParam::Type(type_param) = param {
+            type_param.bounds.push(parse_quote!(binprot::BinProtReadAsync))
+        }
+    }
+    let fns = ReadFns {
+        read_trait: quote! { binprot::BinProtReadAsync },
+        read_method: format_ident!("binprot_read_async"),
+        tagged_trait: quote! { binprot::BinProtReadAsyncTagged },
+        tagged_method: format_ident!("binprot_read_async_tagged"),
+        is_async: true,
+    };
+    add_flatten_bounds(&mut generics, ast, &fns.tagged_trait);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let (read_fn, tagged_fn) = binprot_read_fn(ast, &fns)?;
+    let tagged_impl = tagged_fn.map(|tagged_fn| {
+        quote! {
+            #[binprot::async_trait::async_trait]
+            impl #impl_generics binprot::BinProtReadAsyncTagged for #ident #ty_generics #where_clause {
+                async fn binprot_read_async_tagged<__BinProtR: binprot::tokio::io::AsyncReadExt + Unpin + Send + ?Sized>(__binprot_tag: i32, __binprot_r: &mut __BinProtR) -> std::result::Result<Option<Self>, binprot::Error> {
+                    #tagged_fn
+                }
+            }
+        }
+    });
+
+    let output = quote! {
+        #[binprot::async_trait::async_trait]
+        impl #impl_generics binprot::BinProtReadAsync for #ident #ty_generics #where_clause {
+            async fn binprot_read_async<__BinProtR: binprot::tokio::io::AsyncReadExt + Unpin + Send + ?Sized>(__binprot_r: &mut __BinProtR) -> std::result::Result<Self, binprot::Error> {
+                #read_fn
+            }
+        }
+        #tagged_impl
+    };
+
+    Ok(output)
+}
+
+// The traits and methods used by the generated read functions, the tagged ones
+// read polymorphic variants after their tag.
+struct ReadFns {
+    read_trait: proc_macro2::TokenStream,
+    read_method: syn::Ident,
+    tagged_trait: proc_macro2::TokenStream,
+    tagged_method: syn::Ident,
+    is_async: bool,
+}
+
+impl ReadFns {
+    fn await_(&self) -> proc_macro2::TokenStream {
+        if self.is_async {
+            quote! { .await }
+        } else {
+            quote! {}
+        }
+    }
+
+    // Reads the tag of a variant, a single byte for enums and a 32 bits little
+    // endian integer for polymorphic variants.
+    fn read_tag(&self, polymorphic_variant: bool) -> proc_macro2::TokenStream {
+        match (self.is_async, polymorphic_variant) {
+            (false, false) => quote! { binprot::byteorder::ReadBytesExt::read_u8(__binprot_r)? },
+            (false, true) => quote! {
+                binprot::byteorder::ReadBytesExt::read_i32::<binprot::byteorder::LittleEndian>(__binprot_r)?
+            },
+            (true, false) => {
+                quote! { binprot::tokio::io::AsyncReadExt::read_u8(__binprot_r).await? }
+            }
+            (true, true) => {
+                quote! { binprot::tokio::io::AsyncReadExt::read_i32_le(__binprot_r).await? }
+            }
+        }
+    }
+}
+
+// The types of flattened variants have to be read via the tagged trait.
+fn add_flatten_bounds(
+    generics: &mut syn::Generics,
+    ast: &DeriveInput,
+    tagged_trait: &proc_macro2::TokenStream,
+) {
+    if let syn::Data::Enum(DataEnum { variants, .. }) = &ast.data {
+        for variant in variants.iter().filter(|variant| is_flattened(variant)) {
+            for field in variant.fields.iter() {
+                let ty = &field.ty;
+                generics.make_where_clause().predicates.push(parse_quote!(#ty: #tagged_trait))
+            }
+        }
+    }
+}
+
+// The generic types from std for which the BinProtWrite implementation only
+// requires BinProtWrite on the type arguments.
+const STD_CONTAINERS: &[&str] = &[
+    "Option",
+    "Result",
+    "Box",
+    "Vec",
+    "BTreeMap",
+    "HashMap",
+    "BTreeSet",
+    "HashSet",
+    "VecDeque",
+    "LinkedList",
+];
+
+// Writing a WithLen<T> requires T to implement BinProtSize so that the length
+// prefix can be computed. Rather than requiring BinProtSize on every type
+// parameter, only the type parameters that appear inside a WithLen get this
+// bound. The parameters passed to other generic types, which may themselves wrap
+// them in a WithLen, get the bound too unless the type is a std container or the
+// type being derived.
+fn add_with_len_bounds(generics: &mut syn::Generics, ast: &DeriveInput) {
+    struct WithLenParams<'a> {
+        self_ident: &'a syn::Ident,
+        type_params: &'a [syn::Ident],
+        found: Vec<syn::Ident>,
+    }
+
+    impl syn::visit_mut::VisitMut for WithLenParams<'_> {
+        fn visit_type_path_mut(&mut self, type_path: &mut syn::TypePath) {
+            if let Some(segment) = type_path.path.segments.last() {
+                let ident = &segment.ident;
+                let is_container = ident == self.self_ident
+                    || STD_CONTAINERS.iter().any(|container| ident == container);
+                let args = &segment.arguments;
+                if ident == "WithLen" || !is_container {
+                    for param in self.type_params {
+                        if mentions_any(quote! { #args }, std::slice::from_ref(param)) {
+                            self.found.push(param.clone())
+                        }
                     }
                 }
-                syn::Fields::Unit => unimplemented!(),
             }
+            syn::visit_mut::visit_type_path_mut(self, type_path)
+        }
+    }
+
+    let type_params: Vec<syn::Ident> =
+        generics.type_params().map(|type_param| type_param.ident.clone()).collect();
+    let mut visitor =
+        WithLenParams { self_ident: &ast.ident, type_params: &type_params, found: vec![] };
+    let fields: Vec<&syn::Field> = match &ast.data {
+        syn::Data::Struct(s) => s.fields.iter().collect(),
+        syn::Data::Enum(DataEnum { variants, .. }) => {
+            variants.iter().flat_map(|variant| variant.fields.iter()).collect()
+        }
+        syn::Data::Union(_) => vec![],
+    };
+    for field in fields {
+        let attrs = FieldAttrs::of(field);
+        if !attrs.skip && attrs.with.is_none() {
+            syn::visit_mut::visit_type_mut(&mut visitor, &mut field.ty.clone())
+        }
+    }
+    for type_param in generics.type_params_mut() {
+        if visitor.found.contains(&type_param.ident) {
+            type_param.bounds.push(parse_quote!(binprot::BinProtSize))
         }
+    }
+}
+
+fn mentions_any(tokens: proc_macro2::TokenStream, idents: &[syn::Ident]) -> bool {
+    tokens.into_iter().any(|tree| match tree {
+        proc_macro2::TokenTree::Ident(ident) => idents.contains(&ident),
+        proc_macro2::TokenTree::Group(group) => mentions_any(group.stream(), idents),
+        _ => false,
+    })
+}
+
+// Reads a field into the variable [ident], skipped fields get their default value.
+fn read_field(field: &syn::Field, ident: &syn::Ident, fns: &ReadFns) -> proc_macro2::TokenStream {
+    let ReadFns { read_trait, read_method, .. } = fns;
+    let await_ = fns.await_();
+    let attrs = FieldAttrs::of(field);
+    if attrs.skip {
+        let default_value = attrs.default_value();
+        quote! { let #ident = #default_value; }
+    } else if let Some(with) = attrs.with {
+        quote! { let #ident = #with::#read_method(__binprot_r) #await_?; }
+    } else {
+        quote! { let #ident = #read_trait::#read_method(__binprot_r) #await_?; }
+    }
+}
+
+// Generates the body of a read function using the methods from [fns] to read
+// the fields. For polymorphic variants, the body of the tagged read function is
+// returned as well.
+fn binprot_read_fn(
+    ast: &DeriveInput,
+    fns: &ReadFns,
+) -> syn::Result<(proc_macro2::TokenStream, Option<proc_macro2::TokenStream>)> {
+    let ident = &ast.ident;
+    let has_polymorphic_variant_attr = has_polymorphic_variant_attr(ast);
+    let ast = &check_attrs(ast)?;
+    let rename_all = ContainerAttrs::of(ast).rename_all;
+
+    let read_fn = match &ast.data {
+        syn::Data::Struct(s) => match &s.fields {
+            syn::Fields::Named(FieldsNamed { named, .. }) => {
+                let fields = named.iter().map(|field| field.ident.as_ref().unwrap());
+                let mk_fields = named.iter().map(|field| {
+                    let name = field.ident.as_ref().unwrap();
+                    read_field(field, name, fns)
+                });
+                quote! {
+                    #(#mk_fields)*
+                    Ok(#ident { #(#fields),* })
+                }
+            }
+            syn::Fields::Unnamed(FieldsUnnamed { unnamed, .. }) => {
+                let num_fields = unnamed.len();
+                let fields = (0..num_fields).map(|index| format_ident!("__field{}", index));
+                let mk_fields = unnamed.iter().enumerate().map(|(index, field)| {
+                    read_field(field, &format_ident!("__field{}", index), fns)
+                });
+                quote! {
+                    #(#mk_fields)*
+                    Ok(#ident(#(#fields),*))
+                }
+            }
+            syn::Fields::Unit => {
+                let ReadFns { read_trait, read_method, .. } = fns;
+                let await_ = fns.await_();
+                quote! {
+                    let () = #read_trait::#read_method(__binprot_r) #await_?;
+                    Ok(#ident)
+                }
+            }
+        },
         syn::Data::Enum(DataEnum { enum_token, variants, .. }) => {
             if variants.len() > 256 {
-                return syn::Error::new_spanned(enum_token, "enum with to many cases")
-                    .to_compile_error()
-                    .into();
+                return Err(syn::Error::new_spanned(enum_token, "enum with too many cases"));
             }
-            let cases = variants.iter().enumerate().map(|(variant_index, variant)| {
+            let ReadFns { tagged_trait, tagged_method, .. } = fns;
+            let await_ = fns.await_();
+            let read_tag = fns.read_tag(has_polymorphic_variant_attr);
+            let cases = variants.iter().enumerate().filter(|(_, variant)| !is_flattened(variant));
+            let cases = cases.map(|(variant_index, variant)| {
                 let variant_ident = &variant.ident;
                 let variant_index = if !has_polymorphic_variant_attr {
                     let variant_index = variant_index as u8;
                     quote! { #variant_index }
                 } else {
-                    let variant_index: i32 = variant_int(&variant_ident.to_string());
+                    let variant_index: i32 = variant_int(&variant_name(variant, rename_all));
                     quote! { #variant_index }
                 };
                 let (mk_fields, fields) = match &variant.fields {
@@ -215,126 +1096,155 @@ fn impl_binprot_read(ast: &DeriveInput) -> TokenStream {
                         let fields = named.iter().map(|field| field.ident.as_ref().unwrap());
                         let mk_fields = named.iter().map(|field| {
                             let name = field.ident.as_ref().unwrap();
-                            quote! { let #name = binprot::BinProtRead::binprot_read(__binprot_r)?; }
+                            read_field(field, name, fns)
                         });
                         (quote! { #(#mk_fields)* }, quote! { { #(#fields),* } })
                     }
                     syn::Fields::Unnamed(FieldsUnnamed { unnamed, .. }) => {
                         let num_fields = unnamed.len();
                         let fields = (0..num_fields).map(|index| format_ident!("__field{}", index));
-                        let mk_fields = (0..num_fields).map(|index| {
-                            let ident = format_ident!("__field{}", index);
-                            quote! { let #ident = binprot::BinProtRead::binprot_read(__binprot_r)?; }
+                        let mk_fields = unnamed.iter().enumerate().map(|(index, field)| {
+                            read_field(field, &format_ident!("__field{}", index), fns)
                         });
                         (quote! { #(#mk_fields)* }, quote! { (#(#fields),*) })
                     }
                     syn::Fields::Unit => (quote! {}, quote! {}),
                 };
+                let value = quote! { #ident::#variant_ident #fields };
+                let value = if has_polymorphic_variant_attr {
+                    quote! { Some(#value) }
+                } else {
+                    value
+                };
                 quote! {
                     #variant_index => {
                         #mk_fields
-                        Ok(#ident::#variant_ident #fields)
+                        Ok(#value)
                     }
                 }
             });
             if !has_polymorphic_variant_attr {
                 quote! {
-                    let variant_index = binprot::byteorder::ReadBytesExt::read_u8(__binprot_r)?;
+                    let variant_index = #read_tag;
                     match variant_index {
                         #(#cases)*
                         index => Err(binprot::Error::UnexpectedVariantIndex { index, ident: stringify!(#ident) } ),
                     }
                 }
             } else {
-                quote! {
-                    let variant_index = binprot::byteorder::ReadBytesExt::read_i32::<binprot::byteorder::LittleEndian>(__binprot_r)?;
-                    match variant_index {
+                // The flattened variants are tried in order when the tag does not
+                // match any of the variants of this type.
+                let flattened = variants.iter().filter(|variant| is_flattened(variant)).map(|variant| {
+                    let variant_ident = &variant.ident;
+                    let ty = &variant.fields.iter().next().unwrap().ty;
+                    quote! {
+                        if let Some(v) = <#ty as #tagged_trait>::#tagged_method(__binprot_tag, __binprot_r) #await_? {
+                            return Ok(Some(#ident::#variant_ident(v)));
+                        }
+                    }
+                });
+                let tagged_fn = quote! {
+                    match __binprot_tag {
                         #(#cases)*
-                        index => Err(binprot::Error::UnexpectedPolymorphicVariantIndex { index, ident: stringify!(#ident) } ),
+                        _ => {
+                            #(#flattened)*
+                            Ok(None)
+                        }
                     }
-                }
+                };
+                let read_fn = quote! {
+                    let index = #read_tag;
+                    match <Self as #tagged_trait>::#tagged_method(index, __binprot_r) #await_? {
+                        Some(v) => Ok(v),
+                        None => Err(binprot::Error::UnexpectedPolymorphicVariantIndex { index, ident: stringify!(#ident) } ),
+                    }
+                };
+                return Ok((read_fn, Some(tagged_fn)));
             }
         }
         syn::Data::Union(DataUnion { union_token, .. }) => {
-            return syn::Error::new_spanned(union_token, "union is not supported")
-                .to_compile_error()
-                .into();
-        }
-    };
-
-    let output = quote! {
-        impl #impl_generics binprot::BinProtRead for #ident #ty_generics #where_clause {
-            fn binprot_read<__BinProtR: std::io::Read + ?Sized>(__binprot_r: &mut __BinProtR) -> std::result::Result<Self, binprot::Error> {
-                #read_fn
-            }
+            return Err(syn::Error::new_spanned(union_token, "union is not supported"));
         }
     };
-
-    output.into()
+    Ok((read_fn, None))
 }
 
-#[proc_macro_derive(BinProtShape, attributes(polymorphic_variant))]
+#[proc_macro_derive(BinProtShape, attributes(polymorphic_variant, binprot))]
 pub fn binprot_shape_derive(input: TokenStream) -> TokenStream {
-    let ast = syn::parse(input).unwrap();
-    impl_binprot_shape(&ast)
+    let ast = parse_macro_input!(input as DeriveInput);
+    impl_binprot_shape(&ast).unwrap_or_else(|err| err.to_compile_error()).into()
 }
 
-fn impl_binprot_shape(ast: &DeriveInput) -> TokenStream {
+fn impl_binprot_shape(ast: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let ast = &check_attrs(ast)?;
+    let self_generics = static_ty_generics(&ast.generics);
+    let ast = &static_lifetimes(ast);
     let DeriveInput { ident, data, generics, .. } = ast;
     let mut generics = generics.clone();
     for param in &mut generics.params {
         if let GenericParam::Type(type_param) = param {
-            type_param.bounds.push(parse_quote!(BinProtShape))
+            type_param.bounds.push(parse_quote!(binprot::BinProtShape))
         }
     }
-    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    let (impl_generics, _, where_clause) = generics.split_for_impl();
     let has_polymorphic_variant_attr = has_polymorphic_variant_attr(ast);
+    let container_attrs = ContainerAttrs::of(ast);
+    // The definition body is computed with the type parameters replaced by
+    // binprot::ShapeVar markers so that they appear as Shape::Var, the actual
+    // parameters are then applied to this body as done by ppx_bin_prot.
+    let type_params = generics.type_params().map(|p| p.ident.clone()).collect::<Vec<_>>();
+    check_regular_recursion(ast, &type_params)?;
+    let shape_loop = |field: &syn::Field| match FieldAttrs::of(field).with {
+        Some(with) => quote! { #with::binprot_shape() },
+        None => {
+            let ty = shape_var_type(&field.ty, &type_params);
+            quote! { <#ty as binprot::BinProtShape>::binprot_shape_loop(_c) }
+        }
+    };
 
+    // The tags and their hashes for polymorphic variants.
+    let mut poly_variant = None;
     let impl_fn = match data {
-        syn::Data::Struct(s) => {
-            if has_polymorphic_variant_attr {
-                panic!("polymorphic_variant is only allowed on enum")
-            }
-            match &s.fields {
-                syn::Fields::Named(FieldsNamed { named, .. }) => {
-                    let fields = named.iter().map(|field| {
-                        let name = field.ident.as_ref().unwrap();
-                        let ty = &field.ty;
-                        quote! { (stringify!(#name), <#ty>::binprot_shape_loop(_c)) }
-                    });
-                    quote! {binprot::Shape::Record(vec![#(#fields),*])}
-                }
-                syn::Fields::Unnamed(FieldsUnnamed { unnamed, .. }) => {
-                    let fields = unnamed.iter().map(|field| {
-                        let ty = &field.ty;
-                        quote! {<#ty>::binprot_shape_loop(_c) }
+        syn::Data::Struct(s) => match &s.fields {
+            syn::Fields::Named(FieldsNamed { named, .. }) => {
+                let fields =
+                    named.iter().filter(|field| !FieldAttrs::of(field).skip).map(|field| {
+                        let name = field_name(field, container_attrs.rename_all);
+                        let shape = shape_loop(field);
+                        quote! { (std::borrow::Cow::Borrowed(#name), #shape) }
                     });
-                    quote! {binprot::Shape::Tuple(vec![#(#fields,)*])}
-                }
-                syn::Fields::Unit => {
-                    unimplemented!()
-                }
+                quote! {binprot::Shape::Record(vec![#(#fields),*])}
             }
-        }
+            syn::Fields::Unnamed(FieldsUnnamed { unnamed, .. }) => {
+                let fields =
+                    unnamed.iter().filter(|field| !FieldAttrs::of(field).skip).map(shape_loop);
+                quote! {binprot::Shape::Tuple(vec![#(#fields,)*])}
+            }
+            syn::Fields::Unit => {
+                quote! { <() as binprot::BinProtShape>::binprot_shape_loop(_c) }
+            }
+        },
         syn::Data::Enum(DataEnum { variants, .. }) => {
             if has_polymorphic_variant_attr {
-                let cases = variants.iter().map(|variant| {
+                let cases = variants.iter().filter(|variant| !is_flattened(variant));
+                let cases = cases.map(|variant| {
                     let args = match &variant.fields {
                         syn::Fields::Named(FieldsNamed { named, .. }) => {
-                            let fields = named.iter().map(|field| {
-                                let name = field.ident.as_ref().unwrap();
-                                let ty = &field.ty;
-                                quote! { (stringify!(#name), <#ty>::binprot_shape_loop(_c)) }
-                            });
+                            let fields = named
+                                .iter()
+                                .filter(|field| !FieldAttrs::of(field).skip)
+                                .map(|field| {
+                                    let name = field_name(field, None);
+                                    let shape = shape_loop(field);
+                                    quote! { (std::borrow::Cow::Borrowed(#name), #shape) }
+                                });
                             quote! {Some(binprot::Shape::Record(vec![#(#fields),*]))}
                         }
                         syn::Fields::Unnamed(FieldsUnnamed { unnamed, .. }) => {
                             let tuple = unnamed
                                 .iter()
-                                .map(|field| {
-                                    let ty = &field.ty;
-                                    quote! {<#ty>::binprot_shape_loop(_c) }
-                                })
+                                .filter(|field| !FieldAttrs::of(field).skip)
+                                .map(shape_loop)
                                 .collect::<Vec<_>>();
                             if tuple.len() == 1 {
                                 let tuple = &tuple[0];
@@ -345,34 +1255,61 @@ fn impl_binprot_shape(ast: &DeriveInput) -> TokenStream {
                         }
                         syn::Fields::Unit => quote! {None},
                     };
-                    let name = &variant.ident;
-                    quote! {(stringify!(#name), #args)}
+                    let name = variant_name(variant, container_attrs.rename_all);
+                    quote! {(std::borrow::Cow::Borrowed(#name), #args)}
                 });
-                quote! {
-                    binprot::Shape::PolyVariant(vec![#(#cases,)*].into_iter().collect())
-                }
+                let inherited = variants.iter().filter(|variant| is_flattened(variant));
+                let inherited = inherited.flat_map(|variant| variant.fields.iter());
+                let inherited_tags = inherited.clone().map(|field| {
+                    let ty = shape_var_type(&field.ty, &type_params);
+                    quote! { <#ty as binprot::BinProtShapeTagged>::binprot_shape_tagged(_c) }
+                });
+                // The tags are distinct as checked at compile time by BINPROT_TAG_HASHES,
+                // referring to the constant here forces the check for generic types.
+                let tags = quote! {{
+                    let _ = &<Self as binprot::BinProtShapeTagged>::BINPROT_TAG_HASHES;
+                    let mut tags: std::collections::BTreeMap<_, _> =
+                        vec![#(#cases,)*].into_iter().collect();
+                    #(tags.extend(#inherited_tags);)*
+                    tags
+                }};
+                let hashes =
+                    variants.iter().filter(|variant| !is_flattened(variant)).map(|variant| {
+                        hash_variant(&variant_name(variant, container_attrs.rename_all))
+                    });
+                let inherited_hashes = inherited.map(|field| {
+                    let ty = &field.ty;
+                    quote! { &<#ty as binprot::BinProtShapeTagged>::BINPROT_TAG_HASHES }
+                });
+                let hashes = quote! {
+                    binprot::TagHashes::new(&[#(#hashes),*], &[#(#inherited_hashes),*])
+                };
+                let impl_fn = quote! { binprot::Shape::PolyVariant(#tags) };
+                poly_variant = Some((tags, hashes));
+                impl_fn
             } else {
                 let cases = variants.iter().map(|variant| {
                     let args = match &variant.fields {
                         syn::Fields::Named(FieldsNamed { named, .. }) => {
-                            let fields = named.iter().map(|field| {
-                                let name = field.ident.as_ref().unwrap();
-                                let ty = &field.ty;
-                                quote! { (stringify!(#name), <#ty>::binprot_shape_loop(_c)) }
-                            });
+                            let fields = named
+                                .iter()
+                                .filter(|field| !FieldAttrs::of(field).skip)
+                                .map(|field| {
+                                    let name = field_name(field, None);
+                                    let shape = shape_loop(field);
+                                    quote! { (std::borrow::Cow::Borrowed(#name), #shape) }
+                                });
                             vec![quote! {binprot::Shape::Record(vec![#(#fields),*])}]
                         }
                         syn::Fields::Unnamed(FieldsUnnamed { unnamed, .. }) => unnamed
                             .iter()
-                            .map(|field| {
-                                let ty = &field.ty;
-                                quote! {<#ty>::binprot_shape_loop(_c) }
-                            })
+                            .filter(|field| !FieldAttrs::of(field).skip)
+                            .map(shape_loop)
                             .collect::<Vec<_>>(),
                         syn::Fields::Unit => vec![],
                     };
-                    let name = &variant.ident;
-                    quote! {(stringify!(#name), vec![#(#args,)*])}
+                    let name = variant_name(variant, container_attrs.rename_all);
+                    quote! {(std::borrow::Cow::Borrowed(#name), vec![#(#args,)*])}
                 });
                 quote! {
                     binprot::Shape::Variant(vec![#(#cases,)*])
@@ -380,21 +1317,198 @@ fn impl_binprot_shape(ast: &DeriveInput) -> TokenStream {
             }
         }
         syn::Data::Union(DataUnion { union_token, .. }) => {
-            return syn::Error::new_spanned(union_token, "union is not supported")
-                .to_compile_error()
-                .into();
+            return Err(syn::Error::new_spanned(union_token, "union is not supported"));
+        }
+    };
+
+    let is_opaque = container_attrs.opaque_uuid.is_some();
+    let impl_fn = if let Some(uuid) = container_attrs.opaque_uuid {
+        let vars = (0..type_params.len() as i64).map(|i| quote! { binprot::Shape::Var(#i) });
+        quote! { binprot::Shape::basetype(#uuid, vec![#(#vars),*]) }
+    } else if let Some(uuid) = container_attrs.uuid {
+        quote! { binprot::Shape::annotate(#uuid, #impl_fn) }
+    } else {
+        impl_fn
+    };
+
+    let args = type_params
+        .iter()
+        .map(|p| quote! { <#p as binprot::BinProtShape>::binprot_shape_loop(_c) });
+    let args = quote! { vec![#(#args),*] };
+    let definition = shape_var_type(&parse_quote! { #ident #self_generics }, &type_params);
+    let tagged_impl = match poly_variant {
+        Some((tags, hashes)) if !is_opaque => {
+            // The constant is only evaluated when used, i.e. when building the
+            // instances of generic types. Types without parameters are checked
+            // right away so that collisions are also reported by cargo check.
+            let check = if type_params.is_empty() {
+                Some(quote! {
+                    const _: &binprot::TagHashes =
+                        &<#ident #self_generics as binprot::BinProtShapeTagged>::BINPROT_TAG_HASHES;
+                })
+            } else {
+                None
+            };
+            Some(quote! {
+                impl #impl_generics binprot::BinProtShapeTagged for #ident #self_generics #where_clause {
+                    const BINPROT_TAG_HASHES: binprot::TagHashes = #hashes;
+
+                    fn binprot_shape_tagged(
+                        _c: &mut binprot::ShapeContext,
+                    ) -> std::collections::BTreeMap<std::borrow::Cow<'static, str>, Option<binprot::Shape>> {
+                        let args = #args;
+                        let typeid = std::any::TypeId::of::<#definition>();
+                        binprot::binprot_shape_inherited(_c, typeid, args, |_c| #tags)
+                    }
+                }
+                #check
+            })
         }
+        _ => None,
     };
 
     let output = quote! {
-        impl #impl_generics binprot::BinProtShape for #ident #ty_generics #where_clause {
+        impl #impl_generics binprot::BinProtShape for #ident #self_generics #where_clause {
             fn binprot_shape_impl(_c: &mut binprot::ShapeContext) -> binprot::Shape {
                 #impl_fn
             }
+
+            fn binprot_shape_loop(_c: &mut binprot::ShapeContext) -> binprot::Shape {
+                let args = #args;
+                let typeid = std::any::TypeId::of::<#definition>();
+                binprot::binprot_shape_definition(_c, typeid, args, Self::binprot_shape_impl)
+            }
         }
+        #tagged_impl
     };
 
-    output.into()
+    Ok(output)
+}
+
+// BinProtShape requires 'static, shapes do not depend on lifetimes so the shape
+// is implemented for the type with all its lifetime parameters set to 'static.
+fn static_lifetimes(ast: &DeriveInput) -> DeriveInput {
+    struct Static(Vec<syn::Lifetime>);
+    impl syn::visit_mut::VisitMut for Static {
+        fn visit_lifetime_mut(&mut self, lifetime: &mut syn::Lifetime) {
+            if self.0.contains(lifetime) {
+                *lifetime = syn::Lifetime::new("'static", lifetime.span())
+            }
+        }
+    }
+    let mut ast = ast.clone();
+    let lifetimes = ast.generics.lifetimes().map(|l| l.lifetime.clone()).collect();
+    let params = std::mem::take(&mut ast.generics.params);
+    ast.generics.params =
+        params.into_iter().filter(|p| !matches!(p, GenericParam::Lifetime(_))).collect();
+    let mut visitor = Static(lifetimes);
+    syn::visit_mut::visit_generics_mut(&mut visitor, &mut ast.generics);
+    syn::visit_mut::visit_data_mut(&mut visitor, &mut ast.data);
+    ast
+}
+
+// The generic arguments of the type with its lifetime parameters set to 'static.
+fn static_ty_generics(generics: &syn::Generics) -> proc_macro2::TokenStream {
+    if generics.params.is_empty() {
+        return quote! {};
+    }
+    let args = generics.params.iter().map(|param| match param {
+        GenericParam::Lifetime(_) => quote! { 'static },
+        GenericParam::Type(t) => t.ident.to_token_stream(),
+        GenericParam::Const(c) => c.ident.to_token_stream(),
+    });
+    quote! { <#(#args),*> }
+}
+
+// Replaces the type parameters in a type with the binprot::ShapeVar marker
+// matching their position. Only bare type parameters are replaced: qualified
+// paths such as `T::Assoc` or `<T as Trait>::Assoc` are left untouched as their
+// shape depends on the actual parameter.
+fn shape_var_type(ty: &syn::Type, type_params: &[syn::Ident]) -> syn::Type {
+    struct ShapeVars<'a>(&'a [syn::Ident]);
+
+    impl syn::visit_mut::VisitMut for ShapeVars<'_> {
+        fn visit_type_mut(&mut self, ty: &mut syn::Type) {
+            if let syn::Type::Path(syn::TypePath { qself: None, path }) = ty {
+                if let Some(index) = self.0.iter().position(|p| path.is_ident(p)) {
+                    let index = proc_macro2::Literal::usize_unsuffixed(index);
+                    *ty = parse_quote! { binprot::ShapeVar<#index> };
+                    return;
+                }
+            }
+            syn::visit_mut::visit_type_mut(self, ty)
+        }
+
+        fn visit_type_path_mut(&mut self, type_path: &mut syn::TypePath) {
+            let first = type_path.path.segments.first();
+            if type_path.qself.is_none() && !first.is_some_and(|s| self.0.contains(&s.ident)) {
+                syn::visit_mut::visit_type_path_mut(self, type_path)
+            }
+        }
+    }
+
+    let mut ty = ty.clone();
+    syn::visit_mut::VisitMut::visit_type_mut(&mut ShapeVars(type_params), &mut ty);
+    ty
+}
+
+// Shapes can only represent regular recursion, i.e. the type being derived has to
+// be used with its own type parameters in its fields. Other uses, e.g. the field
+// of `struct Nested<T>(Option<Box<Nested<(T, T)>>>)`, are rejected.
+fn check_regular_recursion(ast: &DeriveInput, type_params: &[syn::Ident]) -> syn::Result<()> {
+    struct Recursion<'a> {
+        ident: &'a syn::Ident,
+        type_params: &'a [syn::Ident],
+        error: Option<syn::Error>,
+    }
+
+    impl syn::visit_mut::VisitMut for Recursion<'_> {
+        fn visit_path_mut(&mut self, path: &mut syn::Path) {
+            let segment = &path.segments[path.segments.len() - 1];
+            if path.segments.len() == 1 && segment.ident == *self.ident && self.error.is_none() {
+                let args: Vec<&syn::Type> = match &segment.arguments {
+                    syn::PathArguments::AngleBracketed(args) => args
+                        .args
+                        .iter()
+                        .filter_map(|arg| match arg {
+                            syn::GenericArgument::Type(ty) => Some(ty),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => vec![],
+                };
+                let regular = args.len() == self.type_params.len()
+                    && args.iter().zip(self.type_params.iter()).all(|(arg, param)| match arg {
+                        syn::Type::Path(arg) => arg.qself.is_none() && arg.path.is_ident(param),
+                        _ => false,
+                    });
+                if !regular {
+                    let msg = "recursive uses of the type have to take its own type parameters";
+                    self.error = Some(syn::Error::new_spanned(&*path, msg))
+                }
+            }
+            syn::visit_mut::visit_path_mut(self, path)
+        }
+    }
+
+    let mut visitor = Recursion { ident: &ast.ident, type_params, error: None };
+    let fields: Vec<&syn::Field> = match &ast.data {
+        syn::Data::Struct(s) => s.fields.iter().collect(),
+        syn::Data::Enum(DataEnum { variants, .. }) => {
+            variants.iter().flat_map(|variant| variant.fields.iter()).collect()
+        }
+        syn::Data::Union(_) => vec![],
+    };
+    for field in fields {
+        let attrs = FieldAttrs::of(field);
+        if !attrs.skip && attrs.with.is_none() {
+            syn::visit_mut::visit_type_mut(&mut visitor, &mut field.ty.clone())
+        }
+    }
+    match visitor.error {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
 }
 
 #[cfg(test)]
@@ -408,4 +1522,40 @@ mod tests {
         assert_eq!(hash_variant("FooBar"), 805748365);
         assert_eq!(hash_variant("FooBarBazAndEvenMoreAlternatives"), 74946334);
     }
+
+    #[test]
+    fn test_rename_rules() {
+        let rule =
+            |rule: &str| RenameRule::parse(&syn::LitStr::new(rule, proc_macro2::Span::call_site()));
+        let rename = |r: &str, variant: &str, field: &str| {
+            let r = rule(r).unwrap();
+            (r.apply_to_variant(variant), r.apply_to_field(field))
+        };
+        // Case conversions apply to non-ASCII characters too.
+        assert_eq!(
+            rename("camelCase", "ÉtéCrêpes", "été_crêpes"),
+            ("étéCrêpes".into(), "étéCrêpes".into())
+        );
+        assert_eq!(
+            rename("PascalCase", "Crêpes", "_été__crêpes"),
+            ("Crêpes".into(), "ÉtéCrêpes".into())
+        );
+        assert_eq!(
+            rename("SCREAMING_SNAKE_CASE", "ÉtéCrêpes", "été_straße"),
+            ("ÉTÉ_CRÊPES".into(), "ÉTÉ_STRASSE".into())
+        );
+        assert_eq!(rename("lowercase", "ÉtéCrêpes", "x"), ("étécrêpes".into(), "x".into()));
+        assert_eq!(rename("camelCase", "Crêpes", "_"), ("crêpes".into(), "".into()));
+        let rename = |r| rename(r, "MorePancakes", "more_pancakes");
+        assert_eq!(rename("lowercase"), ("morepancakes".into(), "more_pancakes".into()));
+        assert_eq!(rename("UPPERCASE"), ("MOREPANCAKES".into(), "MORE_PANCAKES".into()));
+        assert_eq!(rename("PascalCase"), ("MorePancakes".into(), "MorePancakes".into()));
+        assert_eq!(rename("camelCase"), ("morePancakes".into(), "morePancakes".into()));
+        assert_eq!(rename("snake_case"), ("more_pancakes".into(), "more_pancakes".into()));
+        assert_eq!(
+            rename("SCREAMING_SNAKE_CASE"),
+            ("MORE_PANCAKES".into(), "MORE_PANCAKES".into())
+        );
+        assert!(rule("kebab-case").is_err());
+    }
 }
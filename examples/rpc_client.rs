@@ -4,16 +4,16 @@
 //
 // RPC magic number 4_411_474
 use anyhow::Result;
-use binprot::macros::{BinProtRead, BinProtWrite};
+use binprot::macros::{BinProtRead, BinProtSize, BinProtWrite};
 use binprot::{BinProtRead, BinProtSize, BinProtWrite};
 use std::collections::BTreeMap;
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
 
-#[derive(BinProtRead, BinProtWrite, Debug, Clone, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, Clone, PartialEq)]
 struct Handshake(Vec<i64>);
 
-#[derive(BinProtRead, BinProtWrite, Clone, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Clone, PartialEq)]
 enum Sexp {
     Atom(String),
     List(Vec<Sexp>),
@@ -50,7 +50,7 @@ impl std::fmt::Debug for Sexp {
     }
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, Clone, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, Clone, PartialEq)]
 struct Query<T> {
     rpc_tag: String,
     version: i64,
@@ -58,13 +58,13 @@ struct Query<T> {
     data: binprot::WithLen<T>,
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, Clone, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, Clone, PartialEq)]
 #[polymorphic_variant]
 enum Version {
     Version(i64),
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, Clone, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, Clone, PartialEq)]
 enum RpcError {
     BinIoExn(Sexp),
     ConnectionClosed,
@@ -74,19 +74,19 @@ enum RpcError {
     UnknownQueryId(String),
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, Clone, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, Clone, PartialEq)]
 enum RpcResult<T> {
     Ok(binprot::WithLen<T>),
     Error(RpcError),
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, Clone, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, Clone, PartialEq)]
 struct Response<T> {
     id: i64,
     data: RpcResult<T>,
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, Clone, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, Clone, PartialEq)]
 enum Message<Q, R> {
     Heartbeat,
     Query(Query<Q>),
@@ -104,7 +104,7 @@ fn read_bin_prot<T: BinProtRead>(stream: &mut TcpStream, buffer: &mut Vec<u8>) -
     Ok(data)
 }
 
-fn write_bin_prot<T: BinProtWrite>(stream: &mut TcpStream, v: &T) -> Result<()> {
+fn write_bin_prot<T: BinProtWrite + BinProtSize>(stream: &mut TcpStream, v: &T) -> Result<()> {
     let len = v.binprot_size() as i64;
     stream.write_all(&len.to_le_bytes())?;
     v.binprot_write(stream)?;
@@ -161,7 +161,7 @@ impl RpcClient {
 
     fn dispatch<T: JRpc>(&mut self, query: T::Q) -> Result<Response<T::R>>
     where
-        T::Q: BinProtWrite,
+        T::Q: BinProtWrite + BinProtSize,
         T::R: BinProtRead,
     {
         self.id += 1;
@@ -193,7 +193,7 @@ trait JRpcImpl {
 }
 
 trait ErasedJRpcImpl {
-    fn erased_rpc_impl(&mut self, stream: &mut TcpStream, id: i64) -> Result<()>;
+    fn erased_rpc_impl(&mut self, stream: &mut TcpStream, id: i64, len: u64) -> Result<()>;
 }
 
 //impl<Q, R, E> ErasedJRpcImpl for dyn JRpcImpl<Q = Q, R = R, E = E>
@@ -205,17 +205,24 @@ impl<T> ErasedJRpcImpl for T
 where
     T: JRpcImpl,
     T::Q: BinProtRead,
-    T::R: BinProtWrite,
+    T::R: BinProtWrite + BinProtSize,
     T::E: std::error::Error,
 {
-    fn erased_rpc_impl(&mut self, stream: &mut TcpStream, id: i64) -> Result<()> {
-        let query = T::Q::binprot_read(stream)?;
-        let rpc_result = match self.rpc_impl(query) {
-            Ok(response) => RpcResult::Ok(binprot::WithLen(response)),
+    fn erased_rpc_impl(&mut self, stream: &mut TcpStream, id: i64, len: u64) -> Result<()> {
+        // The payload is read up to its announced length so that an invalid query
+        // does not desynchronize the stream.
+        let rpc_result = match binprot::WithLen::<T::Q>::binprot_read_payload(stream, len) {
             Err(error) => {
                 let sexp = Sexp::Atom(error.to_string());
-                RpcResult::Error(RpcError::UncaughtExn(sexp))
+                RpcResult::Error(RpcError::BinIoExn(sexp))
             }
+            Ok(binprot::WithLen(query)) => match self.rpc_impl(query) {
+                Ok(response) => RpcResult::Ok(binprot::WithLen(response)),
+                Err(error) => {
+                    let sexp = Sexp::Atom(error.to_string());
+                    RpcResult::Error(RpcError::UncaughtExn(sexp))
+                }
+            },
         };
         let response = Response { id, data: rpc_result };
         write_bin_prot(stream, &Message::Response::<(), T::R>(response))?;
@@ -250,7 +257,7 @@ impl JRpcImpl for GetUniqueIdImpl {
 // that only parses up to the length of the payload.
 // This only works because the payload appears last in the
 // serialized representation
-#[derive(BinProtRead, BinProtWrite, Debug, Clone, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, Clone, PartialEq)]
 struct ServerQuery {
     rpc_tag: String,
     version: i64,
@@ -258,7 +265,7 @@ struct ServerQuery {
     data: binprot::Nat0,
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, Clone, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, Clone, PartialEq)]
 enum ServerMessage<R> {
     Heartbeat,
     Query(ServerQuery),
@@ -277,43 +284,51 @@ impl RpcServer {
     }
 
     fn run(&mut self) -> Result<()> {
-        for stream in self.listener.incoming() {
-            let mut stream = stream?;
+        loop {
+            let (mut stream, _) = self.listener.accept()?;
             println!("Got connection {stream:?}.");
-            write_bin_prot(&mut stream, &Handshake(vec![4411474, 1]))?;
-            let handshake: Handshake = read_bin_prot(&mut stream, &mut self.buffer)?;
-            println!("Received handshake {handshake:?}");
-            let mut recv_bytes = [0u8; 8];
-            loop {
-                // We don't know the type of rpcs that will be received so the
-                // following parses the incoming messages in a "manual" way.
-                stream.read_exact(&mut recv_bytes)?;
-                let _recv_len = i64::from_le_bytes(recv_bytes);
-                let query = ServerMessage::<()>::binprot_read(&mut stream)?;
-                println!("Received rpc query {query:?}");
-                match query {
-                    ServerMessage::Heartbeat => {}
-                    ServerMessage::Query(query) => match self.rpc_impls.get_mut(&query.rpc_tag) {
-                        None => {
-                            let err = RpcError::UnimplementedRpc((
-                                query.rpc_tag,
-                                Version::Version(query.version),
-                            ));
-                            let message = ServerMessage::Response(Response::<()> {
-                                id: query.id,
-                                data: RpcResult::Error(err),
-                            });
-                            self.buffer.resize(query.data.0 as usize, 0u8);
-                            stream.read_exact(&mut self.buffer)?;
-                            write_bin_prot(&mut stream, &message)?
-                        }
-                        Some(r) => r.erased_rpc_impl(&mut stream, query.id)?,
-                    },
-                    ServerMessage::Response(()) => unimplemented!(),
-                };
+            self.serve(&mut stream)?;
+            println!("Connection closed.");
+        }
+    }
+
+    // Handles the queries of a single client until it closes the connection.
+    fn serve(&mut self, stream: &mut TcpStream) -> Result<()> {
+        write_bin_prot(stream, &Handshake(vec![4411474, 1]))?;
+        let handshake: Handshake = read_bin_prot(stream, &mut self.buffer)?;
+        println!("Received handshake {handshake:?}");
+        let mut recv_bytes = [0u8; 8];
+        loop {
+            // We don't know the type of rpcs that will be received so the
+            // following parses the incoming messages in a "manual" way.
+            match stream.read_exact(&mut recv_bytes) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err.into()),
             }
+            let _recv_len = i64::from_le_bytes(recv_bytes);
+            let query = ServerMessage::<()>::binprot_read(stream)?;
+            println!("Received rpc query {query:?}");
+            match query {
+                ServerMessage::Heartbeat => {}
+                ServerMessage::Query(query) => match self.rpc_impls.get_mut(&query.rpc_tag) {
+                    None => {
+                        let err = RpcError::UnimplementedRpc((
+                            query.rpc_tag,
+                            Version::Version(query.version),
+                        ));
+                        let message = ServerMessage::Response(Response::<()> {
+                            id: query.id,
+                            data: RpcResult::Error(err),
+                        });
+                        binprot::skip_payload(stream, query.data.0)?;
+                        write_bin_prot(stream, &message)?
+                    }
+                    Some(r) => r.erased_rpc_impl(stream, query.id, query.data.0)?,
+                },
+                ServerMessage::Response(()) => unimplemented!(),
+            };
         }
-        Ok(())
     }
 }
 
@@ -1,6 +1,7 @@
 use crate::error::Error;
 use crate::int::{CODE_INT16, CODE_INT32, CODE_INT64, CODE_NEG_INT8};
 use crate::{BinProtRead, BinProtWrite};
+use std::convert::TryFrom;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 pub struct AsyncBuffer(Vec<u8>);
@@ -17,11 +18,15 @@ impl AsyncBuffer {
         let buf = &mut self.0;
         let mut recv_bytes = [0u8; 8];
         r.read_exact(&mut recv_bytes).await?;
-        let recv_len = i64::from_le_bytes(recv_bytes);
-        buf.resize(recv_len as usize, 0u8);
-        r.read_exact(buf).await?;
-        let data = T::binprot_read(&mut buf.as_slice())?;
-        Ok(data)
+        let recv_len = u64::try_from(i64::from_le_bytes(recv_bytes))?;
+        // The length comes from the peer so the buffer only grows as the bytes
+        // arrive rather than being resized upfront.
+        buf.clear();
+        let read = AsyncReadExt::take(&mut *r, recv_len).read_to_end(buf).await?;
+        if (read as u64) < recv_len {
+            return Err(Error::PayloadTooShort { len: recv_len });
+        }
+        crate::binprot_read_bounded(&mut buf.as_slice(), recv_len)
     }
 
     pub async fn write_with_size<T: BinProtWrite, W: AsyncWriteExt + Unpin>(
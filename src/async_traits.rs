@@ -1,16 +1,640 @@
+// Async versions of the read and write traits, values are decoded and encoded
+// field by field directly from/to tokio readers and writers.
+use crate::async_read_write::{read_nat0, read_signed, write_nat0};
+use crate::error::Error;
+use crate::{BinProtSize, BinProtWrite, BufferWithLen, Bytes, Char, Float32, Nat0, Vec32, WithLen};
 use async_trait::async_trait;
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hash};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 #[async_trait]
-pub trait BinProtWriteAsync {
-    async fn binprot_write_async<W: AsyncWriteExt>(&self, w: &mut W) -> std::io::Result<()>;
+pub trait BinProtWriteAsync: Sync {
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()>;
 }
 
 #[async_trait]
-pub trait BinProtReadAsync {
-    async fn binprot_read_async<R: AsyncReadExt + ?Sized>(
+pub trait BinProtReadAsync: Send {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
         r: &mut R,
     ) -> Result<Self, crate::error::Error>
     where
         Self: Sized;
 }
+
+/// Same as `BinProtReadTagged` but decoding from an async reader.
+#[async_trait]
+pub trait BinProtReadAsyncTagged: Sized + Send {
+    async fn binprot_read_async_tagged<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        tag: i32,
+        r: &mut R,
+    ) -> Result<Option<Self>, crate::error::Error>;
+}
+
+// Scalar values are small so they get encoded in a buffer that is then written
+// in one go.
+macro_rules! buffered_write_impls {
+    ( $( $ty: ty ),* ) => {
+        $(
+            #[async_trait]
+            impl BinProtWriteAsync for $ty {
+                async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+                    &self,
+                    w: &mut W,
+                ) -> std::io::Result<()> {
+                    let mut buf = Vec::new();
+                    self.binprot_write(&mut buf)?;
+                    w.write_all(&buf).await
+                }
+            }
+        )*
+    };
+}
+
+buffered_write_impls!(Nat0, i64, f64, Float32, (), bool, Char, Vec<f32>, Vec32);
+buffered_write_impls!(i32, u32, i16, u16, i8, u8, u64, usize, isize);
+
+#[async_trait]
+impl BinProtWriteAsync for String {
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        write_nat0(w, self.len() as u64).await?;
+        w.write_all(self.as_bytes()).await
+    }
+}
+
+#[async_trait]
+impl BinProtWriteAsync for Bytes {
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        write_nat0(w, self.0.len() as u64).await?;
+        w.write_all(&self.0).await
+    }
+}
+
+#[async_trait]
+impl BinProtWriteAsync for BufferWithLen {
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        write_nat0(w, self.0.len() as u64).await?;
+        w.write_all(&self.0).await
+    }
+}
+
+#[async_trait]
+impl<T: BinProtWriteAsync> BinProtWriteAsync for Option<T> {
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        match self {
+            None => w.write_all(&[0u8]).await,
+            Some(v) => {
+                w.write_all(&[1u8]).await?;
+                v.binprot_write_async(w).await
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T: BinProtWriteAsync, E: BinProtWriteAsync> BinProtWriteAsync for Result<T, E> {
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        match self {
+            Ok(v) => {
+                w.write_all(&[0u8]).await?;
+                v.binprot_write_async(w).await
+            }
+            Err(e) => {
+                w.write_all(&[1u8]).await?;
+                e.binprot_write_async(w).await
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T: BinProtWriteAsync> BinProtWriteAsync for Box<T> {
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        self.as_ref().binprot_write_async(w).await
+    }
+}
+
+// Collections are written as their length followed by their elements.
+macro_rules! seq_write_impls {
+    ( $( $ty: ident ),* ) => {
+        $(
+            #[async_trait]
+            impl<T: BinProtWriteAsync> BinProtWriteAsync for std::collections::$ty<T> {
+                async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+                    &self,
+                    w: &mut W,
+                ) -> std::io::Result<()> {
+                    write_nat0(w, self.len() as u64).await?;
+                    for v in self.iter() {
+                        v.binprot_write_async(w).await?
+                    }
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+seq_write_impls!(VecDeque, LinkedList, BTreeSet);
+
+#[async_trait]
+impl<T: BinProtWriteAsync> BinProtWriteAsync for Vec<T> {
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        write_nat0(w, self.len() as u64).await?;
+        for v in self.iter() {
+            v.binprot_write_async(w).await?
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: BinProtWriteAsync, S: Sync> BinProtWriteAsync for std::collections::HashSet<T, S> {
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        write_nat0(w, self.len() as u64).await?;
+        for v in self.iter() {
+            v.binprot_write_async(w).await?
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<K: BinProtWriteAsync, V: BinProtWriteAsync> BinProtWriteAsync
+    for std::collections::BTreeMap<K, V>
+{
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        write_nat0(w, self.len() as u64).await?;
+        for (k, v) in self.iter() {
+            k.binprot_write_async(w).await?;
+            v.binprot_write_async(w).await?
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<K: BinProtWriteAsync, V: BinProtWriteAsync, S: Sync> BinProtWriteAsync
+    for std::collections::HashMap<K, V, S>
+{
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        write_nat0(w, self.len() as u64).await?;
+        for (k, v) in self.iter() {
+            k.binprot_write_async(w).await?;
+            v.binprot_write_async(w).await?
+        }
+        Ok(())
+    }
+}
+
+// Fixed size arrays are serialized as tuples.
+#[async_trait]
+impl<T: BinProtWriteAsync, const N: usize> BinProtWriteAsync for [T; N] {
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        for v in self.iter() {
+            v.binprot_write_async(w).await?
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: BinProtWriteAsync + BinProtSize> BinProtWriteAsync for WithLen<T> {
+    async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+        &self,
+        w: &mut W,
+    ) -> std::io::Result<()> {
+        write_nat0(w, self.0.binprot_size() as u64).await?;
+        self.0.binprot_write_async(w).await
+    }
+}
+
+#[async_trait]
+impl BinProtReadAsync for Nat0 {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let u64 = read_nat0(r).await?;
+        Ok(Nat0(u64))
+    }
+}
+
+#[async_trait]
+impl BinProtReadAsync for i64 {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let i64 = read_signed(r).await?;
+        Ok(i64)
+    }
+}
+
+macro_rules! int_read_impls {
+    ( $( $ty: ty ),* ) => {
+        $(
+            #[async_trait]
+            impl BinProtReadAsync for $ty {
+                async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+                    r: &mut R,
+                ) -> Result<Self, Error> {
+                    let i64 = read_signed(r).await?;
+                    Ok(<$ty>::try_from(i64)?)
+                }
+            }
+        )*
+    };
+}
+
+int_read_impls!(i32, u32, i16, u16, i8, u8, u64, usize, isize);
+
+#[async_trait]
+impl BinProtReadAsync for f64 {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let f64 = r.read_f64_le().await?;
+        Ok(f64)
+    }
+}
+
+#[async_trait]
+impl BinProtReadAsync for Float32 {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let f64 = r.read_f64_le().await?;
+        Ok(Float32(f64 as f32))
+    }
+}
+
+#[async_trait]
+impl BinProtReadAsync for Char {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let c = r.read_u8().await?;
+        Ok(Char(c))
+    }
+}
+
+#[async_trait]
+impl BinProtReadAsync for () {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        match r.read_u8().await? {
+            0 => Ok(()),
+            c => Err(Error::UnexpectedValueForUnit(c)),
+        }
+    }
+}
+
+#[async_trait]
+impl BinProtReadAsync for bool {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        match r.read_u8().await? {
+            0 => Ok(false),
+            1 => Ok(true),
+            c => Err(Error::UnexpectedValueForBool(c)),
+        }
+    }
+}
+
+// Reads a buffer prefixed by its length as a nat0. The length comes from the
+// input so the buffer grows as the bytes arrive rather than being allocated upfront.
+async fn read_buffer<R: AsyncReadExt + Unpin + Send + ?Sized>(r: &mut R) -> Result<Vec<u8>, Error> {
+    let len = read_nat0(r).await?;
+    let mut buf: Vec<u8> = Vec::new();
+    let read = AsyncReadExt::take(&mut *r, len).read_to_end(&mut buf).await?;
+    if (read as u64) < len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(buf)
+}
+
+#[async_trait]
+impl BinProtReadAsync for String {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let buf = read_buffer(r).await?;
+        let str = String::from_utf8(buf).map_err(|err| err.utf8_error())?;
+        Ok(str)
+    }
+}
+
+#[async_trait]
+impl BinProtReadAsync for Bytes {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        Ok(Bytes(read_buffer(r).await?))
+    }
+}
+
+#[async_trait]
+impl BinProtReadAsync for BufferWithLen {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        Ok(BufferWithLen(read_buffer(r).await?))
+    }
+}
+
+#[async_trait]
+impl BinProtReadAsync for Vec<f32> {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let len = read_nat0(r).await?;
+        let mut v: Vec<f32> = Vec::new();
+        for _i in 0..len {
+            let mut bytes = [0u8; 4];
+            r.read_exact(&mut bytes).await?;
+            v.push(f32::from_ne_bytes(bytes))
+        }
+        Ok(v)
+    }
+}
+
+#[async_trait]
+impl BinProtReadAsync for Vec32 {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        Ok(Vec32(Vec::binprot_read_async(r).await?))
+    }
+}
+
+#[async_trait]
+impl<T: BinProtReadAsync> BinProtReadAsync for Option<T> {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        match r.read_u8().await? {
+            0 => Ok(None),
+            1 => Ok(Some(T::binprot_read_async(r).await?)),
+            c => Err(Error::UnexpectedValueForOption(c)),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: BinProtReadAsync, E: BinProtReadAsync> BinProtReadAsync for Result<T, E> {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        match r.read_u8().await? {
+            0 => Ok(Ok(T::binprot_read_async(r).await?)),
+            1 => Ok(Err(E::binprot_read_async(r).await?)),
+            index => Err(Error::UnexpectedVariantIndex { index, ident: "Result" }),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: BinProtReadAsync> BinProtReadAsync for Box<T> {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let v = T::binprot_read_async(r).await?;
+        Ok(Box::new(v))
+    }
+}
+
+#[async_trait]
+impl<T: BinProtReadAsync> BinProtReadAsync for Vec<T> {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let len = read_nat0(r).await?;
+        // The length is not trusted for the allocation as the data has not been
+        // received yet.
+        let mut v: Vec<T> = Vec::new();
+        for _i in 0..len {
+            let item = T::binprot_read_async(r).await?;
+            v.push(item)
+        }
+        Ok(v)
+    }
+}
+
+#[async_trait]
+impl<T: BinProtReadAsync> BinProtReadAsync for std::collections::VecDeque<T> {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let v = Vec::<T>::binprot_read_async(r).await?;
+        Ok(v.into())
+    }
+}
+
+#[async_trait]
+impl<T: BinProtReadAsync> BinProtReadAsync for std::collections::LinkedList<T> {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let v = Vec::<T>::binprot_read_async(r).await?;
+        Ok(v.into_iter().collect())
+    }
+}
+
+#[async_trait]
+impl<T: BinProtReadAsync, const N: usize> BinProtReadAsync for [T; N] {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let mut v: Vec<T> = Vec::with_capacity(N);
+        for _i in 0..N {
+            let item = T::binprot_read_async(r).await?;
+            v.push(item)
+        }
+        Ok(crate::array_of_vec(v))
+    }
+}
+
+#[async_trait]
+impl<K, V> BinProtReadAsync for std::collections::BTreeMap<K, V>
+where
+    K: BinProtReadAsync + Ord,
+    V: BinProtReadAsync,
+{
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let len = read_nat0(r).await?;
+        let mut res = std::collections::BTreeMap::new();
+        for _i in 0..len {
+            let k = K::binprot_read_async(r).await?;
+            let v = V::binprot_read_async(r).await?;
+            if res.insert(k, v).is_some() {
+                return Err(Error::SameKeyAppearsTwiceInMap);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl<K, V, S> BinProtReadAsync for std::collections::HashMap<K, V, S>
+where
+    K: BinProtReadAsync + Hash + Eq,
+    V: BinProtReadAsync,
+    S: BuildHasher + Default + Send,
+{
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let len = read_nat0(r).await?;
+        let mut res = std::collections::HashMap::with_hasher(S::default());
+        for _i in 0..len {
+            let k = K::binprot_read_async(r).await?;
+            let v = V::binprot_read_async(r).await?;
+            if res.insert(k, v).is_some() {
+                return Err(Error::SameKeyAppearsTwiceInMap);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl<T: BinProtReadAsync + Ord> BinProtReadAsync for std::collections::BTreeSet<T> {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let len = read_nat0(r).await?;
+        let mut res = std::collections::BTreeSet::new();
+        for _i in 0..len {
+            let v = T::binprot_read_async(r).await?;
+            if !res.insert(v) {
+                return Err(Error::SameValueAppearsTwiceInSet);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl<T, S> BinProtReadAsync for std::collections::HashSet<T, S>
+where
+    T: BinProtReadAsync + Hash + Eq,
+    S: BuildHasher + Default + Send,
+{
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let len = read_nat0(r).await?;
+        let mut res = std::collections::HashSet::with_hasher(S::default());
+        for _i in 0..len {
+            let v = T::binprot_read_async(r).await?;
+            if !res.insert(v) {
+                return Err(Error::SameValueAppearsTwiceInSet);
+            }
+        }
+        Ok(res)
+    }
+}
+
+// Same behavior as the BinProtRead implementation: decoding cannot go past the
+// length prefix and the remaining bytes are skipped, including when decoding fails.
+#[async_trait]
+impl<T: BinProtReadAsync> BinProtReadAsync for WithLen<T> {
+    async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let len = read_nat0(r).await?;
+        let mut payload = AsyncReadExt::take(&mut *r, len);
+        let v = T::binprot_read_async(&mut payload).await;
+        let remaining = payload.limit();
+        if remaining != 0
+            && tokio::io::copy(&mut payload, &mut tokio::io::sink()).await? < remaining
+        {
+            return Err(Error::PayloadTooShort { len });
+        }
+        match v {
+            Ok(_) if remaining != 0 => Err(Error::PayloadNotFullyConsumed { len, remaining }),
+            Ok(v) => Ok(WithLen(v)),
+            Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Err(Error::PayloadTooShort { len })
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+macro_rules! tuple_impls {
+    ( $( $name:ident )+ ) => {
+        #[async_trait]
+        impl<$($name: BinProtWriteAsync),+> BinProtWriteAsync for ($($name,)+) {
+            #[allow(non_snake_case)]
+            async fn binprot_write_async<W: AsyncWriteExt + Unpin + Send>(
+                &self,
+                w: &mut W,
+            ) -> std::io::Result<()> {
+                let ($($name,)+) = self;
+                $($name.binprot_write_async(w).await?;)+
+                Ok(())
+            }
+        }
+
+        #[async_trait]
+        impl<$($name: BinProtReadAsync),+> BinProtReadAsync for ($($name,)+) {
+            #[allow(non_snake_case)]
+            async fn binprot_read_async<R: AsyncReadExt + Unpin + Send + ?Sized>(
+                r: &mut R,
+            ) -> Result<Self, Error> {
+                $(let $name = $name::binprot_read_async(r).await?;)+
+                Ok(($($name,)+))
+            }
+        }
+    };
+}
+
+tuple_impls! { A }
+tuple_impls! { A B }
+tuple_impls! { A B C }
+tuple_impls! { A B C D }
+tuple_impls! { A B C D E }
+tuple_impls! { A B C D E F }
+tuple_impls! { A B C D E F G }
+tuple_impls! { A B C D E F G H }
+tuple_impls! { A B C D E F G H I }
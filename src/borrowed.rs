@@ -0,0 +1,267 @@
+// Zero-copy decoding from byte slices.
+// Owned types are decoded in the same way as with BinProtRead whereas strings
+// and bytes can be borrowed from the input slice.
+use crate::error::Error;
+use crate::traits::BinProtReadBorrowed;
+use crate::{
+    int, BinProtRead, BorrowedBytes, BufferWithLen, Bytes, Char, Float32, Nat0, Vec32, WithLen,
+};
+use std::convert::TryFrom;
+use std::hash::{BuildHasher, Hash};
+
+impl<'de: 'a, 'a> BinProtReadBorrowed<'de> for BorrowedBytes<'a> {
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let len = int::read_nat0(r)?;
+        let len = usize::try_from(len)?;
+        if r.len() < len {
+            let err = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "borrowed bytes");
+            return Err(Error::IoError(err));
+        }
+        let (bytes, rest) = r.split_at(len);
+        *r = rest;
+        Ok(BorrowedBytes(bytes))
+    }
+}
+
+impl<'de: 'a, 'a> BinProtReadBorrowed<'de> for &'a str {
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let BorrowedBytes(bytes) = BorrowedBytes::binprot_read_borrowed(r)?;
+        let str = std::str::from_utf8(bytes)?;
+        Ok(str)
+    }
+}
+
+impl<'de, T: BinProtReadBorrowed<'de>> BinProtReadBorrowed<'de> for Option<T> {
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let c = u8::binprot_read_borrowed(r)?;
+        match c {
+            0 => Ok(None),
+            1 => Ok(Some(T::binprot_read_borrowed(r)?)),
+            c => Err(Error::UnexpectedValueForOption(c)),
+        }
+    }
+}
+
+impl<'de, T: BinProtReadBorrowed<'de>, E: BinProtReadBorrowed<'de>> BinProtReadBorrowed<'de>
+    for Result<T, E>
+{
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let c = u8::binprot_read_borrowed(r)?;
+        match c {
+            0 => Ok(Ok(T::binprot_read_borrowed(r)?)),
+            1 => Ok(Err(E::binprot_read_borrowed(r)?)),
+            index => Err(Error::UnexpectedVariantIndex { index, ident: "Result" }),
+        }
+    }
+}
+
+impl<'de, T: BinProtReadBorrowed<'de>> BinProtReadBorrowed<'de> for Box<T> {
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let v = T::binprot_read_borrowed(r)?;
+        Ok(Box::new(v))
+    }
+}
+
+impl<'de, T: BinProtReadBorrowed<'de>> BinProtReadBorrowed<'de> for Vec<T> {
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let len = int::read_nat0(r)?;
+        // Each element uses at least one byte, this avoids large allocations on
+        // invalid lengths.
+        let mut v: Vec<T> = Vec::with_capacity(std::cmp::min(len as usize, r.len()));
+        for _i in 0..len {
+            let item = T::binprot_read_borrowed(r)?;
+            v.push(item)
+        }
+        Ok(v)
+    }
+}
+
+impl<'de, T: BinProtReadBorrowed<'de>, const N: usize> BinProtReadBorrowed<'de> for [T; N] {
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let mut v: Vec<T> = Vec::with_capacity(N);
+        for _i in 0..N {
+            let item = T::binprot_read_borrowed(r)?;
+            v.push(item)
+        }
+        Ok(crate::array_of_vec(v))
+    }
+}
+
+impl<'de, K, V> BinProtReadBorrowed<'de> for std::collections::BTreeMap<K, V>
+where
+    K: BinProtReadBorrowed<'de> + Ord,
+    V: BinProtReadBorrowed<'de>,
+{
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let len = int::read_nat0(r)?;
+        let mut res = std::collections::BTreeMap::new();
+        for _i in 0..len {
+            let k = K::binprot_read_borrowed(r)?;
+            let v = V::binprot_read_borrowed(r)?;
+            if res.insert(k, v).is_some() {
+                return Err(Error::SameKeyAppearsTwiceInMap);
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl<'de, K, V, S> BinProtReadBorrowed<'de> for std::collections::HashMap<K, V, S>
+where
+    K: BinProtReadBorrowed<'de> + Hash + Eq,
+    V: BinProtReadBorrowed<'de>,
+    S: BuildHasher + Default,
+{
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let len = int::read_nat0(r)?;
+        let mut res = std::collections::HashMap::with_hasher(S::default());
+        for _i in 0..len {
+            let k = K::binprot_read_borrowed(r)?;
+            let v = V::binprot_read_borrowed(r)?;
+            if res.insert(k, v).is_some() {
+                return Err(Error::SameKeyAppearsTwiceInMap);
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl<'de, T> BinProtReadBorrowed<'de> for std::collections::BTreeSet<T>
+where
+    T: BinProtReadBorrowed<'de> + Ord,
+{
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let len = int::read_nat0(r)?;
+        let mut res = std::collections::BTreeSet::new();
+        for _i in 0..len {
+            let v = T::binprot_read_borrowed(r)?;
+            if !res.insert(v) {
+                return Err(Error::SameValueAppearsTwiceInSet);
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl<'de, T, S> BinProtReadBorrowed<'de> for std::collections::HashSet<T, S>
+where
+    T: BinProtReadBorrowed<'de> + Hash + Eq,
+    S: BuildHasher + Default,
+{
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let len = int::read_nat0(r)?;
+        let mut res = std::collections::HashSet::with_hasher(S::default());
+        for _i in 0..len {
+            let v = T::binprot_read_borrowed(r)?;
+            if !res.insert(v) {
+                return Err(Error::SameValueAppearsTwiceInSet);
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl<'de, T: BinProtReadBorrowed<'de>> BinProtReadBorrowed<'de> for std::collections::VecDeque<T> {
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let v = Vec::<T>::binprot_read_borrowed(r)?;
+        Ok(v.into())
+    }
+}
+
+impl<'de, T: BinProtReadBorrowed<'de>> BinProtReadBorrowed<'de>
+    for std::collections::LinkedList<T>
+{
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let v = Vec::<T>::binprot_read_borrowed(r)?;
+        Ok(v.into_iter().collect())
+    }
+}
+
+impl<'de, T: BinProtReadBorrowed<'de>> BinProtReadBorrowed<'de> for WithLen<T> {
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+        let len = int::read_nat0(r)?;
+        let len = usize::try_from(len)?;
+        if r.len() < len {
+            return Err(Error::PayloadTooShort { len: len as u64 });
+        }
+        // The payload is skipped even if it cannot be decoded, as with BinProtRead.
+        let (mut payload, rest) = r.split_at(len);
+        *r = rest;
+        let v = match T::binprot_read_borrowed(&mut payload) {
+            Ok(v) => v,
+            Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(Error::PayloadTooShort { len: len as u64 })
+            }
+            Err(err) => return Err(err),
+        };
+        if !payload.is_empty() {
+            let remaining = payload.len() as u64;
+            return Err(Error::PayloadNotFullyConsumed { len: len as u64, remaining });
+        }
+        Ok(WithLen(v))
+    }
+}
+
+macro_rules! tuple_impls {
+    ( $( $name:ident )+ ) => {
+        impl<'de, $($name: BinProtReadBorrowed<'de>),+> BinProtReadBorrowed<'de> for ($($name,)+)
+        {
+            #[allow(non_snake_case)]
+            fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+                $(let $name = $name::binprot_read_borrowed(r)?;)+
+                Ok(($($name,)+))
+            }
+        }
+    };
+}
+
+tuple_impls! { A }
+tuple_impls! { A B }
+tuple_impls! { A B C }
+tuple_impls! { A B C D }
+tuple_impls! { A B C D E }
+tuple_impls! { A B C D E F }
+tuple_impls! { A B C D E F G }
+tuple_impls! { A B C D E F G H }
+tuple_impls! { A B C D E F G H I }
+
+// Types that do not borrow anything are decoded using their BinProtRead
+// implementation, the slice is advanced accordingly.
+macro_rules! owned_impls {
+    ( $( $ty: ty ),* ) => {
+        $(
+            impl<'de> BinProtReadBorrowed<'de> for $ty {
+                fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, Error> {
+                    <$ty as BinProtRead>::binprot_read(r)
+                }
+            }
+        )*
+    };
+}
+
+owned_impls!(Nat0, i64, f64, Float32, (), bool, Char, String, Bytes, BufferWithLen, Vec32);
+owned_impls!(Vec<f32>);
+owned_impls!(i32, u32, i16, u16, i8, u8, u64, usize, isize);
+
+/// Serializes `&[u8]` fields as OCaml bytes rather than as an array of ints, to be
+/// used with `#[binprot(with = "binprot::bytes")]`. The bytes are borrowed from the
+/// input when reading via `BinProtReadBorrowed`.
+pub mod bytes {
+    use crate::{BinProtReadBorrowed, BinProtShape, BinProtSize, BinProtWrite, BorrowedBytes};
+
+    pub fn binprot_write<W: std::io::Write>(v: &&[u8], w: &mut W) -> std::io::Result<()> {
+        BorrowedBytes(v).binprot_write(w)
+    }
+
+    pub fn binprot_size(v: &&[u8]) -> usize {
+        BorrowedBytes(v).binprot_size()
+    }
+
+    pub fn binprot_read_borrowed<'de>(r: &mut &'de [u8]) -> Result<&'de [u8], crate::Error> {
+        Ok(BorrowedBytes::binprot_read_borrowed(r)?.0)
+    }
+
+    pub fn binprot_shape() -> crate::Shape {
+        BorrowedBytes::binprot_shape()
+    }
+}
@@ -0,0 +1,354 @@
+// Structural comparison of shapes, used to explain why two digests differ and
+// whether the change affects the binary encoding.
+use crate::shape::{ITERABLE_BINABLE1_UUID, ITERABLE_BINABLE_UUID};
+use crate::Shape;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShapeChangeKind {
+    FieldAdded(String),
+    FieldRemoved(String),
+    /// A field was renamed but kept its position and type.
+    FieldRenamed {
+        old: String,
+        new: String,
+    },
+    FieldsReordered,
+    ConstructorAdded(String),
+    ConstructorRemoved(String),
+    /// A constructor was renamed but kept its position and arguments.
+    ConstructorRenamed {
+        old: String,
+        new: String,
+    },
+    ConstructorsReordered,
+    TypeChanged {
+        old: Shape,
+        new: Shape,
+    },
+}
+
+/// A difference between two shapes, `path` contains the field, constructor
+/// or tuple index names leading to the place where the shapes diverge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShapeChange {
+    pub path: Vec<String>,
+    pub kind: ShapeChangeKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShapeDiff {
+    pub changes: Vec<ShapeChange>,
+    /// Whether both shapes result in the same binary encoding, in which case
+    /// values can still be exchanged even though the digests differ.
+    pub wire_compatible: bool,
+}
+
+impl ShapeDiff {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl std::fmt::Display for ShapeChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path.join("."))?
+        }
+        match &self.kind {
+            ShapeChangeKind::FieldAdded(name) => write!(f, "field {name} added"),
+            ShapeChangeKind::FieldRemoved(name) => write!(f, "field {name} removed"),
+            ShapeChangeKind::FieldRenamed { old, new } => {
+                write!(f, "field {old} renamed to {new}")
+            }
+            ShapeChangeKind::FieldsReordered => write!(f, "fields reordered"),
+            ShapeChangeKind::ConstructorAdded(name) => write!(f, "constructor {name} added"),
+            ShapeChangeKind::ConstructorRemoved(name) => write!(f, "constructor {name} removed"),
+            ShapeChangeKind::ConstructorRenamed { old, new } => {
+                write!(f, "constructor {old} renamed to {new}")
+            }
+            ShapeChangeKind::ConstructorsReordered => write!(f, "constructors reordered"),
+            ShapeChangeKind::TypeChanged { old, new } => {
+                write!(f, "type changed from {} to {}", old.to_string_mach(), new.to_string_mach())
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ShapeDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for change in self.changes.iter() {
+            writeln!(f, "{change}")?
+        }
+        let compatible =
+            if self.wire_compatible { "wire compatible" } else { "not wire compatible" };
+        write!(f, "{compatible}")
+    }
+}
+
+// Pairs the elements of two lists of names: identical names first, then
+// elements at the same position that have the same encoding are considered as
+// renamed.
+struct Matching {
+    common: Vec<(usize, usize)>,
+    renamed: Vec<usize>,
+    added: Vec<usize>,
+    removed: Vec<usize>,
+    reordered: bool,
+}
+
+fn match_names(old: &[&str], new: &[&str], same_wire: impl Fn(usize) -> bool) -> Matching {
+    let common: Vec<_> = old
+        .iter()
+        .enumerate()
+        .filter_map(|(i, name)| new.iter().position(|n| n == name).map(|j| (i, j)))
+        .collect();
+    let mut removed: Vec<_> =
+        (0..old.len()).filter(|i| !common.iter().any(|c| c.0 == *i)).collect();
+    let mut added: Vec<_> = (0..new.len()).filter(|j| !common.iter().any(|c| c.1 == *j)).collect();
+    let renamed: Vec<_> =
+        removed.iter().copied().filter(|i| added.contains(i) && same_wire(*i)).collect();
+    removed.retain(|i| !renamed.contains(i));
+    added.retain(|j| !renamed.contains(j));
+    let reordered = common.windows(2).any(|w| w[0].1 > w[1].1);
+    Matching { common, renamed, added, removed, reordered }
+}
+
+struct Differ {
+    changes: Vec<ShapeChange>,
+}
+
+impl Differ {
+    fn push(&mut self, path: &[String], kind: ShapeChangeKind) {
+        self.changes.push(ShapeChange { path: path.to_vec(), kind })
+    }
+
+    fn type_changed(&mut self, path: &[String], old: &Shape, new: &Shape) {
+        self.push(path, ShapeChangeKind::TypeChanged { old: old.clone(), new: new.clone() })
+    }
+
+    fn diff_list(
+        &mut self,
+        path: &[String],
+        old: &[Shape],
+        new: &[Shape],
+        old_s: &Shape,
+        new_s: &Shape,
+    ) {
+        if old.len() != new.len() {
+            return self.type_changed(path, old_s, new_s);
+        }
+        for (index, (old, new)) in old.iter().zip(new.iter()).enumerate() {
+            let path = with(path, &index.to_string());
+            self.diff(&path, old, new)
+        }
+    }
+
+    fn diff(&mut self, path: &[String], old: &Shape, new: &Shape) {
+        if old == new {
+            return;
+        }
+        match (old, new) {
+            (Shape::Annotate(old_uuid, old_t), Shape::Annotate(new_uuid, new_t))
+                if old_uuid == new_uuid =>
+            {
+                self.diff(path, old_t, new_t)
+            }
+            (Shape::Base(old_uuid, old_args), Shape::Base(new_uuid, new_args))
+                if old_uuid == new_uuid && old_args.len() == new_args.len() =>
+            {
+                self.diff_list(path, old_args, new_args, old, new)
+            }
+            (Shape::Tuple(old_v), Shape::Tuple(new_v)) => {
+                self.diff_list(path, old_v, new_v, old, new)
+            }
+            (Shape::Application(old_t, old_args), Shape::Application(new_t, new_args)) => {
+                self.diff(path, old_t, new_t);
+                self.diff_list(path, old_args, new_args, old, new)
+            }
+            (Shape::Record(old_fields), Shape::Record(new_fields)) => {
+                let old_names: Vec<_> = old_fields.iter().map(|f| f.0.as_ref()).collect();
+                let new_names: Vec<_> = new_fields.iter().map(|f| f.0.as_ref()).collect();
+                let m = match_names(&old_names, &new_names, |i| {
+                    wire_eq(&old_fields[i].1, &new_fields[i].1)
+                });
+                for &i in m.removed.iter() {
+                    self.push(path, ShapeChangeKind::FieldRemoved(old_names[i].to_string()))
+                }
+                for &j in m.added.iter() {
+                    self.push(path, ShapeChangeKind::FieldAdded(new_names[j].to_string()))
+                }
+                for &i in m.renamed.iter() {
+                    let old = old_names[i].to_string();
+                    let new = new_names[i].to_string();
+                    self.push(path, ShapeChangeKind::FieldRenamed { old, new });
+                    self.diff(&with(path, new_names[i]), &old_fields[i].1, &new_fields[i].1)
+                }
+                if m.reordered {
+                    self.push(path, ShapeChangeKind::FieldsReordered)
+                }
+                for &(i, j) in m.common.iter() {
+                    self.diff(&with(path, old_names[i]), &old_fields[i].1, &new_fields[j].1)
+                }
+            }
+            (Shape::Variant(old_cases), Shape::Variant(new_cases)) => {
+                let old_names: Vec<_> = old_cases.iter().map(|c| c.0.as_ref()).collect();
+                let new_names: Vec<_> = new_cases.iter().map(|c| c.0.as_ref()).collect();
+                let m = match_names(&old_names, &new_names, |i| {
+                    wire_eq_list(&old_cases[i].1, &new_cases[i].1)
+                });
+                for &i in m.removed.iter() {
+                    self.push(path, ShapeChangeKind::ConstructorRemoved(old_names[i].to_string()))
+                }
+                for &j in m.added.iter() {
+                    self.push(path, ShapeChangeKind::ConstructorAdded(new_names[j].to_string()))
+                }
+                for &i in m.renamed.iter() {
+                    let old = old_names[i].to_string();
+                    let new = new_names[i].to_string();
+                    self.push(path, ShapeChangeKind::ConstructorRenamed { old, new })
+                }
+                if m.reordered {
+                    self.push(path, ShapeChangeKind::ConstructorsReordered)
+                }
+                for &(i, j) in m.common.iter() {
+                    let (old_args, new_args) = (&old_cases[i].1, &new_cases[j].1);
+                    let path = with(path, old_names[i]);
+                    match (old_args.as_slice(), new_args.as_slice()) {
+                        ([old], [new]) => self.diff(&path, old, new),
+                        _ => {
+                            let old_t = Shape::Tuple(old_args.clone());
+                            let new_t = Shape::Tuple(new_args.clone());
+                            self.diff_list(&path, old_args, new_args, &old_t, &new_t)
+                        }
+                    }
+                }
+            }
+            (Shape::PolyVariant(old_cases), Shape::PolyVariant(new_cases)) => {
+                for name in old_cases.keys().filter(|n| !new_cases.contains_key(*n)) {
+                    self.push(path, ShapeChangeKind::ConstructorRemoved(name.to_string()))
+                }
+                for name in new_cases.keys().filter(|n| !old_cases.contains_key(*n)) {
+                    self.push(path, ShapeChangeKind::ConstructorAdded(name.to_string()))
+                }
+                for (name, old_arg) in old_cases.iter() {
+                    if let Some(new_arg) = new_cases.get(name) {
+                        let path = with(path, name);
+                        match (old_arg, new_arg) {
+                            (Some(old), Some(new)) => self.diff(&path, old, new),
+                            (None, None) => {}
+                            _ => {
+                                let unit = || Shape::Tuple(vec![]);
+                                let old = old_arg.clone().unwrap_or_else(unit);
+                                let new = new_arg.clone().unwrap_or_else(unit);
+                                self.type_changed(&path, &old, &new)
+                            }
+                        }
+                    }
+                }
+            }
+            (old, new) => self.type_changed(path, old, new),
+        }
+    }
+}
+
+fn with(path: &[String], name: &str) -> Vec<String> {
+    let mut path = path.to_vec();
+    path.push(name.to_string());
+    path
+}
+
+// Records and tuples are encoded as the concatenation of their elements.
+fn flatten<'a>(shape: &'a Shape, res: &mut Vec<&'a Shape>) {
+    match shape {
+        Shape::Annotate(_, t) => flatten(t, res),
+        Shape::Tuple(v) => v.iter().for_each(|s| flatten(s, res)),
+        Shape::Record(v) => v.iter().for_each(|(_, s)| flatten(s, res)),
+        shape => res.push(shape),
+    }
+}
+
+fn wire_eq_list(old: &[Shape], new: &[Shape]) -> bool {
+    let (mut old_flat, mut new_flat) = (vec![], vec![]);
+    old.iter().for_each(|s| flatten(s, &mut old_flat));
+    new.iter().for_each(|s| flatten(s, &mut new_flat));
+    old_flat.len() == new_flat.len()
+        && old_flat.iter().zip(new_flat.iter()).all(|(o, n)| wire_eq_single(o, n))
+}
+
+fn wire_eq(old: &Shape, new: &Shape) -> bool {
+    wire_eq_list(std::slice::from_ref(old), std::slice::from_ref(new))
+}
+
+// Base types that share the same encoding, containers serialized via
+// Make_iterable_binable are encoded as arrays of their elements.
+fn wire_base(uuid: &str, args: &[Shape]) -> (&'static str, Vec<Shape>) {
+    if let [Shape::Base(inner, inner_args)] = args {
+        if inner.as_str() == ITERABLE_BINABLE_UUID || inner.as_str() == ITERABLE_BINABLE1_UUID {
+            return ("array", inner_args.clone());
+        }
+    }
+    let uuid = match uuid {
+        "int" | "int32" | "int64" => "int",
+        "string" | "bytes" | "bigstring" => "string",
+        "array" | "list" => "array",
+        _ => return ("", vec![]),
+    };
+    (uuid, args.to_vec())
+}
+
+fn wire_eq_single(old: &Shape, new: &Shape) -> bool {
+    match (old, new) {
+        (Shape::Base(old_uuid, old_args), Shape::Base(new_uuid, new_args)) => {
+            let (old_base, old_args2) = wire_base(old_uuid.as_str(), old_args);
+            let (new_base, new_args2) = wire_base(new_uuid.as_str(), new_args);
+            if old_base.is_empty() || new_base.is_empty() {
+                old_uuid == new_uuid
+                    && old_args.len() == new_args.len()
+                    && old_args.iter().zip(new_args.iter()).all(|(o, n)| wire_eq(o, n))
+            } else {
+                old_base == new_base
+                    && old_args2.len() == new_args2.len()
+                    && old_args2.iter().zip(new_args2.iter()).all(|(o, n)| wire_eq(o, n))
+            }
+        }
+        (Shape::Variant(old_cases), Shape::Variant(new_cases)) => {
+            old_cases.len() == new_cases.len()
+                && old_cases.iter().zip(new_cases.iter()).all(|(o, n)| wire_eq_list(&o.1, &n.1))
+        }
+        (Shape::PolyVariant(old_cases), Shape::PolyVariant(new_cases)) => {
+            old_cases.len() == new_cases.len()
+                && old_cases.iter().zip(new_cases.iter()).all(|((old_name, o), (new_name, n))| {
+                    old_name == new_name
+                        && match (o, n) {
+                            (None, None) => true,
+                            (Some(o), Some(n)) => wire_eq(o, n),
+                            _ => false,
+                        }
+                })
+        }
+        (Shape::Application(old_t, old_args), Shape::Application(new_t, new_args)) => {
+            wire_eq(old_t, new_t)
+                && old_args.len() == new_args.len()
+                && old_args.iter().zip(new_args.iter()).all(|(o, n)| wire_eq(o, n))
+        }
+        (Shape::RecApp(old_n, old_args), Shape::RecApp(new_n, new_args)) => {
+            old_n == new_n
+                && old_args.len() == new_args.len()
+                && old_args.iter().zip(new_args.iter()).all(|(o, n)| wire_eq(o, n))
+        }
+        (Shape::Var(old_v), Shape::Var(new_v)) => old_v == new_v,
+        _ => false,
+    }
+}
+
+impl Shape {
+    /// Compares the canonical forms of two shapes and reports where they diverge,
+    /// e.g. to explain a digest mismatch between two versions of a type.
+    pub fn diff(&self, new: &Shape) -> ShapeDiff {
+        let old = self.eval().into_shape();
+        let new = new.eval().into_shape();
+        let mut differ = Differ { changes: vec![] };
+        differ.diff(&[], &old, &new);
+        ShapeDiff { changes: differ.changes, wire_compatible: wire_eq(&old, &new) }
+    }
+}
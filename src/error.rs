@@ -14,7 +14,34 @@ pub enum Error {
     UnexpectedValueForOption(u8),
     Utf8Error(std::str::Utf8Error),
     SameKeyAppearsTwiceInMap,
+    SameValueAppearsTwiceInSet,
     TryFromIntError(std::num::TryFromIntError),
+    /// The decoder needed more bytes than announced by a length prefix, or the
+    /// input ended before the announced number of bytes.
+    PayloadTooShort {
+        len: u64,
+    },
+    /// The decoder did not consume all the bytes announced by a length prefix.
+    PayloadNotFullyConsumed {
+        len: u64,
+        remaining: u64,
+    },
+    /// The shape cannot be used for dynamic decoding or encoding, e.g. because it
+    /// refers to an unknown base type.
+    UnsupportedShape(Box<crate::Shape>),
+    ValueDoesNotMatchShape {
+        value: Box<crate::Value>,
+        shape: Box<crate::Shape>,
+    },
+    /// The value is nested deeper than supported by the shape driven decoder and
+    /// encoder.
+    NestingTooDeep,
+    /// A polymorphic variant inherits from a shape that is not a polymorphic variant.
+    InheritedNonPolymorphicVariant(Box<crate::Shape>),
+    /// The same tag appears more than once in a polymorphic variant.
+    DuplicatePolyVariantTag(String),
+    /// The string is not a valid sexp representation of a shape.
+    InvalidShapeSexp(String),
     /// For errors raised by custom decoders.
     CustomError(Box<dyn std::error::Error + Sync + Send>),
 }
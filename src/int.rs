@@ -1,4 +1,5 @@
 use byteorder::{LittleEndian, ReadBytesExt};
+use std::convert::TryFrom;
 use std::io::{Read, Write};
 
 pub const CODE_NEG_INT8: u8 = 0xff;
@@ -6,6 +7,25 @@ pub const CODE_INT16: u8 = 0xfe;
 pub const CODE_INT32: u8 = 0xfd;
 pub const CODE_INT64: u8 = 0xfc;
 
+// OCaml ints use 63 bits.
+pub const MAX_INT63: i64 = (1 << 62) - 1;
+pub const MIN_INT63: i64 = -(1 << 62);
+
+/// Converts a value to an OCaml int, an error is returned if the value cannot be
+/// represented using 63 bits.
+pub fn to_int63<T: Copy + std::fmt::Display>(v: T) -> std::io::Result<i64>
+where
+    i64: TryFrom<T>,
+{
+    match i64::try_from(v) {
+        Ok(i) if (MIN_INT63..=MAX_INT63).contains(&i) => Ok(i),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{v} does not fit in an OCaml int (63 bits)"),
+        )),
+    }
+}
+
 pub fn write_nat0<W: Write>(w: &mut W, v: u64) -> std::io::Result<()> {
     if v < 0x000000080 {
         w.write_all(&[v as u8])?;
@@ -52,6 +72,40 @@ pub fn write_i64<W: Write>(w: &mut W, v: i64) -> std::io::Result<()> {
     Ok(())
 }
 
+pub fn nat0_size(v: u64) -> usize {
+    if v < 0x000000080 {
+        1
+    } else if v < 0x000010000 {
+        3
+    } else if v < 0x100000000 {
+        5
+    } else {
+        9
+    }
+}
+
+pub fn i64_size(v: i64) -> usize {
+    if 0 <= v {
+        if v < 0x000000080 {
+            1
+        } else if v < 0x00008000 {
+            3
+        } else if v < 0x80000000 {
+            5
+        } else {
+            9
+        }
+    } else if v >= -0x00000080 {
+        2
+    } else if v >= -0x00008000 {
+        3
+    } else if v >= -0x80000000 {
+        5
+    } else {
+        9
+    }
+}
+
 pub fn read_signed<R: Read + ?Sized>(r: &mut R) -> std::io::Result<i64> {
     let c = r.read_u8()?;
     let v = match c {
@@ -81,6 +135,30 @@ pub fn read_nat0<R: Read + ?Sized>(r: &mut R) -> std::io::Result<u64> {
     Ok(v)
 }
 
+/// The hash function used by OCaml to get the identifier of a polymorphic
+/// variant constructor from its name.
+pub fn hash_variant(str: &str) -> i32 {
+    let mut accu = std::num::Wrapping(0i64);
+    for &v in str.as_bytes().iter() {
+        accu = std::num::Wrapping(223) * accu + std::num::Wrapping(v as i64)
+    }
+    accu &= std::num::Wrapping((1 << 31) - 1);
+    let accu = accu.0;
+    if accu > 0x3FFFFFFF {
+        (accu - (1 << 31)) as i32
+    } else {
+        accu as i32
+    }
+}
+
+// The tag written for a polymorphic variant constructor, binprot_derive has its
+// own copy of this function which is checked against this one in the tests.
+// https://github.com/janestreet/bin_prot/blob/5915cde59105f398b53f682c5f4dad29e272f696/src/write.ml#L387-L393
+pub fn variant_int(str: &str) -> i32 {
+    let v = hash_variant(str);
+    (v << 1) | 1
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -91,4 +169,32 @@ mod tests {
         let mut encoded = &[CODE_NEG_INT8, 0][..];
         assert!(read_signed(&mut encoded).is_err())
     }
+
+    /// Checks that the computed sizes match the number of bytes written.
+    #[test]
+    fn sizes() {
+        for &v in [0, 1, 127, 128, 255, 256, 32767, 32768, 65535, 65536, 1 << 31, 1 << 32, i64::MAX]
+            .iter()
+        {
+            for &v in [v, -v, -v - 1].iter() {
+                let mut data = vec![];
+                write_i64(&mut data, v).unwrap();
+                assert_eq!(data.len(), i64_size(v), "{}", v);
+            }
+            let mut data = vec![];
+            write_nat0(&mut data, v as u64).unwrap();
+            assert_eq!(data.len(), nat0_size(v as u64), "{}", v);
+        }
+    }
+
+    /// Checks the polymorphic variant hashes against the values from OCaml.
+    #[test]
+    fn variant_hashes() {
+        assert_eq!(hash_variant(""), 0);
+        assert_eq!(hash_variant("foo"), 5097222);
+        assert_eq!(hash_variant("FooBar"), 805748365);
+        assert_eq!(hash_variant("FooBarBazAndEvenMoreAlternatives"), 74946334);
+        assert_eq!(variant_int("foo"), 10194445);
+        assert_eq!(variant_int("FooBar"), 1611496731);
+    }
 }
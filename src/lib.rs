@@ -11,34 +11,110 @@ pub mod macros {
 // Re-export byteorder as it can be used by the macros.
 #[doc(hidden)]
 pub use ::byteorder;
+// Same for the crates used by the async derive macros.
+#[cfg(feature = "async")]
+#[doc(hidden)]
+pub use ::async_trait;
+#[cfg(feature = "async")]
+#[doc(hidden)]
+pub use ::tokio;
 
+mod borrowed;
+mod diff;
 mod error;
 mod int;
+mod sexp;
 mod shape;
+mod size;
 mod traits;
+mod value;
 
+#[cfg(feature = "async")]
+pub use crate::async_traits::{BinProtReadAsync, BinProtReadAsyncTagged, BinProtWriteAsync};
+pub use crate::borrowed::bytes;
+pub use crate::diff::{ShapeChange, ShapeChangeKind, ShapeDiff};
 pub use crate::error::Error;
-pub use crate::shape::{Digestible, Shape};
-pub use crate::traits::{BinProtRead, BinProtShape, BinProtSize, BinProtWrite, ShapeContext};
+pub use crate::int::hash_variant;
+pub use crate::shape::{Canonical, Digestible, Shape, ShapeVar, Uuid};
+pub use crate::traits::{
+    binprot_shape_definition, binprot_shape_inherited, BinProtRead, BinProtReadBorrowed,
+    BinProtReadBorrowedTagged, BinProtReadTagged, BinProtShape, BinProtShapeTagged, BinProtSize,
+    BinProtWrite, ShapeContext, TagHashes,
+};
+pub use crate::value::{from_value, to_value, Value};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::convert::TryFrom;
-use std::hash::Hash;
+use std::hash::{BuildHasher, Hash};
 use std::io::{Read, Write};
 
 /// This uses the "size-prefixed binary protocol".
 /// https://ocaml.janestreet.com/ocaml-core/v0.13/doc/async_unix/Async_unix/Writer/index.html#val-write_bin_prot
-pub fn binprot_write_with_size<W: Write, B: BinProtWrite>(b: &B, w: &mut W) -> std::io::Result<()> {
+pub fn binprot_write_with_size<W: Write, B: BinProtWrite + BinProtSize>(
+    b: &B,
+    w: &mut W,
+) -> std::io::Result<()> {
     let len = b.binprot_size();
     w.write_i64::<byteorder::LittleEndian>(len as i64)?;
     b.binprot_write(w)
 }
 
+/// Computes the size of a value by serializing it and counting the bytes. This can be
+/// used to implement `BinProtSize` for types that only implement `BinProtWrite`,
+/// at the cost of serializing the value.
+pub fn binprot_size_by_writing<B: BinProtWrite + ?Sized>(b: &B) -> usize {
+    struct SizeWriter(usize);
+
+    impl Write for SizeWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut w = SizeWriter(0);
+    // Writing only fails for values that cannot be serialized, the bytes written
+    // until then are counted.
+    let _ = b.binprot_write(&mut w);
+    w.0
+}
+
 /// This also uses the "size-prefixed binary protocol".
+/// The decoder is not allowed to read past the announced size and has to consume
+/// all of it, otherwise an error is returned. In the latter case the remaining bytes
+/// are skipped so that the reader stays at the beginning of the next frame.
 pub fn binprot_read_with_size<R: Read, B: BinProtRead>(r: &mut R) -> Result<B, Error> {
-    // TODO: use the length value to avoid reading more that the specified number of bytes.
-    let _len = r.read_i64::<byteorder::LittleEndian>()?;
-    B::binprot_read(r)
+    let len = r.read_i64::<byteorder::LittleEndian>()?;
+    let len = u64::try_from(len)?;
+    binprot_read_bounded(r, len)
+}
+
+// Reads a value that is expected to use exactly [len] bytes.
+fn binprot_read_bounded<R: Read + ?Sized, B: BinProtRead>(r: &mut R, len: u64) -> Result<B, Error> {
+    let mut r = Read::take(r, len);
+    let v = B::binprot_read(&mut r);
+    // The remaining bytes are skipped, including when decoding fails, so that the
+    // reader stays at the beginning of the next frame.
+    let remaining = r.limit();
+    if remaining != 0 {
+        match skip_payload(r.into_inner(), remaining) {
+            Ok(()) => {}
+            Err(Error::PayloadTooShort { .. }) => return Err(Error::PayloadTooShort { len }),
+            Err(err) => return Err(err),
+        }
+    }
+    match v {
+        Ok(_) if remaining != 0 => Err(Error::PayloadNotFullyConsumed { len, remaining }),
+        Ok(v) => Ok(v),
+        Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Err(Error::PayloadTooShort { len })
+        }
+        Err(err) => Err(err),
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Copy)]
@@ -47,6 +123,73 @@ pub struct Nat0(pub u64);
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Bytes(pub Vec<u8>);
 
+/// Bytes borrowed from the input when decoding with [BinProtReadBorrowed], this
+/// uses the same encoding as [Bytes]. Note that `&[u8]` is encoded as an array
+/// of ints, in the same way as `Vec<u8>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BorrowedBytes<'a>(pub &'a [u8]);
+
+impl<'a> std::convert::From<&'a [u8]> for BorrowedBytes<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        BorrowedBytes(bytes)
+    }
+}
+
+/// An OCaml char, this is serialized as a single byte.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Copy)]
+pub struct Char(pub u8);
+
+impl std::convert::From<u8> for Char {
+    fn from(c: u8) -> Self {
+        Char(c)
+    }
+}
+
+impl std::convert::From<Char> for char {
+    fn from(c: Char) -> Self {
+        char::from(c.0)
+    }
+}
+
+impl std::convert::TryFrom<char> for Char {
+    type Error = std::char::TryFromCharError;
+
+    fn try_from(c: char) -> Result<Self, Self::Error> {
+        Ok(Char(u8::try_from(c)?))
+    }
+}
+
+/// A single precision float serialized as an OCaml float.
+///
+/// OCaml only has double precision floats so the value is serialized as a f64,
+/// this uses 8 bytes. The conversion is lossless when writing, however reading
+/// rounds the f64 value to the nearest f32 so precision is lost for values that
+/// have not been written from a f32. Plain `f32` values have no bin_prot
+/// representation on their own, `Vec<f32>` uses the float32 bigarray encoding.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub struct Float32(pub f32);
+
+impl std::convert::From<f32> for Float32 {
+    fn from(f: f32) -> Self {
+        Float32(f)
+    }
+}
+
+impl std::convert::From<Float32> for f32 {
+    fn from(f: Float32) -> Self {
+        f.0
+    }
+}
+
+/// A vector of single precision floats serialized using the same format as
+/// the OCaml bigarray type:
+/// type vec32 = (float, Bigarray.float32_elt, Bigarray.fortran_layout) Bigarray.Array1.t
+///
+/// This is the encoding used for `Vec<f32>`, the wrapper also implements
+/// `BinProtShape`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vec32(pub Vec<f32>);
+
 impl std::convert::From<String> for Bytes {
     fn from(str: String) -> Self {
         Bytes(str.into_bytes())
@@ -83,6 +226,18 @@ impl BinProtWrite for f64 {
     }
 }
 
+impl BinProtWrite for Float32 {
+    fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        f64::from(self.0).binprot_write(w)
+    }
+}
+
+impl BinProtWrite for Char {
+    fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        w.write_all(&[self.0])
+    }
+}
+
 impl BinProtWrite for () {
     fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
         w.write_all(&[0u8])
@@ -152,6 +307,12 @@ impl BinProtWrite for Vec<f32> {
     }
 }
 
+impl BinProtWrite for Vec32 {
+    fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        self.0.binprot_write(w)
+    }
+}
+
 impl<T: BinProtWrite> BinProtWrite for &[T] {
     fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
         int::write_nat0(w, self.len() as u64)?;
@@ -178,6 +339,12 @@ impl BinProtWrite for &str {
     }
 }
 
+impl BinProtWrite for std::borrow::Cow<'_, str> {
+    fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        self.as_ref().binprot_write(w)
+    }
+}
+
 impl BinProtWrite for Bytes {
     fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
         let bytes = &self.0;
@@ -186,6 +353,13 @@ impl BinProtWrite for Bytes {
     }
 }
 
+impl BinProtWrite for BorrowedBytes<'_> {
+    fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        int::write_nat0(w, self.0.len() as u64)?;
+        w.write_all(self.0)
+    }
+}
+
 impl<K: BinProtWrite, V: BinProtWrite> BinProtWrite for std::collections::BTreeMap<K, V> {
     // The order is unspecified by the protocol
     fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
@@ -198,7 +372,7 @@ impl<K: BinProtWrite, V: BinProtWrite> BinProtWrite for std::collections::BTreeM
     }
 }
 
-impl<K: BinProtWrite, V: BinProtWrite> BinProtWrite for std::collections::HashMap<K, V> {
+impl<K: BinProtWrite, V: BinProtWrite, S> BinProtWrite for std::collections::HashMap<K, V, S> {
     // The order is unspecified by the protocol
     fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
         int::write_nat0(w, self.len() as u64)?;
@@ -210,6 +384,48 @@ impl<K: BinProtWrite, V: BinProtWrite> BinProtWrite for std::collections::HashMa
     }
 }
 
+impl<T: BinProtWrite> BinProtWrite for std::collections::BTreeSet<T> {
+    // The order is unspecified by the protocol
+    fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        int::write_nat0(w, self.len() as u64)?;
+        for v in self.iter() {
+            v.binprot_write(w)?
+        }
+        Ok(())
+    }
+}
+
+impl<T: BinProtWrite, S> BinProtWrite for std::collections::HashSet<T, S> {
+    // The order is unspecified by the protocol
+    fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        int::write_nat0(w, self.len() as u64)?;
+        for v in self.iter() {
+            v.binprot_write(w)?
+        }
+        Ok(())
+    }
+}
+
+impl<T: BinProtWrite> BinProtWrite for std::collections::VecDeque<T> {
+    fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        int::write_nat0(w, self.len() as u64)?;
+        for v in self.iter() {
+            v.binprot_write(w)?
+        }
+        Ok(())
+    }
+}
+
+impl<T: BinProtWrite> BinProtWrite for std::collections::LinkedList<T> {
+    fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        int::write_nat0(w, self.len() as u64)?;
+        for v in self.iter() {
+            v.binprot_write(w)?
+        }
+        Ok(())
+    }
+}
+
 macro_rules! tuple_impls {
     ( $( $name:ident )+ ) => {
         impl<$($name: BinProtWrite),+> BinProtWrite for ($($name,)+)
@@ -242,6 +458,11 @@ macro_rules! tuple_impls {
                 $(let $name = <$name>::binprot_shape_loop(ctxt);)+
                 Shape::Tuple(vec![$($name,)+])
             }
+
+            // Tuples are not type definitions so they are not checked for recursion.
+            fn binprot_shape_loop(ctxt: &mut ShapeContext) -> Shape {
+                Self::binprot_shape_impl(ctxt)
+            }
         }
     };
 }
@@ -256,6 +477,38 @@ tuple_impls! { A B C D E F G }
 tuple_impls! { A B C D E F G H }
 tuple_impls! { A B C D E F G H I }
 
+// Fixed size arrays are serialized as tuples.
+impl<T: BinProtWrite, const N: usize> BinProtWrite for [T; N] {
+    fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        for v in self.iter() {
+            v.binprot_write(w)?
+        }
+        Ok(())
+    }
+}
+
+impl<T: BinProtRead, const N: usize> BinProtRead for [T; N] {
+    fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let mut v: Vec<T> = Vec::with_capacity(N);
+        for _i in 0..N {
+            let item = T::binprot_read(r)?;
+            v.push(item)
+        }
+        Ok(array_of_vec(v))
+    }
+}
+
+// The vector length has to be N.
+pub(crate) fn array_of_vec<T, const N: usize>(v: Vec<T>) -> [T; N] {
+    match <[T; N]>::try_from(v) {
+        Ok(array) => array,
+        Err(_) => unreachable!(),
+    }
+}
+
 impl BinProtRead for Nat0 {
     fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
     where
@@ -286,6 +539,27 @@ impl BinProtRead for f64 {
     }
 }
 
+/// The f64 value is rounded to the nearest f32, see the [Float32] documentation.
+impl BinProtRead for Float32 {
+    fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let f64 = f64::binprot_read(r)?;
+        Ok(Float32(f64 as f32))
+    }
+}
+
+impl BinProtRead for Char {
+    fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let c = r.read_u8()?;
+        Ok(Char(c))
+    }
+}
+
 impl BinProtRead for () {
     fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
     where
@@ -394,6 +668,15 @@ impl BinProtRead for Vec<f32> {
     }
 }
 
+impl BinProtRead for Vec32 {
+    fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        Ok(Vec32(Vec::binprot_read(r)?))
+    }
+}
+
 impl<K: BinProtRead + Ord, V: BinProtRead> BinProtRead for std::collections::BTreeMap<K, V> {
     fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
     where
@@ -412,13 +695,18 @@ impl<K: BinProtRead + Ord, V: BinProtRead> BinProtRead for std::collections::BTr
     }
 }
 
-impl<K: BinProtRead + Hash + Eq, V: BinProtRead> BinProtRead for std::collections::HashMap<K, V> {
+impl<K, V, S> BinProtRead for std::collections::HashMap<K, V, S>
+where
+    K: BinProtRead + Hash + Eq,
+    V: BinProtRead,
+    S: BuildHasher + Default,
+{
     fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
     where
         Self: Sized,
     {
         let len = int::read_nat0(r)?;
-        let mut res = std::collections::HashMap::new();
+        let mut res = std::collections::HashMap::with_hasher(S::default());
         for _i in 0..len {
             let k = K::binprot_read(r)?;
             let v = V::binprot_read(r)?;
@@ -430,33 +718,118 @@ impl<K: BinProtRead + Hash + Eq, V: BinProtRead> BinProtRead for std::collection
     }
 }
 
-impl BinProtRead for String {
+impl<T: BinProtRead + Ord> BinProtRead for std::collections::BTreeSet<T> {
     fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
     where
         Self: Sized,
     {
         let len = int::read_nat0(r)?;
-        let mut buf: Vec<u8> = vec![0u8; len as usize];
-        r.read_exact(&mut buf)?;
-        let str = std::str::from_utf8(&buf)?;
-        Ok(str.to_string())
+        let mut res = std::collections::BTreeSet::new();
+        for _i in 0..len {
+            let v = T::binprot_read(r)?;
+            if !res.insert(v) {
+                return Err(Error::SameValueAppearsTwiceInSet);
+            }
+        }
+        Ok(res)
     }
 }
 
-impl BinProtRead for Bytes {
+impl<T, S> BinProtRead for std::collections::HashSet<T, S>
+where
+    T: BinProtRead + Hash + Eq,
+    S: BuildHasher + Default,
+{
     fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
     where
         Self: Sized,
     {
         let len = int::read_nat0(r)?;
-        let mut buf: Vec<u8> = vec![0u8; len as usize];
-        r.read_exact(&mut buf)?;
-        Ok(Bytes(buf))
+        let mut res = std::collections::HashSet::with_hasher(S::default());
+        for _i in 0..len {
+            let v = T::binprot_read(r)?;
+            if !res.insert(v) {
+                return Err(Error::SameValueAppearsTwiceInSet);
+            }
+        }
+        Ok(res)
+    }
+}
+
+impl<T: BinProtRead> BinProtRead for std::collections::VecDeque<T> {
+    fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let v = Vec::<T>::binprot_read(r)?;
+        Ok(v.into())
+    }
+}
+
+impl<T: BinProtRead> BinProtRead for std::collections::LinkedList<T> {
+    fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let len = int::read_nat0(r)?;
+        let mut res = std::collections::LinkedList::new();
+        for _i in 0..len {
+            let item = T::binprot_read(r)?;
+            res.push_back(item)
+        }
+        Ok(res)
+    }
+}
+
+// Reads a buffer prefixed by its length as a nat0. The length comes from the
+// input so the buffer grows as the bytes arrive rather than being allocated upfront.
+fn read_buffer<R: Read + ?Sized>(r: &mut R) -> Result<Vec<u8>, Error> {
+    let len = int::read_nat0(r)?;
+    let mut buf: Vec<u8> = Vec::new();
+    let read = Read::take(r, len).read_to_end(&mut buf)?;
+    if (read as u64) < len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(buf)
+}
+
+impl BinProtRead for String {
+    fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        let buf = read_buffer(r)?;
+        let str = String::from_utf8(buf).map_err(|err| err.utf8_error())?;
+        Ok(str)
+    }
+}
+
+impl BinProtRead for std::borrow::Cow<'static, str> {
+    fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        Ok(std::borrow::Cow::Owned(String::binprot_read(r)?))
+    }
+}
+
+impl BinProtRead for Bytes {
+    fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
+    where
+        Self: Sized,
+    {
+        Ok(Bytes(read_buffer(r)?))
     }
 }
 
 /// A value serialized by first having its size as a nat0, then the
 /// encoding of the value itself.
+///
+/// The shape of a `WithLen<T>` is the shape of `T` wrapped in a `with_len` base
+/// type so that shape diffs detect a length prefix being added or removed. In
+/// OCaml the length prefix is added by the reader/writer, e.g. for the data field
+/// of async_rpc queries, and is not part of the type, so the digests of types
+/// containing a `WithLen` differ from the OCaml ones.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct WithLen<T>(pub T);
 
@@ -473,13 +846,39 @@ impl<T: BinProtRead> BinProtRead for WithLen<T> {
     where
         Self: Sized,
     {
-        // TODO: stop reading past this length
-        let _len = int::read_nat0(r)?;
-        let t = T::binprot_read(r)?;
+        let len = int::read_nat0(r)?;
+        Self::binprot_read_payload(r, len)
+    }
+}
+
+impl<T: BinProtRead> WithLen<T> {
+    /// Reads the value once the nat0 length prefix has already been consumed, e.g. when
+    /// the type of the payload is only known after having read some header.
+    /// Decoding is not allowed to go past [len] bytes and has to consume all of them.
+    pub fn binprot_read_payload<R: Read + ?Sized>(r: &mut R, len: u64) -> Result<Self, Error> {
+        let t = binprot_read_bounded(r, len)?;
         Ok(WithLen(t))
     }
 }
 
+/// Skips a value serialized as a [WithLen] without decoding it, this can be used when
+/// the type of the payload is not known. Returns the length of the skipped payload.
+pub fn skip_with_len<R: Read + ?Sized>(r: &mut R) -> Result<u64, Error> {
+    let len = int::read_nat0(r)?;
+    skip_payload(r, len)?;
+    Ok(len)
+}
+
+/// Skips the [len] bytes of a payload for which the length prefix has already
+/// been consumed.
+pub fn skip_payload<R: Read + ?Sized>(r: &mut R, len: u64) -> Result<(), Error> {
+    let mut r = Read::take(r, len);
+    if std::io::copy(&mut r, &mut std::io::sink())? < len {
+        return Err(Error::PayloadTooShort { len });
+    }
+    Ok(())
+}
+
 /// A buffer serialized as its size first as a nat0, then the payload itself.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BufferWithLen(pub Vec<u8>);
@@ -489,10 +888,7 @@ impl BinProtRead for BufferWithLen {
     where
         Self: Sized,
     {
-        let len = Nat0::binprot_read(r)?;
-        let mut buf: Vec<u8> = vec![0u8; len.0 as usize];
-        r.read_exact(&mut buf)?;
-        Ok(BufferWithLen(buf))
+        Ok(BufferWithLen(read_buffer(r)?))
     }
 }
 
@@ -534,3 +930,29 @@ int_impls!(i16);
 int_impls!(u16);
 int_impls!(i8);
 int_impls!(u8);
+
+// Types that are not guaranteed to fit in an OCaml int. An error is returned
+// when writing values outside of the 63 bits range rather than wrapping.
+macro_rules! int63_impls {
+    ( $ty: ty) => {
+        impl BinProtWrite for $ty {
+            fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+                int::write_i64(w, int::to_int63(*self)?)
+            }
+        }
+
+        impl BinProtRead for $ty {
+            fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, Error>
+            where
+                Self: Sized,
+            {
+                let i64 = int::read_signed(r)?;
+                Ok(<$ty>::try_from(i64)?)
+            }
+        }
+    };
+}
+
+int63_impls!(u64);
+int63_impls!(usize);
+int63_impls!(isize);
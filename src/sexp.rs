@@ -0,0 +1,493 @@
+// Minimal s-expressions, used to print values in the same way as OCaml's
+// Sexplib. The human readable printer is a port of the parts of the OCaml
+// Format module that Sexp.to_string_hum relies on so that line breaks and
+// indentation match.
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Sexp {
+    Atom(String),
+    List(Vec<Sexp>),
+}
+
+impl Sexp {
+    pub(crate) fn atom<S: ToString>(s: S) -> Sexp {
+        Sexp::Atom(s.to_string())
+    }
+
+    pub(crate) fn to_string_mach(&self) -> String {
+        let mut res = String::new();
+        self.mach_loop(&mut res, false);
+        res
+    }
+
+    // Returns whether the last printed element was an unescaped atom, a space is
+    // only needed between two unescaped atoms as in sexplib's to_buffer_mach.
+    fn mach_loop(&self, res: &mut String, may_need_space: bool) -> bool {
+        match self {
+            Sexp::Atom(s) => {
+                let new_may_need_space = !must_escape(s);
+                if may_need_space && new_may_need_space {
+                    res.push(' ')
+                }
+                res.push_str(&maybe_escape(s));
+                new_may_need_space
+            }
+            Sexp::List(l) => {
+                res.push('(');
+                let mut may_need_space = false;
+                for s in l.iter() {
+                    may_need_space = s.mach_loop(res, may_need_space);
+                }
+                res.push(')');
+                false
+            }
+        }
+    }
+
+    pub(crate) fn to_string_hum(&self) -> String {
+        match self {
+            Sexp::Atom(s) if !s.contains('\n') => maybe_escape(s),
+            sexp => {
+                let mut f = Formatter::new();
+                sexp.hum_loop(&mut f);
+                f.flush()
+            }
+        }
+    }
+
+    fn hum_loop(&self, f: &mut Formatter) {
+        match self {
+            Sexp::Atom(s) => f.print_string(&maybe_escape(s)),
+            Sexp::List(l) if l.is_empty() => f.print_string("()"),
+            Sexp::List(l) => {
+                f.open_box(1);
+                f.print_string("(");
+                for (i, s) in l.iter().enumerate() {
+                    if i > 0 {
+                        f.print_space()
+                    }
+                    s.hum_loop(f)
+                }
+                f.print_string(")");
+                f.close_box()
+            }
+        }
+    }
+    /// Parses a single s-expression, the errors are returned as strings and
+    /// wrapped by the callers.
+    pub(crate) fn parse(s: &str) -> Result<Sexp, String> {
+        let mut parser = Parser { bytes: s.as_bytes(), pos: 0, depth: 0 };
+        let sexp = parser.sexp()?;
+        parser.skip_blanks();
+        if parser.pos < parser.bytes.len() {
+            return Err(format!("unexpected trailing data at position {}", parser.pos));
+        }
+        Ok(sexp)
+    }
+}
+
+// Lists nested deeper than this are rejected rather than overflowing the stack,
+// this is far above the depth of the shapes of actual types.
+const MAX_DEPTH: usize = 512;
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // The number of lists being parsed.
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn skip_blanks(&mut self) {
+        while let Some(&c) = self.bytes.get(self.pos) {
+            if c == b';' {
+                while self.pos < self.bytes.len() && self.bytes[self.pos] != b'\n' {
+                    self.pos += 1
+                }
+            } else if c.is_ascii_whitespace() {
+                self.pos += 1
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn sexp(&mut self) -> Result<Sexp, String> {
+        self.skip_blanks();
+        match self.bytes.get(self.pos) {
+            None => Err("unexpected end of input".to_string()),
+            Some(b'(') => {
+                if self.depth >= MAX_DEPTH {
+                    return Err(format!("lists nested too deeply at position {}", self.pos));
+                }
+                self.pos += 1;
+                self.depth += 1;
+                let mut list = vec![];
+                loop {
+                    self.skip_blanks();
+                    match self.bytes.get(self.pos) {
+                        None => return Err("unterminated list".to_string()),
+                        Some(b')') => {
+                            self.pos += 1;
+                            self.depth -= 1;
+                            return Ok(Sexp::List(list));
+                        }
+                        Some(_) => list.push(self.sexp()?),
+                    }
+                }
+            }
+            Some(b')') => Err(format!("unexpected closing parenthesis at position {}", self.pos)),
+            Some(b'"') => self.quoted_atom(),
+            Some(_) => {
+                let start = self.pos;
+                while let Some(&c) = self.bytes.get(self.pos) {
+                    if c.is_ascii_whitespace() || matches!(c, b'(' | b')' | b'"' | b';') {
+                        break;
+                    }
+                    self.pos += 1
+                }
+                let atom = std::str::from_utf8(&self.bytes[start..self.pos])
+                    .map_err(|err| err.to_string())?;
+                Ok(Sexp::atom(atom))
+            }
+        }
+    }
+
+    fn quoted_atom(&mut self) -> Result<Sexp, String> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut res = vec![];
+        loop {
+            let c = match self.bytes.get(self.pos) {
+                None => return Err(format!("unterminated string at position {start}")),
+                Some(&c) => c,
+            };
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let c = self.bytes.get(self.pos).copied();
+                    self.pos += 1;
+                    match c {
+                        Some(b'n') => res.push(b'\n'),
+                        Some(b't') => res.push(b'\t'),
+                        Some(b'r') => res.push(b'\r'),
+                        Some(b'b') => res.push(8),
+                        Some(c @ (b'"' | b'\\' | b' ' | b'\'')) => res.push(c),
+                        Some(b'\n') => self.skip_blanks(),
+                        Some(c) if c.is_ascii_digit() => {
+                            let digits = self.bytes.get(self.pos - 1..self.pos + 2);
+                            let code = digits
+                                .and_then(|d| std::str::from_utf8(d).ok())
+                                .and_then(|d| d.parse::<u8>().ok())
+                                .ok_or_else(|| {
+                                    format!("invalid escape at position {}", self.pos)
+                                })?;
+                            res.push(code);
+                            self.pos += 2
+                        }
+                        _ => return Err(format!("invalid escape at position {}", self.pos - 1)),
+                    }
+                }
+                c => res.push(c),
+            }
+        }
+        let atom = String::from_utf8(res).map_err(|err| err.to_string())?;
+        Ok(Sexp::Atom(atom))
+    }
+}
+
+fn must_escape(s: &str) -> bool {
+    s.is_empty()
+        || s.contains("#|")
+        || s.contains("|#")
+        || s.chars().any(|c| {
+            matches!(c, ' ' | '"' | '(' | ')' | ';' | '\\') || c.is_control() || !c.is_ascii()
+        })
+}
+
+fn maybe_escape(s: &str) -> String {
+    if !must_escape(s) {
+        return s.to_string();
+    }
+    let mut res = String::from("\"");
+    for b in s.bytes() {
+        match b {
+            b'"' => res.push_str("\\\""),
+            b'\\' => res.push_str("\\\\"),
+            b'\n' => res.push_str("\\n"),
+            b'\t' => res.push_str("\\t"),
+            b'\r' => res.push_str("\\r"),
+            b' '..=b'~' => res.push(b as char),
+            b => res.push_str(&format!("\\{:03}", b)),
+        }
+    }
+    res.push('"');
+    res
+}
+
+const INFINITY: i64 = 1_000_000_010;
+
+#[derive(Debug, Clone, Copy)]
+enum BoxType {
+    HovBox,
+    Box,
+    Fits,
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Text(String),
+    Break { width: i64, offset: i64 },
+    Begin { indent: i64, box_type: BoxType },
+    End,
+}
+
+#[derive(Debug)]
+struct Elem {
+    // Negative while the size is not known yet.
+    size: i64,
+    token: Token,
+    length: i64,
+}
+
+// Same state as OCaml's formatters, using the default margin of 78.
+struct Formatter {
+    margin: i64,
+    max_indent: i64,
+    space_left: i64,
+    current_indent: i64,
+    is_new_line: bool,
+    left_total: i64,
+    right_total: i64,
+    curr_depth: usize,
+    queue: VecDeque<Elem>,
+    // Number of elements popped from the queue, used to index the queue from
+    // the scan stack.
+    queue_offset: usize,
+    // The breaks and boxes for which the size is not known yet, together with
+    // the value of right_total when they were pushed.
+    scan_stack: Vec<(i64, Option<(usize, bool)>)>,
+    format_stack: Vec<(BoxType, i64)>,
+    out: String,
+}
+
+impl Formatter {
+    fn new() -> Self {
+        let mut f = Formatter {
+            margin: 78,
+            max_indent: 68,
+            space_left: 78,
+            current_indent: 0,
+            is_new_line: true,
+            left_total: 1,
+            right_total: 1,
+            curr_depth: 0,
+            queue: VecDeque::new(),
+            queue_offset: 0,
+            scan_stack: vec![],
+            format_stack: vec![],
+            out: String::new(),
+        };
+        f.rinit();
+        f
+    }
+
+    fn rinit(&mut self) {
+        self.queue_offset += self.queue.len();
+        self.queue.clear();
+        self.left_total = 1;
+        self.right_total = 1;
+        self.scan_stack = vec![(-1, None)];
+        self.format_stack.clear();
+        self.current_indent = 0;
+        self.curr_depth = 0;
+        self.space_left = self.margin;
+        self.open_box_gen(0, BoxType::HovBox)
+    }
+
+    fn enqueue(&mut self, elem: Elem) -> usize {
+        self.right_total += elem.length;
+        self.queue.push_back(elem);
+        self.queue_offset + self.queue.len() - 1
+    }
+
+    fn advance_left(&mut self) {
+        while let Some(elem) = self.queue.front() {
+            let pending_count = self.right_total - self.left_total;
+            if elem.size < 0 && pending_count < self.space_left {
+                break;
+            }
+            let elem = self.queue.pop_front().unwrap();
+            self.queue_offset += 1;
+            let size = if elem.size < 0 { INFINITY } else { elem.size };
+            self.format_token(size, elem.token);
+            self.left_total += elem.length;
+        }
+    }
+
+    fn format_token(&mut self, size: i64, token: Token) {
+        match token {
+            Token::Text(s) => {
+                self.space_left -= size;
+                self.out.push_str(&s);
+                self.is_new_line = false;
+            }
+            Token::Begin { indent, box_type } => {
+                let insertion_point = self.margin - self.space_left;
+                if insertion_point > self.max_indent {
+                    self.force_break_line()
+                }
+                let width = self.space_left - indent;
+                let box_type = if size > self.space_left { box_type } else { BoxType::Fits };
+                self.format_stack.push((box_type, width))
+            }
+            Token::End => {
+                self.format_stack.pop();
+            }
+            Token::Break { width: fits_width, offset } => {
+                if let Some(&(box_type, width)) = self.format_stack.last() {
+                    let new_line = match box_type {
+                        BoxType::HovBox => size > self.space_left,
+                        BoxType::Box => {
+                            !self.is_new_line
+                                && (size > self.space_left
+                                    || self.current_indent > self.margin - width + offset)
+                        }
+                        BoxType::Fits => false,
+                    };
+                    if new_line {
+                        self.break_new_line(offset, width)
+                    } else {
+                        self.space_left -= fits_width;
+                        self.out.push_str(&" ".repeat(fits_width as usize))
+                    }
+                }
+            }
+        }
+    }
+
+    fn break_new_line(&mut self, offset: i64, width: i64) {
+        self.out.push('\n');
+        self.is_new_line = true;
+        let indent = self.margin - width + offset;
+        self.current_indent = std::cmp::min(self.max_indent, indent);
+        self.space_left = self.margin - self.current_indent;
+        self.out.push_str(&" ".repeat(self.current_indent as usize))
+    }
+
+    fn force_break_line(&mut self) {
+        match self.format_stack.last() {
+            None => self.out.push('\n'),
+            Some(&(box_type, width)) => {
+                if width > self.space_left {
+                    match box_type {
+                        BoxType::Fits => (),
+                        BoxType::HovBox | BoxType::Box => self.break_new_line(0, width),
+                    }
+                }
+            }
+        }
+    }
+
+    fn set_size(&mut self, is_break: bool) {
+        let (left_total, elem) = *self.scan_stack.last().unwrap();
+        if left_total < self.left_total {
+            self.scan_stack = vec![(-1, None)];
+            return;
+        }
+        if let Some((index, elem_is_break)) = elem {
+            if elem_is_break == is_break {
+                // The element may have been printed already, in which case its
+                // size is not used anymore.
+                if let Some(index) = index.checked_sub(self.queue_offset) {
+                    self.queue[index].size += self.right_total;
+                }
+                self.scan_stack.pop();
+            }
+        }
+    }
+
+    fn scan_push(&mut self, is_break: bool, elem: Elem) {
+        let index = self.enqueue(elem);
+        if is_break {
+            self.set_size(true)
+        }
+        self.scan_stack.push((self.right_total, Some((index, is_break))))
+    }
+
+    fn open_box_gen(&mut self, indent: i64, box_type: BoxType) {
+        self.curr_depth += 1;
+        let elem =
+            Elem { size: -self.right_total, token: Token::Begin { indent, box_type }, length: 0 };
+        self.scan_push(false, elem)
+    }
+
+    fn open_box(&mut self, indent: i64) {
+        self.open_box_gen(indent, BoxType::Box)
+    }
+
+    fn close_box(&mut self) {
+        if self.curr_depth > 1 {
+            self.enqueue(Elem { size: 0, token: Token::End, length: 0 });
+            self.set_size(true);
+            self.set_size(false);
+            self.curr_depth -= 1;
+        }
+    }
+
+    fn print_string(&mut self, s: &str) {
+        let len = s.len() as i64;
+        self.enqueue(Elem { size: len, token: Token::Text(s.to_string()), length: len });
+        self.advance_left()
+    }
+
+    fn print_space(&mut self) {
+        let elem = Elem {
+            size: -self.right_total,
+            token: Token::Break { width: 1, offset: 0 },
+            length: 1,
+        };
+        self.scan_push(true, elem)
+    }
+
+    fn flush(mut self) -> String {
+        while self.curr_depth > 1 {
+            self.close_box()
+        }
+        self.right_total = INFINITY;
+        self.advance_left();
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(l: Vec<Sexp>) -> Sexp {
+        Sexp::List(l)
+    }
+
+    #[test]
+    fn sexp_printing() {
+        let sexp = list(vec![Sexp::atom("foo"), list(vec![]), Sexp::atom("a b"), Sexp::atom(42)]);
+        assert_eq!(sexp.to_string_mach(), "(foo()\"a b\"42)");
+        assert_eq!(sexp.to_string_hum(), "(foo () \"a b\" 42)");
+        let sexp =
+            list(vec![Sexp::atom("foo"), Sexp::atom("a b"), Sexp::atom("bar"), Sexp::atom(1)]);
+        assert_eq!(sexp.to_string_mach(), "(foo\"a b\"bar 1)");
+        let field = |i: usize| list(vec![Sexp::atom(format!("field_{}", i)), Sexp::atom(i)]);
+        let sexp = list((0..8).map(field).collect());
+        assert_eq!(
+            sexp.to_string_hum(),
+            "((field_0 0) (field_1 1) (field_2 2) (field_3 3) (field_4 4) (field_5 5)\n (field_6 6) (field_7 7))"
+        );
+        let sexp = list(vec![Sexp::atom("record"), sexp]);
+        assert_eq!(
+            sexp.to_string_hum(),
+            "(record\n ((field_0 0) (field_1 1) (field_2 2) (field_3 3) (field_4 4) (field_5 5)\n  (field_6 6) (field_7 7)))"
+        );
+    }
+}
@@ -1,23 +1,28 @@
 // Support for bin_prot_shape like digest computation.
 // https://github.com/janestreet/bin_prot/tree/master/shape
-// TODO: handle recursive types!
+use crate::sexp::Sexp;
 use crate::traits::ShapeContext;
-use crate::BinProtShape;
-use std::collections::{BTreeMap, HashMap};
+use crate::{BinProtRead, BinProtShape, BinProtSize, BinProtWrite};
+use byteorder::ReadBytesExt;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, LinkedList, VecDeque};
+use std::io::{Read, Write};
 
 // In the OCaml version, uuids are used as strings.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Uuid(&'static str);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Uuid(Cow<'static, str>);
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Shape {
     Annotate(Uuid, Box<Shape>),
     Base(Uuid, Vec<Shape>),
     Tuple(Vec<Shape>),
-    Record(Vec<(&'static str, Shape)>),
-    Variant(Vec<(&'static str, Vec<Shape>)>),
+    Record(Vec<(Cow<'static, str>, Shape)>),
+    Variant(Vec<(Cow<'static, str>, Vec<Shape>)>),
     // Polymorphic variants are insensitive to the order the constructors are listed
-    PolyVariant(BTreeMap<&'static str, Option<Shape>>),
+    PolyVariant(BTreeMap<Cow<'static, str>, Option<Shape>>),
     Application(Box<Shape>, Vec<Shape>),
     RecApp(i64, Vec<Shape>),
     Var(i64),
@@ -29,11 +34,11 @@ pub trait Digestible {
 
 impl Digestible for Uuid {
     fn digest(&self) -> md5::Digest {
-        md5::compute(self.0)
+        md5::compute(self.0.as_bytes())
     }
 }
 
-impl<T: Digestible> Digestible for (&'static str, T) {
+impl<T: Digestible> Digestible for (&str, T) {
     fn digest(&self) -> md5::Digest {
         let mut context = md5::Context::new();
         context.consume(<[u8; 16]>::from(self.0.digest()));
@@ -153,33 +158,464 @@ impl Constructor {
     }
 }
 
-impl Digestible for Shape {
+/// The evaluated form of a shape as returned by `Shape::eval`, similar to
+/// `Bin_shape.Canonical.t` in OCaml. Applications of non-recursive definitions
+/// are expanded so shapes that only differ in the way they are written have the
+/// same canonical form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Canonical(Shape);
+
+impl Canonical {
+    pub fn shape(&self) -> &Shape {
+        &self.0
+    }
+
+    pub fn into_shape(self) -> Shape {
+        self.0
+    }
+
+    /// Prints the canonical form in the same way as
+    /// `Bin_shape.Canonical.to_string_hum` in OCaml.
+    pub fn to_string_hum(&self) -> String {
+        sexp_of_shape(&self.0).to_string_hum()
+    }
+
+    /// Prints the canonical form on a single line, matching
+    /// `Sexp.to_string (Bin_shape.Canonical.sexp_of_t t)` in OCaml.
+    pub fn to_string_mach(&self) -> String {
+        sexp_of_shape(&self.0).to_string_mach()
+    }
+}
+
+// Same representation as the sexp converter derived for Bin_shape.Canonical.t
+fn sexp_of_shape(shape: &Shape) -> Sexp {
+    let list = |shapes: &[Shape]| Sexp::List(shapes.iter().map(sexp_of_shape).collect());
+    let exp = match shape {
+        Shape::Annotate(uuid, t) => {
+            vec![Sexp::atom("Annotate"), Sexp::atom(uuid.as_str()), sexp_of_shape(t)]
+        }
+        Shape::Base(uuid, vec) => vec![Sexp::atom("Base"), Sexp::atom(uuid.as_str()), list(vec)],
+        Shape::Tuple(vec) => vec![Sexp::atom("Tuple"), list(vec)],
+        Shape::Record(vec) => {
+            let fields = vec
+                .iter()
+                .map(|(name, s)| Sexp::List(vec![Sexp::atom(name), sexp_of_shape(s)]))
+                .collect();
+            vec![Sexp::atom("Record"), Sexp::List(fields)]
+        }
+        Shape::Variant(vec) => {
+            let cases =
+                vec.iter().map(|(name, v)| Sexp::List(vec![Sexp::atom(name), list(v)])).collect();
+            vec![Sexp::atom("Variant"), Sexp::List(cases)]
+        }
+        Shape::PolyVariant(map) => {
+            let cases = map
+                .iter()
+                .map(|(name, s)| {
+                    let s = Sexp::List(s.iter().map(sexp_of_shape).collect());
+                    Sexp::List(vec![Sexp::atom(name), s])
+                })
+                .collect();
+            let sorted = Sexp::List(vec![Sexp::atom("sorted"), Sexp::List(cases)]);
+            vec![Sexp::atom("Poly_variant"), Sexp::List(vec![sorted])]
+        }
+        Shape::Application(t, vec) => vec![Sexp::atom("Application"), sexp_of_shape(t), list(vec)],
+        Shape::RecApp(n, vec) => vec![Sexp::atom("Rec_app"), Sexp::atom(n), list(vec)],
+        Shape::Var(n) => vec![Sexp::atom("Var"), Sexp::atom(n)],
+    };
+    Sexp::List(vec![Sexp::atom("Exp"), Sexp::List(exp)])
+}
+
+// Inverse of sexp_of_shape.
+fn shape_of_sexp(sexp: &Sexp) -> Result<Shape, String> {
+    fn atom(sexp: &Sexp) -> Result<&str, String> {
+        match sexp {
+            Sexp::Atom(s) => Ok(s),
+            Sexp::List(_) => Err(format!("expected an atom, got {}", sexp.to_string_mach())),
+        }
+    }
+    fn list(sexp: &Sexp) -> Result<&[Sexp], String> {
+        match sexp {
+            Sexp::List(l) => Ok(l),
+            Sexp::Atom(_) => Err(format!("expected a list, got {}", sexp.to_string_mach())),
+        }
+    }
+    fn int(sexp: &Sexp) -> Result<i64, String> {
+        atom(sexp)?.parse().map_err(|_| format!("expected an int, got {}", sexp.to_string_mach()))
+    }
+    fn name(sexp: &Sexp) -> Result<Cow<'static, str>, String> {
+        Ok(Cow::Owned(atom(sexp)?.to_string()))
+    }
+    fn shapes(sexp: &Sexp) -> Result<Vec<Shape>, String> {
+        list(sexp)?.iter().map(shape_of_sexp).collect()
+    }
+    fn pair(sexp: &Sexp) -> Result<(&Sexp, &Sexp), String> {
+        match list(sexp)? {
+            [fst, snd] => Ok((fst, snd)),
+            _ => Err(format!("expected a pair, got {}", sexp.to_string_mach())),
+        }
+    }
+    let err = || format!("unexpected shape {}", sexp.to_string_mach());
+    let exp = match list(sexp)? {
+        [Sexp::Atom(exp), exp_sexp] if exp == "Exp" => list(exp_sexp)?,
+        _ => return Err(err()),
+    };
+    let shape = match exp {
+        [Sexp::Atom(c), uuid, t] if c == "Annotate" => {
+            Shape::Annotate(Uuid::from(atom(uuid)?.to_string()), Box::new(shape_of_sexp(t)?))
+        }
+        [Sexp::Atom(c), uuid, args] if c == "Base" => {
+            Shape::Base(Uuid::from(atom(uuid)?.to_string()), shapes(args)?)
+        }
+        [Sexp::Atom(c), args] if c == "Tuple" => Shape::Tuple(shapes(args)?),
+        [Sexp::Atom(c), fields] if c == "Record" => {
+            let fields = list(fields)?.iter().map(|field| {
+                let (n, s) = pair(field)?;
+                Ok((name(n)?, shape_of_sexp(s)?))
+            });
+            Shape::Record(fields.collect::<Result<_, String>>()?)
+        }
+        [Sexp::Atom(c), cases] if c == "Variant" => {
+            let cases = list(cases)?.iter().map(|case| {
+                let (n, args) = pair(case)?;
+                Ok((name(n)?, shapes(args)?))
+            });
+            Shape::Variant(cases.collect::<Result<_, String>>()?)
+        }
+        [Sexp::Atom(c), cases] if c == "Poly_variant" => {
+            let cases = match list(cases)? {
+                [sorted] => match pair(sorted)? {
+                    (Sexp::Atom(s), cases) if s == "sorted" => list(cases)?,
+                    _ => return Err(err()),
+                },
+                _ => return Err(err()),
+            };
+            let cases = cases.iter().map(|case| {
+                let (n, arg) = pair(case)?;
+                let arg = match list(arg)? {
+                    [] => None,
+                    [arg] => Some(shape_of_sexp(arg)?),
+                    _ => return Err(err()),
+                };
+                Ok((name(n)?, arg))
+            });
+            Shape::PolyVariant(cases.collect::<Result<_, String>>()?)
+        }
+        [Sexp::Atom(c), t, args] if c == "Application" => {
+            Shape::Application(Box::new(shape_of_sexp(t)?), shapes(args)?)
+        }
+        [Sexp::Atom(c), n, args] if c == "Rec_app" => Shape::RecApp(int(n)?, shapes(args)?),
+        [Sexp::Atom(c), n] if c == "Var" => Shape::Var(int(n)?),
+        _ => return Err(err()),
+    };
+    Ok(shape)
+}
+
+/// Parses shapes printed with `Shape::to_string_mach` or `to_string_hum`, or by
+/// `Bin_shape.Canonical.to_string_hum` in OCaml.
+impl std::str::FromStr for Shape {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let sexp = Sexp::parse(s).map_err(crate::Error::InvalidShapeSexp)?;
+        shape_of_sexp(&sexp).map_err(crate::Error::InvalidShapeSexp)
+    }
+}
+
+// The sub-shapes of a canonical shape are canonical too so the digest can be
+// computed on them directly.
+struct CanonicalRef<'a>(&'a Shape);
+
+impl<'a> CanonicalRef<'a> {
+    fn vec(shapes: &'a [Shape]) -> Vec<Self> {
+        shapes.iter().map(CanonicalRef).collect()
+    }
+}
+
+impl Digestible for CanonicalRef<'_> {
     fn digest(&self) -> md5::Digest {
-        match self {
+        match self.0 {
             Shape::Annotate(uuid, t) => {
-                Constructor::new("annotate").add_digest(uuid).add_digest(&**t).finish()
+                Constructor::new("annotate").add_digest(uuid).add_digest(&CanonicalRef(t)).finish()
             }
             Shape::Base(uuid, vec) => {
-                Constructor::new("base").add_digest(uuid).add_digest(vec).finish()
+                Constructor::new("base").add_digest(uuid).add_digest(&Self::vec(vec)).finish()
             }
-            Shape::Tuple(vec) => Constructor::new("tuple").add_digest(vec).finish(),
-            Shape::Record(vec) => Constructor::new("record").add_digest(vec).finish(),
-            Shape::Variant(vec) => Constructor::new("variant").add_digest(vec).finish(),
-            Shape::PolyVariant(map) => Constructor::new("poly_variant").add_digest(map).finish(),
-            Shape::RecApp(n, vec) => {
-                Constructor::new("rec_app").add_digest(&n.to_string()).add_digest(vec).finish()
+            Shape::Tuple(vec) => Constructor::new("tuple").add_digest(&Self::vec(vec)).finish(),
+            Shape::Record(vec) => {
+                let vec: Vec<_> = vec.iter().map(|(n, s)| (n.as_ref(), CanonicalRef(s))).collect();
+                Constructor::new("record").add_digest(&vec).finish()
             }
-            Shape::Application(t, vec) => {
-                Constructor::new("application").add_digest(&**t).add_digest(vec).finish()
+            Shape::Variant(vec) => {
+                let vec: Vec<_> = vec.iter().map(|(n, v)| (n.as_ref(), Self::vec(v))).collect();
+                Constructor::new("variant").add_digest(&vec).finish()
             }
+            Shape::PolyVariant(map) => {
+                let map: BTreeMap<_, _> =
+                    map.iter().map(|(n, s)| (n.as_ref(), s.as_ref().map(CanonicalRef))).collect();
+                Constructor::new("poly_variant").add_digest(&map).finish()
+            }
+            Shape::RecApp(n, vec) => Constructor::new("rec_app")
+                .add_digest(&n.to_string())
+                .add_digest(&Self::vec(vec))
+                .finish(),
+            Shape::Application(t, vec) => Constructor::new("application")
+                .add_digest(&CanonicalRef(t))
+                .add_digest(&Self::vec(vec))
+                .finish(),
             Shape::Var(v) => Constructor::new("var").add_digest(&v.to_string()).finish(),
         }
     }
 }
 
+impl Digestible for Canonical {
+    fn digest(&self) -> md5::Digest {
+        CanonicalRef(&self.0).digest()
+    }
+}
+
+// As in OCaml, the digest of a shape is the digest of its canonical form.
+impl Digestible for Shape {
+    fn digest(&self) -> md5::Digest {
+        self.eval().digest()
+    }
+}
+
+impl Shape {
+    /// Annotates a shape with a uuid, similar to `Bin_shape.annotate`.
+    pub fn annotate(uuid: impl Into<Uuid>, t: Shape) -> Shape {
+        Shape::Annotate(uuid.into(), Box::new(t))
+    }
+
+    /// The shape of an abstract type identified by a uuid, `args` are the shapes of
+    /// the type parameters. This is similar to `Bin_shape.basetype`.
+    pub fn basetype(uuid: impl Into<Uuid>, args: Vec<Shape>) -> Shape {
+        Shape::Base(uuid.into(), args)
+    }
+
+    /// The shape of a polymorphic variant that includes the tags of the `inherited`
+    /// polymorphic variants, similar to `Inherit` rows in `Bin_shape.poly_variant`.
+    /// Annotations and applications of the inherited shapes are expanded, an error
+    /// is returned if one of them is not a polymorphic variant or if a tag appears
+    /// more than once.
+    pub fn poly_variant(
+        tags: Vec<(Cow<'static, str>, Option<Shape>)>,
+        inherited: Vec<Shape>,
+    ) -> Result<Shape, crate::Error> {
+        let mut res = BTreeMap::new();
+        let mut add_tags = |tags: Vec<(Cow<'static, str>, Option<Shape>)>| {
+            for (name, shape) in tags {
+                if res.contains_key(&name) {
+                    return Err(crate::Error::DuplicatePolyVariantTag(name.into_owned()));
+                }
+                res.insert(name, shape);
+            }
+            Ok(())
+        };
+        add_tags(tags)?;
+        for mut shape in inherited {
+            loop {
+                match shape {
+                    Shape::Annotate(_, t) => shape = *t,
+                    Shape::Application(t, args) => shape = t.unfold(&args),
+                    Shape::PolyVariant(inherited) => {
+                        add_tags(inherited.into_iter().collect())?;
+                        break;
+                    }
+                    shape => {
+                        return Err(crate::Error::InheritedNonPolymorphicVariant(Box::new(shape)))
+                    }
+                }
+            }
+        }
+        Ok(Shape::PolyVariant(res))
+    }
+
+    /// Evaluates the shape to its canonical form, mirroring `Bin_shape.eval`.
+    /// Applications of definitions that are not recursive are expanded by
+    /// substituting their arguments, recursive definitions are left applied.
+    pub fn eval(&self) -> Canonical {
+        Canonical(self.clone().eval_loop())
+    }
+
+    /// Prints the shape as a sexp using the same representation as
+    /// `Bin_shape.Canonical.sexp_of_t`, the shape is not evaluated.
+    pub fn to_string_mach(&self) -> String {
+        sexp_of_shape(self).to_string_mach()
+    }
+
+    /// Prints the shape as an indented sexp, in the same way as
+    /// `Bin_shape.Canonical.to_string_hum`, the shape is not evaluated.
+    pub fn to_string_hum(&self) -> String {
+        sexp_of_shape(self).to_string_hum()
+    }
+
+    fn eval_loop(self) -> Shape {
+        match self {
+            Shape::Application(t, args) => {
+                let args: Vec<_> = args.into_iter().map(Shape::eval_loop).collect();
+                let t = t.eval_loop();
+                if t.has_rec_app(0) {
+                    Shape::Application(Box::new(t), args)
+                } else {
+                    t.unshift_rec_apps(0).subst_vars(&args)
+                }
+            }
+            shape => shape.map_children(Shape::eval_loop),
+        }
+    }
+
+    // Whether the shape refers to the definition [depth] levels above it.
+    fn has_rec_app(&self, depth: i64) -> bool {
+        let any = |v: &[Shape]| v.iter().any(|s| s.has_rec_app(depth));
+        match self {
+            Shape::Annotate(_, t) => t.has_rec_app(depth),
+            Shape::Base(_, vec) | Shape::Tuple(vec) => any(vec),
+            Shape::Record(vec) => vec.iter().any(|(_, s)| s.has_rec_app(depth)),
+            Shape::Variant(vec) => vec.iter().any(|(_, v)| any(v)),
+            Shape::PolyVariant(map) => map.values().flatten().any(|s| s.has_rec_app(depth)),
+            Shape::Application(t, vec) => t.has_rec_app(depth + 1) || any(vec),
+            Shape::RecApp(n, vec) => *n == depth || any(vec),
+            Shape::Var(_) => false,
+        }
+    }
+
+    fn map_children(self, f: impl Fn(Shape) -> Shape) -> Shape {
+        let map = |v: Vec<Shape>| v.into_iter().map(&f).collect::<Vec<_>>();
+        match self {
+            Shape::Annotate(uuid, t) => Shape::Annotate(uuid, Box::new(f(*t))),
+            Shape::Base(uuid, vec) => Shape::Base(uuid, map(vec)),
+            Shape::Tuple(vec) => Shape::Tuple(map(vec)),
+            Shape::Record(vec) => Shape::Record(vec.into_iter().map(|(n, s)| (n, f(s))).collect()),
+            Shape::Variant(vec) => {
+                Shape::Variant(vec.into_iter().map(|(n, v)| (n, map(v))).collect())
+            }
+            Shape::PolyVariant(map) => {
+                Shape::PolyVariant(map.into_iter().map(|(n, s)| (n, s.map(&f))).collect())
+            }
+            Shape::Application(t, vec) => Shape::Application(Box::new(f(*t)), map(vec)),
+            Shape::RecApp(n, vec) => Shape::RecApp(n, map(vec)),
+            Shape::Var(v) => Shape::Var(v),
+        }
+    }
+
+    // Replaces the variables bound by the current definition with the given
+    // arguments, the body of nested definitions is left untouched.
+    pub(crate) fn subst_vars(self, args: &[Shape]) -> Shape {
+        match self {
+            Shape::Application(t, vec) => {
+                Shape::Application(t, vec.into_iter().map(|s| s.subst_vars(args)).collect())
+            }
+            Shape::Var(v) => match args.get(v as usize) {
+                Some(arg) => arg.clone(),
+                None => Shape::Var(v),
+            },
+            shape => shape.map_children(|s| s.subst_vars(args)),
+        }
+    }
+
+    // Expands the body of a definition applied to [args], the references to the
+    // definition itself are replaced by applications of its body.
+    fn unfold(self, args: &[Shape]) -> Shape {
+        let body = self.clone();
+        self.unfold_loop(0, &body).subst_vars(args)
+    }
+
+    fn unfold_loop(self, depth: i64, body: &Shape) -> Shape {
+        match self {
+            Shape::Application(t, vec) => Shape::Application(
+                Box::new(t.unfold_loop(depth + 1, body)),
+                vec.into_iter().map(|s| s.unfold_loop(depth, body)).collect(),
+            ),
+            Shape::RecApp(n, vec) => {
+                let vec = vec.into_iter().map(|s| s.unfold_loop(depth, body)).collect();
+                if n == depth {
+                    // The body now appears [depth] definitions deeper.
+                    Shape::Application(Box::new(body.clone().shift_rec_apps(1, depth)), vec)
+                } else {
+                    Shape::RecApp(if n > depth { n - 1 } else { n }, vec)
+                }
+            }
+            shape => shape.map_children(|s| s.unfold_loop(depth, body)),
+        }
+    }
+
+    // Same as unfold for a polymorphic variant body, returning its tags.
+    pub(crate) fn unfold_poly_variant(
+        tags: BTreeMap<Cow<'static, str>, Option<Shape>>,
+        args: &[Shape],
+    ) -> BTreeMap<Cow<'static, str>, Option<Shape>> {
+        let body = Shape::PolyVariant(tags.clone());
+        let unfold = |shape: Shape| shape.unfold_loop(0, &body).subst_vars(args);
+        tags.into_iter().map(|(name, shape)| (name, shape.map(unfold))).collect()
+    }
+
+    // Adds [by] to the RecApp indexes pointing outside of the [depth] innermost
+    // definitions.
+    fn shift_rec_apps(self, depth: i64, by: i64) -> Shape {
+        match self {
+            Shape::Application(t, vec) => Shape::Application(
+                Box::new(t.shift_rec_apps(depth + 1, by)),
+                vec.into_iter().map(|s| s.shift_rec_apps(depth, by)).collect(),
+            ),
+            Shape::RecApp(n, vec) => {
+                let n = if n >= depth { n + by } else { n };
+                let vec = vec.into_iter().map(|s| s.shift_rec_apps(depth, by)).collect();
+                Shape::RecApp(n, vec)
+            }
+            shape => shape.map_children(|s| s.shift_rec_apps(depth, by)),
+        }
+    }
+
+    // Adjusts the RecApp indexes pointing outside of a definition body that is
+    // being inlined, [depth] is the number of definitions entered in this body.
+    pub(crate) fn unshift_rec_apps(self, depth: i64) -> Shape {
+        match self {
+            Shape::Application(t, vec) => Shape::Application(
+                Box::new(t.unshift_rec_apps(depth + 1)),
+                vec.into_iter().map(|s| s.unshift_rec_apps(depth)).collect(),
+            ),
+            Shape::RecApp(n, vec) => {
+                let n = if n > depth { n - 1 } else { n };
+                let vec = vec.into_iter().map(|s| s.unshift_rec_apps(depth)).collect();
+                Shape::RecApp(n, vec)
+            }
+            shape => shape.map_children(|s| s.unshift_rec_apps(depth)),
+        }
+    }
+}
+
+/// A placeholder for the `N`-th type parameter of a generic type, its shape is
+/// `Shape::Var(N)`. This is used when deriving `BinProtShape` for generic types.
+pub struct ShapeVar<const N: usize>;
+
+impl<const N: usize> BinProtShape for ShapeVar<N> {
+    fn binprot_shape_impl(_: &mut ShapeContext) -> Shape {
+        Shape::Var(N as i64)
+    }
+}
+
+impl Uuid {
+    /// Creates a uuid, names of base types such as `int` or `option` are used
+    /// as uuids too.
+    pub fn new(s: impl Into<Cow<'static, str>>) -> Self {
+        Uuid(s.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
 impl From<&'static str> for Uuid {
     fn from(s: &'static str) -> Self {
-        Uuid(s)
+        Uuid(Cow::Borrowed(s))
+    }
+}
+
+impl From<String> for Uuid {
+    fn from(s: String) -> Self {
+        Uuid(Cow::Owned(s))
     }
 }
 
@@ -199,6 +635,18 @@ impl BinProtShape for f64 {
     }
 }
 
+impl BinProtShape for crate::Float32 {
+    fn binprot_shape_impl(_: &mut ShapeContext) -> Shape {
+        base("float")
+    }
+}
+
+impl BinProtShape for crate::Char {
+    fn binprot_shape_impl(_: &mut ShapeContext) -> Shape {
+        base("char")
+    }
+}
+
 impl BinProtShape for String {
     fn binprot_shape_impl(_: &mut ShapeContext) -> Shape {
         base("string")
@@ -217,28 +665,141 @@ impl BinProtShape for char {
     }
 }
 
-impl BinProtShape for i32 {
+impl BinProtShape for () {
     fn binprot_shape_impl(_: &mut ShapeContext) -> Shape {
-        base("i32")
+        base("unit")
     }
 }
 
-impl BinProtShape for () {
+// All these types use the same serialization as OCaml ints. Int32.t also uses this
+// serialization and has its own shape.
+macro_rules! int_impls {
+    ( $ty: ty, $uuid: expr) => {
+        impl BinProtShape for $ty {
+            fn binprot_shape_impl(_: &mut ShapeContext) -> Shape {
+                base($uuid)
+            }
+        }
+    };
+}
+
+int_impls!(i32, "int32");
+int_impls!(u32, "int");
+int_impls!(i16, "int");
+int_impls!(u16, "int");
+int_impls!(i8, "int");
+int_impls!(u8, "int");
+int_impls!(u64, "int");
+int_impls!(usize, "int");
+int_impls!(isize, "int");
+
+impl BinProtShape for crate::Nat0 {
     fn binprot_shape_impl(_: &mut ShapeContext) -> Shape {
-        base("unit")
+        base("nat0")
+    }
+}
+
+impl BinProtShape for &'static str {
+    fn binprot_shape_impl(_: &mut ShapeContext) -> Shape {
+        base("string")
+    }
+}
+
+impl BinProtShape for crate::Bytes {
+    fn binprot_shape_impl(_: &mut ShapeContext) -> Shape {
+        base("bytes")
+    }
+}
+
+impl BinProtShape for crate::BorrowedBytes<'static> {
+    fn binprot_shape_impl(_: &mut ShapeContext) -> Shape {
+        base("bytes")
+    }
+}
+
+impl BinProtShape for crate::BufferWithLen {
+    fn binprot_shape_impl(_: &mut ShapeContext) -> Shape {
+        base("bigstring")
+    }
+}
+
+impl BinProtShape for crate::Vec32 {
+    fn binprot_shape_impl(_: &mut ShapeContext) -> Shape {
+        base("float32_vec")
     }
 }
 
+// Builtin containers are not type definitions and, as in OCaml, do not take part
+// in the recursion detection, only the types they contain do.
+macro_rules! container_shape_loop {
+    () => {
+        fn binprot_shape_loop(c: &mut ShapeContext) -> Shape {
+            Self::binprot_shape_impl(c)
+        }
+    };
+}
+
+// The length prefix is part of the shape, see the WithLen documentation.
+impl<T: BinProtShape> BinProtShape for crate::WithLen<T> {
+    fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
+        Shape::Base(Uuid::from("with_len"), vec![T::binprot_shape_loop(c)])
+    }
+
+    container_shape_loop!();
+}
+
 impl<T: BinProtShape> BinProtShape for Vec<T> {
     fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
         Shape::Base(Uuid::from("array"), vec![T::binprot_shape_loop(c)])
     }
+
+    container_shape_loop!();
+}
+
+impl<T: BinProtShape> BinProtShape for &'static [T] {
+    fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
+        Shape::Base(Uuid::from("array"), vec![T::binprot_shape_loop(c)])
+    }
+
+    container_shape_loop!();
+}
+
+// Fixed size arrays have the same shape as tuples.
+impl<T: BinProtShape, const N: usize> BinProtShape for [T; N] {
+    fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
+        Shape::Tuple((0..N).map(|_| T::binprot_shape_loop(c)).collect())
+    }
+
+    container_shape_loop!();
+}
+
+// The OCaml list type uses the same serialization as arrays.
+impl<T: BinProtShape> BinProtShape for LinkedList<T> {
+    fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
+        Shape::Base(Uuid::from("list"), vec![T::binprot_shape_loop(c)])
+    }
+
+    container_shape_loop!();
+}
+
+pub(crate) const ITERABLE_BINABLE_UUID: &str = "6592371a-4994-11e6-923a-7748e4182764";
+pub(crate) const ITERABLE_BINABLE1_UUID: &str = "ac8a9ff4-4994-11e6-9a1b-9fb4e933bd9d";
+pub(crate) const HASHTBL_UUID: &str = "8fabab0a-4992-11e6-8cca-9ba2c4686d9e";
+pub(crate) const MAP_UUID: &str = "dfb300f8-4992-11e6-9c15-73a2ac6b815c";
+
+// Shape used by Bin_prot.Utils.Make_iterable_binable, e.g. for Core's Set and Hash_set
+// types which have a fixed element type.
+fn iterable_binable_shape(caller_identity: Uuid, bin_shape_el: Shape) -> Shape {
+    Shape::Base(
+        caller_identity,
+        vec![Shape::Base(Uuid::from(ITERABLE_BINABLE_UUID), vec![bin_shape_el])],
+    )
 }
 
 fn iterable_binable1_shape(caller_identity: Uuid, bin_shape_el: Shape) -> Shape {
     Shape::Base(
         caller_identity,
-        vec![Shape::Base(Uuid::from("ac8a9ff4-4994-11e6-9a1b-9fb4e933bd9d"), vec![bin_shape_el])],
+        vec![Shape::Base(Uuid::from(ITERABLE_BINABLE1_UUID), vec![bin_shape_el])],
     )
 }
 
@@ -247,36 +808,195 @@ where
     S: 'static,
 {
     fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
-        let caller_identity = Uuid::from("8fabab0a-4992-11e6-8cca-9ba2c4686d9e");
+        let caller_identity = Uuid::from(HASHTBL_UUID);
         let bin_shape_el = Shape::Tuple(vec![K::binprot_shape_loop(c), V::binprot_shape_loop(c)]);
         iterable_binable1_shape(caller_identity, bin_shape_el)
     }
+
+    container_shape_loop!();
 }
 
 impl<K: BinProtShape, V: BinProtShape> BinProtShape for BTreeMap<K, V> {
     fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
-        let caller_identity = Uuid::from("dfb300f8-4992-11e6-9c15-73a2ac6b815c");
+        let caller_identity = Uuid::from(MAP_UUID);
         let bin_shape_el = Shape::Tuple(vec![K::binprot_shape_loop(c), V::binprot_shape_loop(c)]);
         iterable_binable1_shape(caller_identity, bin_shape_el)
     }
+
+    container_shape_loop!();
+}
+
+impl<T: BinProtShape> BinProtShape for BTreeSet<T> {
+    fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
+        let caller_identity = Uuid::from("8989278e-4992-11e6-8f4a-6b89776b1e53");
+        iterable_binable_shape(caller_identity, T::binprot_shape_loop(c))
+    }
+
+    container_shape_loop!();
+}
+
+impl<T: BinProtShape, S> BinProtShape for HashSet<T, S>
+where
+    S: 'static,
+{
+    fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
+        let caller_identity = Uuid::from("ad381672-4992-11e6-9e36-b76dc8cd466f");
+        iterable_binable_shape(caller_identity, T::binprot_shape_loop(c))
+    }
+
+    container_shape_loop!();
+}
+
+// Core's Queue type.
+impl<T: BinProtShape> BinProtShape for VecDeque<T> {
+    fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
+        let caller_identity = Uuid::from("b4c84254-4992-11e6-9ba7-734e154027bd");
+        iterable_binable1_shape(caller_identity, T::binprot_shape_loop(c))
+    }
+
+    container_shape_loop!();
 }
 
 impl<T: BinProtShape> BinProtShape for Option<T> {
     fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
         Shape::Base(Uuid::from("option"), vec![T::binprot_shape_loop(c)])
     }
+
+    container_shape_loop!();
 }
 
 impl<T: BinProtShape, E: BinProtShape> BinProtShape for Result<T, E> {
     fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
         Shape::Base(Uuid::from("result"), vec![T::binprot_shape_loop(c), E::binprot_shape_loop(c)])
     }
+
+    container_shape_loop!();
 }
 
 impl<T: BinProtShape> BinProtShape for Box<T> {
     fn binprot_shape_impl(c: &mut ShapeContext) -> Shape {
         T::binprot_shape_loop(c)
     }
+
+    container_shape_loop!();
+}
+
+// Shapes are serialized in the same way as the following OCaml type.
+//   type t =
+//     | Annotate of string * t
+//     | Base of string * t list
+//     | Tuple of t list
+//     | Record of (string * t) list
+//     | Variant of (string * t list) list
+//     | Poly_variant of (string * t option) list
+//     | Application of t * t list
+//     | Rec_app of int * t list
+//     | Var of int
+//   [@@deriving bin_io]
+impl BinProtWrite for Uuid {
+    fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        self.0.binprot_write(w)
+    }
+}
+
+impl BinProtRead for Uuid {
+    fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, crate::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Uuid(Cow::binprot_read(r)?))
+    }
+}
+
+impl BinProtSize for Uuid {
+    fn binprot_size(&self) -> usize {
+        self.0.binprot_size()
+    }
+}
+
+impl BinProtWrite for Shape {
+    fn binprot_write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        match self {
+            Shape::Annotate(uuid, t) => {
+                w.write_all(&[0])?;
+                uuid.binprot_write(w)?;
+                t.binprot_write(w)
+            }
+            Shape::Base(uuid, vec) => {
+                w.write_all(&[1])?;
+                uuid.binprot_write(w)?;
+                vec.binprot_write(w)
+            }
+            Shape::Tuple(vec) => {
+                w.write_all(&[2])?;
+                vec.binprot_write(w)
+            }
+            Shape::Record(vec) => {
+                w.write_all(&[3])?;
+                vec.binprot_write(w)
+            }
+            Shape::Variant(vec) => {
+                w.write_all(&[4])?;
+                vec.binprot_write(w)
+            }
+            Shape::PolyVariant(map) => {
+                w.write_all(&[5])?;
+                map.binprot_write(w)
+            }
+            Shape::Application(t, vec) => {
+                w.write_all(&[6])?;
+                t.binprot_write(w)?;
+                vec.binprot_write(w)
+            }
+            Shape::RecApp(n, vec) => {
+                w.write_all(&[7])?;
+                n.binprot_write(w)?;
+                vec.binprot_write(w)
+            }
+            Shape::Var(n) => {
+                w.write_all(&[8])?;
+                n.binprot_write(w)
+            }
+        }
+    }
+}
+
+impl BinProtRead for Shape {
+    fn binprot_read<R: Read + ?Sized>(r: &mut R) -> Result<Self, crate::Error>
+    where
+        Self: Sized,
+    {
+        let shape = match r.read_u8()? {
+            0 => Shape::Annotate(Uuid::binprot_read(r)?, Box::binprot_read(r)?),
+            1 => Shape::Base(Uuid::binprot_read(r)?, Vec::binprot_read(r)?),
+            2 => Shape::Tuple(Vec::binprot_read(r)?),
+            3 => Shape::Record(Vec::binprot_read(r)?),
+            4 => Shape::Variant(Vec::binprot_read(r)?),
+            5 => Shape::PolyVariant(BTreeMap::binprot_read(r)?),
+            6 => Shape::Application(Box::binprot_read(r)?, Vec::binprot_read(r)?),
+            7 => Shape::RecApp(i64::binprot_read(r)?, Vec::binprot_read(r)?),
+            8 => Shape::Var(i64::binprot_read(r)?),
+            index => return Err(crate::Error::UnexpectedVariantIndex { index, ident: "Shape" }),
+        };
+        Ok(shape)
+    }
+}
+
+impl BinProtSize for Shape {
+    fn binprot_size(&self) -> usize {
+        let size = match self {
+            Shape::Annotate(uuid, t) => uuid.binprot_size() + t.binprot_size(),
+            Shape::Base(uuid, vec) => uuid.binprot_size() + vec.binprot_size(),
+            Shape::Tuple(vec) => vec.binprot_size(),
+            Shape::Record(vec) => vec.binprot_size(),
+            Shape::Variant(vec) => vec.binprot_size(),
+            Shape::PolyVariant(map) => map.binprot_size(),
+            Shape::Application(t, vec) => t.binprot_size() + vec.binprot_size(),
+            Shape::RecApp(n, vec) => n.binprot_size() + vec.binprot_size(),
+            Shape::Var(n) => n.binprot_size(),
+        };
+        1 + size
+    }
 }
 
 #[cfg(test)]
@@ -296,11 +1016,11 @@ mod tests {
         assert_eq!(digest_str(&base("float")), "1fd923acb2dd9c5d401ad5b08b1d40cd");
         assert_eq!(digest_str(&base("bool")), "a25306e4c5d30d35adbb5b0462a6b1b3");
         assert_eq!(digest_str(&base("char")), "84610d32d63dcff5c93f1033ec8cb1d5");
-        let shape_t = Shape::Record(vec![("t", base("int"))]);
+        let shape_t = Shape::Record(vec![("t".into(), base("int"))]);
         assert_eq!(digest_str(&shape_t), "43fa87a0bac7a0bb295f67cdc685aa26");
-        let shape_u = Shape::Record(vec![("t", base("int")), ("u", base("float"))]);
+        let shape_u = Shape::Record(vec![("t".into(), base("int")), ("u".into(), base("float"))]);
         assert_eq!(digest_str(&shape_u), "485a864ae3ab9d4e12534fd17f64a7c4");
-        let shape_v = Shape::Record(vec![("t", shape_t), ("u", shape_u)]);
+        let shape_v = Shape::Record(vec![("t".into(), shape_t), ("u".into(), shape_u)]);
         assert_eq!(digest_str(&shape_v), "3a9e779c28768361e904e90f37728927");
         // Shape used for some recursive type, see tests/shape_tests.ml
         //   type int_list =
@@ -308,8 +1028,8 @@ mod tests {
         //     | Cons of (int * int_list)
         let shape_rec = {
             let inner = Shape::Variant(vec![
-                ("Empty", vec![]),
-                ("Cons", vec![Shape::Tuple(vec![base("int"), Shape::RecApp(0, vec![])])]),
+                ("Empty".into(), vec![]),
+                ("Cons".into(), vec![Shape::Tuple(vec![base("int"), Shape::RecApp(0, vec![])])]),
             ]);
             Shape::Application(Box::new(inner), vec![])
         };
@@ -317,7 +1037,7 @@ mod tests {
         // type simple_rec = { foo : simple_rec option } [@@deriving bin_io]
         let shape_rec = {
             let inner = Shape::Record(vec![(
-                "foo",
+                "foo".into(),
                 Shape::Base("option".into(), vec![Shape::RecApp(0, vec![])]),
             )]);
             Shape::Application(Box::new(inner), vec![])
@@ -332,4 +1052,36 @@ mod tests {
         };
         assert_eq!(digest_str(&shape_i64_i64_btreemap), "ed73a010af8ffc32cab7411d6be2d676");
     }
+
+    #[test]
+    fn shape_eval() {
+        let app = |t: Shape, args: Vec<Shape>| Shape::Application(Box::new(t), args);
+        let option = |t: Shape| Shape::Base(Uuid::from("option"), vec![t]);
+        // Non-recursive definitions are expanded.
+        let shape_t = Shape::Record(vec![("t".into(), base("int"))]);
+        let applied = app(Shape::Record(vec![("t".into(), Shape::Var(0))]), vec![base("int")]);
+        assert_eq!(applied.eval().shape(), &shape_t);
+        assert_eq!(digest_str(&applied), "43fa87a0bac7a0bb295f67cdc685aa26");
+        let nested = app(app(Shape::Tuple(vec![Shape::Var(0), Shape::Var(1)]), vec![]), vec![]);
+        assert_eq!(nested.eval().into_shape(), Shape::Tuple(vec![Shape::Var(0), Shape::Var(1)]));
+        // Recursive definitions are kept, references to enclosing definitions
+        // are adjusted when an inner definition gets expanded.
+        let shape_rec =
+            app(Shape::Record(vec![("foo".into(), option(Shape::RecApp(0, vec![])))]), vec![]);
+        assert_eq!(shape_rec.eval().shape(), &shape_rec);
+        let inner = app(Shape::Record(vec![("x".into(), Shape::RecApp(1, vec![]))]), vec![]);
+        let shape_rec2 = app(Shape::Record(vec![("foo".into(), option(inner))]), vec![]);
+        let expected = {
+            let inner = Shape::Record(vec![("x".into(), Shape::RecApp(0, vec![]))]);
+            app(Shape::Record(vec![("foo".into(), option(inner))]), vec![])
+        };
+        assert_eq!(shape_rec2.eval().shape(), &expected);
+        assert_eq!(digest_str(&shape_rec2), digest_str(&expected));
+        let list = Shape::Variant(vec![
+            ("Empty".into(), vec![]),
+            ("Cons".into(), vec![Shape::Var(0), Shape::RecApp(0, vec![Shape::Var(0)])]),
+        ]);
+        let int_list = app(list.clone(), vec![app(Shape::Var(0), vec![base("int")])]);
+        assert_eq!(int_list.eval().into_shape(), app(list, vec![base("int")]));
+    }
 }
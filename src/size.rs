@@ -0,0 +1,253 @@
+// Size computations, these have to be kept in sync with the BinProtWrite
+// implementations.
+use crate::traits::BinProtSize;
+use crate::{int, BorrowedBytes, BufferWithLen, Bytes, Char, Float32, Nat0, Vec32, WithLen};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, LinkedList, VecDeque};
+
+impl BinProtSize for Nat0 {
+    fn binprot_size(&self) -> usize {
+        int::nat0_size(self.0)
+    }
+}
+
+impl BinProtSize for i64 {
+    fn binprot_size(&self) -> usize {
+        int::i64_size(*self)
+    }
+}
+
+impl BinProtSize for f64 {
+    fn binprot_size(&self) -> usize {
+        8
+    }
+}
+
+impl BinProtSize for Float32 {
+    fn binprot_size(&self) -> usize {
+        8
+    }
+}
+
+impl BinProtSize for Char {
+    fn binprot_size(&self) -> usize {
+        1
+    }
+}
+
+impl BinProtSize for () {
+    fn binprot_size(&self) -> usize {
+        1
+    }
+}
+
+impl BinProtSize for bool {
+    fn binprot_size(&self) -> usize {
+        1
+    }
+}
+
+impl<T: BinProtSize> BinProtSize for Option<T> {
+    fn binprot_size(&self) -> usize {
+        match self {
+            None => 1,
+            Some(v) => 1 + v.binprot_size(),
+        }
+    }
+}
+
+impl<T: BinProtSize, E: BinProtSize> BinProtSize for Result<T, E> {
+    fn binprot_size(&self) -> usize {
+        match self {
+            Ok(v) => 1 + v.binprot_size(),
+            Err(e) => 1 + e.binprot_size(),
+        }
+    }
+}
+
+impl<T: BinProtSize> BinProtSize for Box<T> {
+    fn binprot_size(&self) -> usize {
+        self.as_ref().binprot_size()
+    }
+}
+
+impl<T: BinProtSize> BinProtSize for Vec<T> {
+    fn binprot_size(&self) -> usize {
+        self.as_slice().binprot_size()
+    }
+}
+
+impl BinProtSize for Vec<f32> {
+    fn binprot_size(&self) -> usize {
+        int::nat0_size(self.len() as u64) + 4 * self.len()
+    }
+}
+
+impl BinProtSize for Vec32 {
+    fn binprot_size(&self) -> usize {
+        self.0.binprot_size()
+    }
+}
+
+impl<T: BinProtSize> BinProtSize for [T] {
+    fn binprot_size(&self) -> usize {
+        let len = int::nat0_size(self.len() as u64);
+        self.iter().fold(len, |acc, v| acc + v.binprot_size())
+    }
+}
+
+impl<T: BinProtSize> BinProtSize for &[T] {
+    fn binprot_size(&self) -> usize {
+        (*self).binprot_size()
+    }
+}
+
+impl<T: BinProtSize, const N: usize> BinProtSize for [T; N] {
+    fn binprot_size(&self) -> usize {
+        self.iter().map(|v| v.binprot_size()).sum()
+    }
+}
+
+fn bytes_size(bytes: &[u8]) -> usize {
+    int::nat0_size(bytes.len() as u64) + bytes.len()
+}
+
+impl BinProtSize for String {
+    fn binprot_size(&self) -> usize {
+        bytes_size(self.as_bytes())
+    }
+}
+
+impl BinProtSize for &str {
+    fn binprot_size(&self) -> usize {
+        bytes_size(self.as_bytes())
+    }
+}
+
+impl BinProtSize for std::borrow::Cow<'_, str> {
+    fn binprot_size(&self) -> usize {
+        bytes_size(self.as_bytes())
+    }
+}
+
+impl BinProtSize for Bytes {
+    fn binprot_size(&self) -> usize {
+        bytes_size(&self.0)
+    }
+}
+
+impl BinProtSize for BorrowedBytes<'_> {
+    fn binprot_size(&self) -> usize {
+        bytes_size(self.0)
+    }
+}
+
+impl BinProtSize for BufferWithLen {
+    fn binprot_size(&self) -> usize {
+        bytes_size(&self.0)
+    }
+}
+
+impl<T: BinProtSize> BinProtSize for WithLen<T> {
+    fn binprot_size(&self) -> usize {
+        let len = self.0.binprot_size();
+        int::nat0_size(len as u64) + len
+    }
+}
+
+impl<K: BinProtSize, V: BinProtSize> BinProtSize for BTreeMap<K, V> {
+    fn binprot_size(&self) -> usize {
+        let len = int::nat0_size(self.len() as u64);
+        self.iter().fold(len, |acc, (k, v)| acc + k.binprot_size() + v.binprot_size())
+    }
+}
+
+impl<K: BinProtSize, V: BinProtSize, S> BinProtSize for HashMap<K, V, S> {
+    fn binprot_size(&self) -> usize {
+        let len = int::nat0_size(self.len() as u64);
+        self.iter().fold(len, |acc, (k, v)| acc + k.binprot_size() + v.binprot_size())
+    }
+}
+
+impl<T: BinProtSize> BinProtSize for BTreeSet<T> {
+    fn binprot_size(&self) -> usize {
+        let len = int::nat0_size(self.len() as u64);
+        self.iter().fold(len, |acc, v| acc + v.binprot_size())
+    }
+}
+
+impl<T: BinProtSize, S> BinProtSize for HashSet<T, S> {
+    fn binprot_size(&self) -> usize {
+        let len = int::nat0_size(self.len() as u64);
+        self.iter().fold(len, |acc, v| acc + v.binprot_size())
+    }
+}
+
+impl<T: BinProtSize> BinProtSize for VecDeque<T> {
+    fn binprot_size(&self) -> usize {
+        let len = int::nat0_size(self.len() as u64);
+        self.iter().fold(len, |acc, v| acc + v.binprot_size())
+    }
+}
+
+impl<T: BinProtSize> BinProtSize for LinkedList<T> {
+    fn binprot_size(&self) -> usize {
+        let len = int::nat0_size(self.len() as u64);
+        self.iter().fold(len, |acc, v| acc + v.binprot_size())
+    }
+}
+
+macro_rules! tuple_impls {
+    ( $( $name:ident )+ ) => {
+        impl<$($name: BinProtSize),+> BinProtSize for ($($name,)+)
+        {
+            #[allow(non_snake_case)]
+            fn binprot_size(&self) -> usize {
+                let ($($name,)+) = self;
+                0 $(+ $name.binprot_size())+
+            }
+        }
+    };
+}
+
+tuple_impls! { A }
+tuple_impls! { A B }
+tuple_impls! { A B C }
+tuple_impls! { A B C D }
+tuple_impls! { A B C D E }
+tuple_impls! { A B C D E F }
+tuple_impls! { A B C D E F G }
+tuple_impls! { A B C D E F G H }
+tuple_impls! { A B C D E F G H I }
+
+macro_rules! int_impls {
+    ( $ty: ty) => {
+        impl BinProtSize for $ty {
+            fn binprot_size(&self) -> usize {
+                int::i64_size((*self).into())
+            }
+        }
+    };
+}
+
+int_impls!(i32);
+int_impls!(u32);
+int_impls!(i16);
+int_impls!(u16);
+int_impls!(i8);
+int_impls!(u8);
+
+macro_rules! int63_impls {
+    ( $ty: ty) => {
+        impl BinProtSize for $ty {
+            fn binprot_size(&self) -> usize {
+                // Values that do not fit in 63 bits cannot be written, the size of
+                // the largest int encoding is used for them.
+                int::i64_size(int::to_int63(*self).unwrap_or(i64::MAX))
+            }
+        }
+    };
+}
+
+int63_impls!(u64);
+int63_impls!(usize);
+int63_impls!(isize);
@@ -1,37 +1,193 @@
 use crate::Shape;
-use std::collections::{hash_map::Entry, HashMap};
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
 
-pub type ShapeContext = HashMap<std::any::TypeId, bool>;
+/// The type definitions for which the shape is being computed, this is used to
+/// detect recursive types.
+#[derive(Debug, Default)]
+pub struct ShapeContext {
+    // Innermost definition last.
+    pending: Vec<PendingDefinition>,
+}
+
+#[derive(Debug)]
+struct PendingDefinition {
+    typeid: TypeId,
+    recursive: bool,
+    refers_to_outer: bool,
+}
 
+/// The shape of a type as used by `Bin_prot.Shape` in OCaml. Types are identified
+/// by their `TypeId` to detect recursion so implementors have to be `'static`:
+/// shapes do not depend on lifetimes and types with lifetime parameters implement
+/// this for `'static` lifetimes, e.g. `&'static str` or `Foo<'static>` when using
+/// the derive macro.
 pub trait BinProtShape: 'static {
     fn binprot_shape_impl(_: &mut ShapeContext) -> Shape;
 
-    fn binprot_shape_loop(typeids: &mut ShapeContext) -> Shape {
-        let typeid = std::any::TypeId::of::<Self>();
-        match typeids.entry(typeid) {
-            Entry::Occupied(mut e) => {
-                // TODO: Adjust the parameters.
-                e.insert(true);
-                Shape::RecApp(0, vec![])
-            }
-            Entry::Vacant(e) => {
-                e.insert(false);
-                let shape = Self::binprot_shape_impl(typeids);
-                match typeids.remove(&typeid) {
-                    None | Some(false) => shape,
-                    Some(true) => Shape::Application(Box::new(shape), vec![]),
+    /// The shape of the type when used within another type. This defaults to the
+    /// shape returned by `binprot_shape_impl` computed as a definition without
+    /// type parameters, so that recursive types result in a recursive reference
+    /// rather than an infinite loop. Generic types should go through
+    /// `binprot_shape_definition` with their parameters as done by the derive macro.
+    fn binprot_shape_loop(c: &mut ShapeContext) -> Shape {
+        binprot_shape_definition(c, TypeId::of::<Self>(), vec![], Self::binprot_shape_impl)
+    }
+
+    fn binprot_shape() -> Shape {
+        Self::binprot_shape_loop(&mut ShapeContext::default())
+    }
+}
+
+/// Computes the shape of a type definition identified by `typeid`, `args` are the
+/// shapes of the type parameters and `shape_impl` returns the body of the definition
+/// in which these parameters appear as `Shape::Var`.
+/// Similarly to `Bin_shape.eval` in OCaml, the parameters are substituted in the
+/// body for non-recursive types whereas recursive types result in the body being
+/// applied to the parameters.
+pub fn binprot_shape_definition(
+    c: &mut ShapeContext,
+    typeid: TypeId,
+    args: Vec<Shape>,
+    shape_impl: fn(&mut ShapeContext) -> Shape,
+) -> Shape {
+    shape_definition(c, typeid, args, shape_impl)
+}
+
+/// Computes the tags of a polymorphic variant definition inherited by another
+/// polymorphic variant, `tags_impl` returns the tags of the definition in which
+/// the type parameters appear as `Shape::Var`. Contrary to `binprot_shape_definition`
+/// the body is computed even when `typeid` is already being defined as the tags
+/// are needed, recursive definitions are unfolded.
+pub fn binprot_shape_inherited(
+    c: &mut ShapeContext,
+    typeid: TypeId,
+    args: Vec<Shape>,
+    tags_impl: fn(&mut ShapeContext) -> BTreeMap<Cow<'static, str>, Option<Shape>>,
+) -> BTreeMap<Cow<'static, str>, Option<Shape>> {
+    c.pending.push(PendingDefinition { typeid, recursive: false, refers_to_outer: false });
+    let tags = tags_impl(c);
+    let definition = c.pending.pop().unwrap();
+    if definition.recursive {
+        Shape::unfold_poly_variant(tags, &args)
+    } else {
+        let inline = |shape: Shape| {
+            let shape = if definition.refers_to_outer { shape.unshift_rec_apps(0) } else { shape };
+            shape.subst_vars(&args)
+        };
+        tags.into_iter().map(|(name, shape)| (name, shape.map(inline))).collect()
+    }
+}
+
+fn shape_definition(
+    c: &mut ShapeContext,
+    typeid: TypeId,
+    args: Vec<Shape>,
+    shape_impl: fn(&mut ShapeContext) -> Shape,
+) -> Shape {
+    let depth = c.pending.len();
+    // The innermost definition is used as inherited definitions can appear twice.
+    if let Some(index) = c.pending.iter().rposition(|d| d.typeid == typeid) {
+        c.pending[index].recursive = true;
+        for d in c.pending[index + 1..].iter_mut() {
+            d.refers_to_outer = true
+        }
+        // RecApp uses de Bruijn indexes, 0 is the innermost definition.
+        return Shape::RecApp((depth - 1 - index) as i64, args);
+    }
+    c.pending.push(PendingDefinition { typeid, recursive: false, refers_to_outer: false });
+    let shape = shape_impl(c);
+    let definition = c.pending.pop().unwrap();
+    if definition.recursive {
+        Shape::Application(Box::new(shape), args)
+    } else {
+        // The definition gets inlined so references to the enclosing definitions
+        // have to be adjusted.
+        let shape = if definition.refers_to_outer { shape.unshift_rec_apps(0) } else { shape };
+        shape.subst_vars(&args)
+    }
+}
+
+/// The hashes of the tags of a polymorphic variant including the inherited ones,
+/// these are known at compile time so that collisions between inherited tags can
+/// be rejected when building rather than when computing shapes.
+#[derive(Debug)]
+pub struct TagHashes {
+    hashes: &'static [i32],
+    inherited: &'static [&'static TagHashes],
+}
+
+impl TagHashes {
+    /// Panics if two of the tags have the same hash, this is meant to be used when
+    /// defining `BinProtShapeTagged::BINPROT_TAG_HASHES` so that the panic happens
+    /// at compile time.
+    pub const fn new(hashes: &'static [i32], inherited: &'static [&'static TagHashes]) -> Self {
+        let res = TagHashes { hashes, inherited };
+        let len = res.len();
+        let mut i = 0;
+        while i < len {
+            let mut j = i + 1;
+            while j < len {
+                if res.get(i) == res.get(j) {
+                    panic!("a polymorphic variant tag appears twice or two tags have the same hash")
                 }
+                j += 1
             }
+            i += 1
         }
+        res
     }
 
-    fn binprot_shape() -> Shape {
-        let mut typeids = HashMap::new();
-        Self::binprot_shape_loop(&mut typeids)
+    const fn len(&self) -> usize {
+        let mut len = self.hashes.len();
+        let mut i = 0;
+        while i < self.inherited.len() {
+            len += self.inherited[i].len();
+            i += 1
+        }
+        len
+    }
+
+    const fn get(&self, mut index: usize) -> i32 {
+        if index < self.hashes.len() {
+            return self.hashes[index];
+        }
+        index -= self.hashes.len();
+        let mut i = 0;
+        loop {
+            let inherited = self.inherited[i];
+            if index < inherited.len() {
+                return inherited.get(index);
+            }
+            index -= inherited.len();
+            i += 1
+        }
     }
 }
 
+/// Implemented by the polymorphic variants deriving `BinProtShape`, only these can
+/// be inherited by other polymorphic variants using `#[binprot(flatten)]`.
+pub trait BinProtShapeTagged: BinProtShape {
+    /// The hashes of the tags returned by `binprot_shape_tagged`.
+    const BINPROT_TAG_HASHES: TagHashes;
+
+    /// The tags of the type when inherited, this is computed with
+    /// `binprot_shape_inherited` so that they never contain a recursive reference to
+    /// the type itself.
+    fn binprot_shape_tagged(c: &mut ShapeContext) -> BTreeMap<Cow<'static, str>, Option<Shape>>;
+}
+
+/// The number of bytes used by the serialized representation of a value, this is
+/// computed without serializing the value.
+///
+/// This used to be implemented for all `BinProtWrite` types by serializing the
+/// value, it now has to be implemented explicitly, usually via
+/// `#[derive(BinProtSize)]`. This is a breaking change for code calling
+/// `binprot_size` or writing a `WithLen<T>` where `T` only implements `BinProtWrite`,
+/// such types can keep the previous behavior by implementing `binprot_size` with
+/// `binprot::binprot_size_by_writing(self)`.
 pub trait BinProtSize {
     fn binprot_size(&self) -> usize;
 }
@@ -46,29 +202,27 @@ pub trait BinProtRead {
         Self: Sized;
 }
 
-struct SizeWrite(usize);
-
-impl Write for SizeWrite {
-    fn write(&mut self, data: &[u8]) -> std::result::Result<usize, std::io::Error> {
-        let len = data.len();
-        self.0 += len;
-        Ok(len)
-    }
-    fn flush(&mut self) -> std::result::Result<(), std::io::Error> {
-        Ok(())
-    }
+/// Decoding from a byte slice, the decoded value can borrow from the input data
+/// rather than allocating, e.g. when reading `&'de str` or `&'de [u8]`.
+pub trait BinProtReadBorrowed<'de>: Sized {
+    fn binprot_read_borrowed(r: &mut &'de [u8]) -> Result<Self, crate::error::Error>;
 }
 
-impl SizeWrite {
-    fn new() -> Self {
-        SizeWrite(0)
-    }
+/// Decoding of polymorphic variants once their tag has been read, this is
+/// implemented by the derive macros and used for polymorphic variants included
+/// in other ones via `#[binprot(flatten)]`. `None` is returned when the tag is
+/// not one of the type tags, in which case nothing is read.
+pub trait BinProtReadTagged: Sized {
+    fn binprot_read_tagged<R: Read + ?Sized>(
+        tag: i32,
+        r: &mut R,
+    ) -> Result<Option<Self>, crate::error::Error>;
 }
 
-impl<T: BinProtWrite> BinProtSize for T {
-    fn binprot_size(&self) -> usize {
-        let mut w = SizeWrite::new();
-        self.binprot_write(&mut w).unwrap();
-        w.0
-    }
+/// Same as `BinProtReadTagged` but decoding from a byte slice.
+pub trait BinProtReadBorrowedTagged<'de>: Sized {
+    fn binprot_read_borrowed_tagged(
+        tag: i32,
+        r: &mut &'de [u8],
+    ) -> Result<Option<Self>, crate::error::Error>;
 }
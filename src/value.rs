@@ -0,0 +1,436 @@
+// Dynamic decoding and encoding driven by shapes, this makes it possible to
+// inspect values without having a Rust type for them.
+use crate::error::Error;
+use crate::int::variant_int;
+use crate::shape::{HASHTBL_UUID, ITERABLE_BINABLE1_UUID, ITERABLE_BINABLE_UUID, MAP_UUID};
+use crate::{
+    BinProtRead, BinProtShape, BinProtWrite, BufferWithLen, Bytes, Char, Nat0, Shape, Vec32,
+};
+use byteorder::{LittleEndian, ReadBytesExt};
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+/// A decoded value, the variants follow the OCaml types rather than the Rust
+/// ones, e.g. arrays, lists and sets all result in a `List`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Unit,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Char(u8),
+    String(String),
+    Bytes(Vec<u8>),
+    Tuple(Vec<Value>),
+    Record(Vec<(String, Value)>),
+    Variant(String, Vec<Value>),
+    PolyVariant(String, Option<Box<Value>>),
+    List(Vec<Value>),
+    Option(Option<Box<Value>>),
+    Map(Vec<(Value, Value)>),
+}
+
+// Guards against shapes that never reach an actual type, e.g. a definition
+// whose body is a recursive reference to itself.
+const MAX_RESOLVE_STEPS: usize = 1024;
+
+// Values are decoded and encoded recursively, deeper values are rejected rather
+// than overflowing the stack, e.g. on untrusted input for a recursive shape.
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Default)]
+struct Env<'a> {
+    // The bodies of the enclosing recursive definitions, innermost last.
+    defs: Vec<&'a Shape>,
+    // The arguments of the innermost definition together with the environment
+    // in which they have to be evaluated.
+    vars: Vec<(&'a Shape, Rc<Env<'a>>)>,
+}
+
+impl<'a> Env<'a> {
+    // Follows annotations, applications and variables until reaching a shape
+    // that describes the encoding.
+    fn resolve(&self, mut shape: &'a Shape) -> Result<(&'a Shape, Env<'a>), Error> {
+        let mut env = self.clone();
+        for _step in 0..MAX_RESOLVE_STEPS {
+            match shape {
+                Shape::Annotate(_, t) => shape = t,
+                Shape::Application(body, args) => {
+                    let outer = Rc::new(env.clone());
+                    env.defs.push(body);
+                    env.vars = args.iter().map(|arg| (arg, outer.clone())).collect();
+                    shape = body
+                }
+                Shape::RecApp(n, args) => {
+                    let index = usize::try_from(*n)
+                        .ok()
+                        .and_then(|n| env.defs.len().checked_sub(n + 1))
+                        .ok_or_else(|| Error::UnsupportedShape(Box::new(shape.clone())))?;
+                    let outer = Rc::new(env.clone());
+                    env.defs.truncate(index + 1);
+                    env.vars = args.iter().map(|arg| (arg, outer.clone())).collect();
+                    shape = env.defs[index]
+                }
+                Shape::Var(v) => {
+                    let (var_shape, var_env) = usize::try_from(*v)
+                        .ok()
+                        .and_then(|v| env.vars.get(v))
+                        .ok_or_else(|| Error::UnsupportedShape(Box::new(shape.clone())))?;
+                    shape = var_shape;
+                    env = (**var_env).clone()
+                }
+                shape => return Ok((shape, env)),
+            }
+        }
+        Err(Error::UnsupportedShape(Box::new(shape.clone())))
+    }
+}
+
+// The element shape of types serialized via Bin_prot.Utils.Make_iterable_binable
+// and Make_iterable_binable1, e.g. Core's sets, maps and hash tables.
+fn iterable_element(args: &[Shape]) -> Option<&Shape> {
+    match args {
+        [Shape::Base(uuid, args)] => match (uuid.as_str(), args.as_slice()) {
+            (ITERABLE_BINABLE_UUID, [el]) | (ITERABLE_BINABLE1_UUID, [el]) => Some(el),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn read_list<R: Read + ?Sized>(
+    r: &mut R,
+    el: &Shape,
+    env: &Env,
+    depth: usize,
+) -> Result<Vec<Value>, Error> {
+    let len = Nat0::binprot_read(r)?.0;
+    // Avoid large allocations on invalid lengths.
+    let mut res = Vec::with_capacity(std::cmp::min(len as usize, 4096));
+    for _i in 0..len {
+        res.push(read(r, el, env, depth)?)
+    }
+    Ok(res)
+}
+
+// Base types are decoded separately so that the stack frame of read, which is
+// used for every nesting level, stays small.
+fn read_base<R: Read + ?Sized>(
+    r: &mut R,
+    shape: &Shape,
+    uuid: &str,
+    args: &[Shape],
+    env: &Env,
+    depth: usize,
+) -> Result<Value, Error> {
+    let value = match (uuid, args) {
+        ("int", []) | ("int32", []) | ("int64", []) => Value::Int(i64::binprot_read(r)?),
+        ("nat0", []) => Value::Int(i64::try_from(Nat0::binprot_read(r)?.0)?),
+        ("float", []) => Value::Float(f64::binprot_read(r)?),
+        ("bool", []) => Value::Bool(bool::binprot_read(r)?),
+        ("unit", []) => {
+            <()>::binprot_read(r)?;
+            Value::Unit
+        }
+        ("char", []) => Value::Char(Char::binprot_read(r)?.0),
+        ("string", []) => Value::String(String::binprot_read(r)?),
+        ("bytes", []) => Value::Bytes(Bytes::binprot_read(r)?.0),
+        ("bigstring", []) => Value::Bytes(BufferWithLen::binprot_read(r)?.0),
+        ("float32_vec", []) => {
+            let Vec32(v) = Vec32::binprot_read(r)?;
+            Value::List(v.into_iter().map(|f| Value::Float(f as f64)).collect())
+        }
+        ("array", [el]) | ("list", [el]) => Value::List(read_list(r, el, env, depth + 1)?),
+        ("with_len", [el]) => {
+            let len = Nat0::binprot_read(r)?.0;
+            // The payload is buffered as wrapping the reader would result in
+            // an unbounded number of reader types for nested payloads.
+            let mut payload = vec![];
+            Read::take(r, len).read_to_end(&mut payload)?;
+            if (payload.len() as u64) < len {
+                return Err(Error::PayloadTooShort { len });
+            }
+            let mut payload = payload.as_slice();
+            let value = read(&mut payload, el, env, depth + 1)?;
+            let remaining = payload.len() as u64;
+            if remaining != 0 {
+                return Err(Error::PayloadNotFullyConsumed { len, remaining });
+            }
+            value
+        }
+        ("option", [el]) => match r.read_u8()? {
+            0 => Value::Option(None),
+            1 => Value::Option(Some(Box::new(read(r, el, env, depth + 1)?))),
+            c => return Err(Error::UnexpectedValueForOption(c)),
+        },
+        ("result", [ok, err]) => match r.read_u8()? {
+            0 => Value::Variant("Ok".to_string(), vec![read(r, ok, env, depth + 1)?]),
+            1 => Value::Variant("Error".to_string(), vec![read(r, err, env, depth + 1)?]),
+            index => return Err(Error::UnexpectedVariantIndex { index, ident: "Result" }),
+        },
+        (uuid, args) => match iterable_element(args) {
+            Some(el) => {
+                let values = read_list(r, el, env, depth + 1)?;
+                match uuid {
+                    HASHTBL_UUID | MAP_UUID => {
+                        let mut map = Vec::with_capacity(values.len());
+                        for value in values.into_iter() {
+                            match value {
+                                Value::Tuple(mut kv) if kv.len() == 2 => {
+                                    let v = kv.pop().unwrap();
+                                    let k = kv.pop().unwrap();
+                                    map.push((k, v))
+                                }
+                                _ => return Err(Error::UnsupportedShape(Box::new(shape.clone()))),
+                            }
+                        }
+                        Value::Map(map)
+                    }
+                    _ => Value::List(values),
+                }
+            }
+            None => return Err(Error::UnsupportedShape(Box::new(shape.clone()))),
+        },
+    };
+    Ok(value)
+}
+
+fn read_all<R: Read + ?Sized>(
+    r: &mut R,
+    shapes: &[Shape],
+    env: &Env,
+    depth: usize,
+) -> Result<Vec<Value>, Error> {
+    let mut values = Vec::with_capacity(shapes.len());
+    for s in shapes.iter() {
+        values.push(read(r, s, env, depth)?)
+    }
+    Ok(values)
+}
+
+fn read<R: Read + ?Sized>(
+    r: &mut R,
+    shape: &Shape,
+    env: &Env,
+    depth: usize,
+) -> Result<Value, Error> {
+    if depth > MAX_DEPTH {
+        return Err(Error::NestingTooDeep);
+    }
+    let resolved;
+    let (shape, env) = match shape {
+        Shape::Annotate(_, _) | Shape::Application(_, _) | Shape::RecApp(_, _) | Shape::Var(_) => {
+            resolved = env.resolve(shape)?;
+            (resolved.0, &resolved.1)
+        }
+        shape => (shape, env),
+    };
+    let value = match shape {
+        Shape::Annotate(_, _) | Shape::Application(_, _) | Shape::RecApp(_, _) | Shape::Var(_) => {
+            return Err(Error::UnsupportedShape(Box::new(shape.clone())))
+        }
+        Shape::Base(uuid, args) => read_base(r, shape, uuid.as_str(), args, env, depth)?,
+        Shape::Tuple(shapes) => Value::Tuple(read_all(r, shapes, env, depth + 1)?),
+        Shape::Record(fields) => {
+            let mut values = Vec::with_capacity(fields.len());
+            for (name, s) in fields.iter() {
+                values.push((name.to_string(), read(r, s, env, depth + 1)?))
+            }
+            Value::Record(values)
+        }
+        Shape::Variant(cases) => {
+            let index = r.read_u8()?;
+            let (name, args) = cases
+                .get(index as usize)
+                .ok_or(Error::UnexpectedVariantIndex { index, ident: "Value" })?;
+            Value::Variant(name.to_string(), read_all(r, args, env, depth + 1)?)
+        }
+        Shape::PolyVariant(cases) => {
+            let index = r.read_i32::<LittleEndian>()?;
+            let (name, arg) = cases
+                .iter()
+                .find(|(name, _)| variant_int(name) == index)
+                .ok_or(Error::UnexpectedPolymorphicVariantIndex { index, ident: "Value" })?;
+            let arg = match arg {
+                None => None,
+                Some(s) => Some(Box::new(read(r, s, env, depth + 1)?)),
+            };
+            Value::PolyVariant(name.to_string(), arg)
+        }
+    };
+    Ok(value)
+}
+
+fn write_list<W: Write>(
+    w: &mut W,
+    vs: &[Value],
+    el: &Shape,
+    env: &Env,
+    depth: usize,
+) -> Result<(), Error> {
+    Nat0(vs.len() as u64).binprot_write(w)?;
+    for v in vs.iter() {
+        write(w, v, el, env, depth)?
+    }
+    Ok(())
+}
+
+fn write<W: Write>(
+    w: &mut W,
+    value: &Value,
+    shape: &Shape,
+    env: &Env,
+    depth: usize,
+) -> Result<(), Error> {
+    if depth > MAX_DEPTH {
+        return Err(Error::NestingTooDeep);
+    }
+    let resolved;
+    let (shape, env) = match shape {
+        Shape::Annotate(_, _) | Shape::Application(_, _) | Shape::RecApp(_, _) | Shape::Var(_) => {
+            resolved = env.resolve(shape)?;
+            (resolved.0, &resolved.1)
+        }
+        shape => (shape, env),
+    };
+    let mismatch = || Error::ValueDoesNotMatchShape {
+        value: Box::new(value.clone()),
+        shape: Box::new(shape.clone()),
+    };
+    match (shape, value) {
+        (Shape::Base(uuid, args), value) => match (uuid.as_str(), args.as_slice(), value) {
+            ("int", [], Value::Int(i))
+            | ("int32", [], Value::Int(i))
+            | ("int64", [], Value::Int(i)) => i.binprot_write(w)?,
+            ("nat0", [], Value::Int(i)) => Nat0(u64::try_from(*i)?).binprot_write(w)?,
+            ("float", [], Value::Float(f)) => f.binprot_write(w)?,
+            ("bool", [], Value::Bool(b)) => b.binprot_write(w)?,
+            ("unit", [], Value::Unit) => ().binprot_write(w)?,
+            ("char", [], Value::Char(c)) => Char(*c).binprot_write(w)?,
+            ("string", [], Value::String(s)) => s.binprot_write(w)?,
+            ("bytes", [], Value::Bytes(b)) | ("bigstring", [], Value::Bytes(b)) => {
+                Nat0(b.len() as u64).binprot_write(w)?;
+                w.write_all(b)?
+            }
+            ("float32_vec", [], Value::List(vs)) => {
+                let vs = vs
+                    .iter()
+                    .map(|v| match v {
+                        Value::Float(f) => Ok(*f as f32),
+                        _ => Err(mismatch()),
+                    })
+                    .collect::<Result<_, _>>()?;
+                Vec32(vs).binprot_write(w)?
+            }
+            ("array", [el], Value::List(vs)) | ("list", [el], Value::List(vs)) => {
+                write_list(w, vs, el, env, depth + 1)?
+            }
+            ("with_len", [el], value) => {
+                let mut payload = Vec::new();
+                write(&mut payload, value, el, env, depth + 1)?;
+                Nat0(payload.len() as u64).binprot_write(w)?;
+                w.write_all(&payload)?
+            }
+            ("option", [_], Value::Option(None)) => w.write_all(&[0u8])?,
+            ("option", [el], Value::Option(Some(v))) => {
+                w.write_all(&[1u8])?;
+                write(w, v, el, env, depth + 1)?
+            }
+            ("result", [ok, err], Value::Variant(name, args)) => {
+                match (name.as_str(), args.as_slice()) {
+                    ("Ok", [v]) => {
+                        w.write_all(&[0u8])?;
+                        write(w, v, ok, env, depth + 1)?
+                    }
+                    ("Error", [v]) => {
+                        w.write_all(&[1u8])?;
+                        write(w, v, err, env, depth + 1)?
+                    }
+                    _ => return Err(mismatch()),
+                }
+            }
+            (uuid, args, value) => match (uuid, iterable_element(args), value) {
+                (HASHTBL_UUID, Some(el), Value::Map(kvs))
+                | (MAP_UUID, Some(el), Value::Map(kvs)) => {
+                    Nat0(kvs.len() as u64).binprot_write(w)?;
+                    for (k, v) in kvs.iter() {
+                        write(w, &Value::Tuple(vec![k.clone(), v.clone()]), el, env, depth + 1)?
+                    }
+                }
+                (_, Some(el), Value::List(vs)) => write_list(w, vs, el, env, depth + 1)?,
+                _ => return Err(mismatch()),
+            },
+        },
+        (Shape::Tuple(shapes), Value::Tuple(vs)) if shapes.len() == vs.len() => {
+            for (s, v) in shapes.iter().zip(vs.iter()) {
+                write(w, v, s, env, depth + 1)?
+            }
+        }
+        (Shape::Record(fields), Value::Record(vs)) if fields.len() == vs.len() => {
+            for ((name, s), (v_name, v)) in fields.iter().zip(vs.iter()) {
+                if name != v_name {
+                    return Err(mismatch());
+                }
+                write(w, v, s, env, depth + 1)?
+            }
+        }
+        (Shape::Variant(cases), Value::Variant(name, vs)) => {
+            let index = cases.iter().position(|(n, _)| n == name).ok_or_else(mismatch)?;
+            let shapes = &cases[index].1;
+            if shapes.len() != vs.len() {
+                return Err(mismatch());
+            }
+            w.write_all(&[index as u8])?;
+            for (s, v) in shapes.iter().zip(vs.iter()) {
+                write(w, v, s, env, depth + 1)?
+            }
+        }
+        (Shape::PolyVariant(cases), Value::PolyVariant(name, v)) => {
+            let arg = cases.get(name.as_str()).ok_or_else(mismatch)?;
+            w.write_all(&variant_int(name).to_le_bytes())?;
+            match (arg, v) {
+                (None, None) => {}
+                (Some(s), Some(v)) => write(w, v, s, env, depth + 1)?,
+                _ => return Err(mismatch()),
+            }
+        }
+        _ => return Err(mismatch()),
+    }
+    Ok(())
+}
+
+impl Value {
+    /// Decodes a value using only its shape, e.g. as obtained from the peer
+    /// that sent the message.
+    pub fn binprot_read_with_shape<R: Read + ?Sized>(
+        r: &mut R,
+        shape: &Shape,
+    ) -> Result<Value, Error> {
+        read(r, shape, &Env::default(), 0)
+    }
+
+    /// Encodes a value so that it can be decoded by a type with the given shape.
+    pub fn binprot_write_with_shape<W: Write>(
+        &self,
+        w: &mut W,
+        shape: &Shape,
+    ) -> Result<(), Error> {
+        write(w, self, shape, &Env::default(), 0)
+    }
+}
+
+/// Converts a typed value to a [Value] by going through its serialized form.
+pub fn to_value<T: BinProtWrite + BinProtShape>(t: &T) -> Result<Value, Error> {
+    let mut data = vec![];
+    t.binprot_write(&mut data)?;
+    Value::binprot_read_with_shape(&mut data.as_slice(), &T::binprot_shape())
+}
+
+/// Converts a [Value] to a typed value, this fails if the value does not match
+/// the shape of the type.
+pub fn from_value<T: BinProtRead + BinProtShape>(value: &Value) -> Result<T, Error> {
+    let mut data = vec![];
+    value.binprot_write_with_shape(&mut data, &T::binprot_shape())?;
+    T::binprot_read(&mut data.as_slice())
+}
@@ -1,7 +1,8 @@
+#![cfg(feature = "async")]
+#![allow(clippy::approx_constant)]
 use binprot::macros::{BinProtRead, BinProtWrite};
 use std::fmt::Debug;
 
-#[cfg(feature = "async")]
 use binprot::{BinProtRead, BinProtWrite};
 
 #[derive(BinProtRead, BinProtWrite, Clone, Debug, PartialEq)]
@@ -18,7 +19,6 @@ struct Breakfasts {
     value2: (f64, f64),
 }
 
-#[cfg(feature = "async")]
 async fn roundtrip<
     T: 'static + Clone + BinProtRead + BinProtWrite + PartialEq + Debug + Send + Sync,
 >(
@@ -41,7 +41,15 @@ async fn roundtrip<
     Ok(())
 }
 
-#[cfg(feature = "async")]
+#[tokio::test]
+async fn read_with_size_untrusted_length() {
+    // The announced size, here 2^48 bytes, is not trusted for allocating.
+    let data = [0u8, 0, 0, 0, 0, 0, 1, 0, 42];
+    let mut buffer = binprot::async_read_write::AsyncBuffer::new(1);
+    let err = buffer.read_with_size::<Pancakes, _>(&mut data.as_slice()).await.unwrap_err();
+    assert!(matches!(err, binprot::Error::PayloadTooShort { len: 0x1000000000000 }));
+}
+
 #[tokio::test]
 async fn roundtrip_test() -> Result<(), binprot::Error> {
     roundtrip(&vec![Pancakes(42); 100]).await?;
@@ -55,3 +63,141 @@ async fn roundtrip_test() -> Result<(), binprot::Error> {
     roundtrip(&vec![breakfasts; 100]).await?;
     Ok(())
 }
+
+mod derive_async {
+    use binprot::macros::{
+        BinProtRead, BinProtReadAsync, BinProtSize, BinProtWrite, BinProtWriteAsync,
+    };
+    use binprot::{BinProtRead, BinProtReadAsync, BinProtWrite, BinProtWriteAsync};
+    use std::collections::BTreeMap;
+    use std::fmt::Debug;
+
+    #[derive(BinProtRead, BinProtWrite, BinProtReadAsync, BinProtWriteAsync, Debug, PartialEq)]
+    #[polymorphic_variant]
+    enum Drink {
+        Coffee,
+        Juice(i64),
+    }
+
+    #[derive(BinProtRead, BinProtWrite, BinProtReadAsync, BinProtWriteAsync, Debug, PartialEq)]
+    #[polymorphic_variant]
+    enum Brunch {
+        #[binprot(flatten)]
+        Drink(Drink),
+        Eggs(i64),
+    }
+
+    #[derive(
+        BinProtRead,
+        BinProtWrite,
+        BinProtSize,
+        BinProtReadAsync,
+        BinProtWriteAsync,
+        Debug,
+        PartialEq,
+    )]
+    enum Order<T> {
+        Cancel,
+        New { id: i64, item: T, notes: Option<String> },
+        Amend(i64, Vec<T>),
+    }
+
+    #[derive(BinProtRead, BinProtWrite, BinProtReadAsync, BinProtWriteAsync, Debug, PartialEq)]
+    struct Heartbeat;
+
+    #[derive(BinProtRead, BinProtWrite, BinProtReadAsync, BinProtWriteAsync, Debug, PartialEq)]
+    struct Table {
+        orders: BTreeMap<String, Order<(f64, bool)>>,
+        brunch: Vec<Brunch>,
+        framed: binprot::WithLen<Order<i64>>,
+        #[binprot(skip)]
+        cache: Option<String>,
+        heartbeat: Heartbeat,
+    }
+
+    // The value is written and read asynchronously, the encoding has to be the
+    // same as with the synchronous traits.
+    async fn roundtrip<T>(t: T) -> Result<(), binprot::Error>
+    where
+        T: BinProtRead + BinProtWrite + BinProtReadAsync + BinProtWriteAsync + PartialEq + Debug,
+    {
+        let mut expected = vec![];
+        t.binprot_write(&mut expected)?;
+        let (mut client, mut server) = tokio::io::duplex(4);
+        let (written, read) = tokio::join!(
+            async {
+                t.binprot_write_async(&mut client).await?;
+                drop(client);
+                Ok::<_, binprot::Error>(())
+            },
+            async {
+                let v = T::binprot_read_async(&mut server).await?;
+                let mut rest = vec![];
+                tokio::io::AsyncReadExt::read_to_end(&mut server, &mut rest).await?;
+                Ok::<_, binprot::Error>((v, rest))
+            }
+        );
+        written?;
+        let (v, rest) = read?;
+        assert_eq!(v, t);
+        assert!(rest.is_empty());
+        assert_eq!(T::binprot_read(&mut expected.as_slice())?, t);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn derived_roundtrip() -> Result<(), binprot::Error> {
+        roundtrip(Heartbeat).await?;
+        roundtrip(Brunch::Drink(Drink::Juice(3))).await?;
+        roundtrip(Brunch::Eggs(-2)).await?;
+        roundtrip(Order::<i64>::Cancel).await?;
+        roundtrip(Order::Amend(42, vec!["a".to_string(), "b".to_string()])).await?;
+        let mut orders = BTreeMap::new();
+        let order = Order::New { id: 1, item: (2.5, true), notes: Some("x".repeat(300)) };
+        orders.insert("first".to_string(), order);
+        orders.insert("second".to_string(), Order::Amend(1234567, vec![]));
+        let table = Table {
+            orders,
+            brunch: vec![Brunch::Drink(Drink::Coffee), Brunch::Eggs(12)],
+            framed: binprot::WithLen(Order::Amend(-1, vec![1, 2, 3])),
+            cache: None,
+            heartbeat: Heartbeat,
+        };
+        roundtrip(table).await
+    }
+
+    #[tokio::test]
+    async fn derived_errors() {
+        let data = [0u8, 0, 0, 0];
+        let err = Brunch::binprot_read_async(&mut data.as_slice()).await.unwrap_err();
+        assert!(matches!(err, binprot::Error::UnexpectedPolymorphicVariantIndex { index: 0, .. }));
+        let err = Order::<i64>::binprot_read_async(&mut [3u8].as_slice()).await.unwrap_err();
+        assert!(matches!(err, binprot::Error::UnexpectedVariantIndex { index: 3, .. }));
+        // The payload of a WithLen is not fully consumed.
+        let data = [2u8, 0, 0, 1];
+        let err = binprot::WithLen::<Order<i64>>::binprot_read_async(&mut data.as_slice())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, binprot::Error::PayloadNotFullyConsumed { len: 2, remaining: 1 }));
+        // The payload is skipped as well when it cannot be decoded.
+        let mut data = vec![];
+        binprot::WithLen(Order::Amend(1, vec![2])).binprot_write(&mut data).unwrap();
+        binprot::WithLen(Order::<i64>::Cancel).binprot_write(&mut data).unwrap();
+        data[1] = 4;
+        let mut slice = data.as_slice();
+        let err = binprot::WithLen::<Order<i64>>::binprot_read_async(&mut slice).await.unwrap_err();
+        assert!(matches!(err, binprot::Error::UnexpectedVariantIndex { index: 4, .. }));
+        let v = binprot::WithLen::<Order<i64>>::binprot_read_async(&mut slice).await.unwrap();
+        assert_eq!(v.0, Order::Cancel);
+        assert!(slice.is_empty());
+        // The announced length of a string is not trusted for allocating.
+        let data = [0xfcu8, 0, 0, 0, 0, 0, 1, 0, 0, b'a'];
+        let err = String::binprot_read_async(&mut data.as_slice()).await.unwrap_err();
+        assert!(
+            matches!(err, binprot::Error::IoError(err) if err.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+        let data = [3u8, 2, b'a'];
+        let err = binprot::WithLen::<String>::binprot_read_async(&mut data.as_slice()).await;
+        assert!(matches!(err.unwrap_err(), binprot::Error::PayloadTooShort { len: 3 }));
+    }
+}
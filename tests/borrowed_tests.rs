@@ -0,0 +1,138 @@
+use binprot::macros::{BinProtRead, BinProtReadBorrowed, BinProtWrite};
+use binprot::{BinProtRead, BinProtReadBorrowed, BinProtWrite};
+
+#[derive(BinProtRead, BinProtWrite, Debug, PartialEq)]
+struct Quote {
+    symbol: String,
+    venue: binprot::Bytes,
+    prices: Vec<(i64, f64)>,
+    flags: Option<String>,
+}
+
+#[derive(BinProtReadBorrowed, Debug, PartialEq)]
+struct BorrowedQuote<'a> {
+    symbol: &'a str,
+    venue: binprot::BorrowedBytes<'a>,
+    prices: Vec<(i64, f64)>,
+    flags: Option<&'a str>,
+}
+
+#[derive(BinProtReadBorrowed, Debug, PartialEq)]
+enum BorrowedMessage<'a, T> {
+    Heartbeat,
+    Quote(BorrowedQuote<'a>),
+    Other { tag: &'a str, data: binprot::WithLen<T> },
+}
+
+#[derive(BinProtWrite, Debug, PartialEq)]
+enum Message<T> {
+    Heartbeat,
+    Quote(Quote),
+    Other { tag: String, data: binprot::WithLen<T> },
+}
+
+fn quote() -> Quote {
+    Quote {
+        symbol: "AAPL".to_string(),
+        venue: "XNAS".into(),
+        prices: vec![(100, 123.45), (200, 123.5)],
+        flags: Some("halted".to_string()),
+    }
+}
+
+#[test]
+fn borrowed_struct() {
+    let mut data: Vec<u8> = Vec::new();
+    quote().binprot_write(&mut data).unwrap();
+    let mut slice = data.as_slice();
+    let borrowed = BorrowedQuote::binprot_read_borrowed(&mut slice).unwrap();
+    assert!(slice.is_empty());
+    assert_eq!(
+        borrowed,
+        BorrowedQuote {
+            symbol: "AAPL",
+            venue: binprot::BorrowedBytes(b"XNAS"),
+            prices: vec![(100, 123.45), (200, 123.5)],
+            flags: Some("halted"),
+        }
+    );
+    // The strings point into the original buffer.
+    let range = data.as_ptr_range();
+    assert!(range.contains(&borrowed.symbol.as_ptr()));
+    assert!(range.contains(&borrowed.venue.0.as_ptr()));
+    assert_eq!(Quote::binprot_read(&mut data.as_slice()).unwrap(), quote());
+}
+
+#[test]
+fn borrowed_enum() {
+    let mut data: Vec<u8> = Vec::new();
+    Message::<i64>::Heartbeat.binprot_write(&mut data).unwrap();
+    Message::<i64>::Quote(quote()).binprot_write(&mut data).unwrap();
+    let other = Message::Other { tag: "tag".to_string(), data: binprot::WithLen(42i64) };
+    other.binprot_write(&mut data).unwrap();
+    let mut slice = data.as_slice();
+    let msg = BorrowedMessage::<i64>::binprot_read_borrowed(&mut slice).unwrap();
+    assert_eq!(msg, BorrowedMessage::Heartbeat);
+    let msg = BorrowedMessage::<i64>::binprot_read_borrowed(&mut slice).unwrap();
+    assert!(matches!(msg, BorrowedMessage::Quote(BorrowedQuote { symbol: "AAPL", .. })));
+    let msg = BorrowedMessage::<i64>::binprot_read_borrowed(&mut slice).unwrap();
+    assert_eq!(msg, BorrowedMessage::Other { tag: "tag", data: binprot::WithLen(42) });
+    assert!(slice.is_empty());
+}
+
+#[test]
+fn borrowed_errors() {
+    // Truncated input.
+    assert!(<&str>::binprot_read_borrowed(&mut &[3, 97, 98][..]).is_err());
+    // Invalid utf8 is only accepted as bytes.
+    let data = [2, 0, 255];
+    assert!(<&str>::binprot_read_borrowed(&mut &data[..]).is_err());
+    let bytes = binprot::BorrowedBytes::binprot_read_borrowed(&mut &data[..]).unwrap();
+    assert_eq!(bytes.0, [0, 255]);
+    // Invalid payloads are skipped.
+    let data = [2, 2, 0, 42];
+    let mut slice = &data[..];
+    assert!(binprot::WithLen::<Option<i64>>::binprot_read_borrowed(&mut slice).is_err());
+    assert_eq!(i64::binprot_read_borrowed(&mut slice).unwrap(), 42);
+}
+
+#[derive(BinProtWrite, BinProtReadBorrowed, binprot::macros::BinProtShape, Debug, PartialEq)]
+struct Frame<'a> {
+    header: binprot::BorrowedBytes<'a>,
+    body: &'a str,
+    #[binprot(with = "binprot::bytes")]
+    trailer: &'a [u8],
+}
+
+#[allow(dead_code)]
+#[derive(binprot::macros::BinProtShape)]
+struct OwnedFrame {
+    header: binprot::Bytes,
+    body: String,
+    trailer: binprot::Bytes,
+}
+
+#[test]
+fn borrowed_bytes_roundtrip() {
+    use binprot::{BinProtShape, BinProtSize};
+    let frame = Frame {
+        header: binprot::BorrowedBytes(&[0, 0x7f, 0x80, 0xff]),
+        body: "é",
+        trailer: &[0x81],
+    };
+    let mut data: Vec<u8> = Vec::new();
+    frame.binprot_write(&mut data).unwrap();
+    assert_eq!(data, [4, 0, 0x7f, 0x80, 0xff, 2, 0xc3, 0xa9, 1, 0x81]);
+    assert_eq!(Frame::binprot_read_borrowed(&mut data.as_slice()).unwrap(), frame);
+    // Same encoding and shape as the owned version.
+    let owned = binprot::Bytes::binprot_read(&mut data.as_slice()).unwrap();
+    assert_eq!(owned.0, frame.header.0);
+    assert_eq!(frame.header.binprot_size(), owned.binprot_size());
+    assert_eq!(binprot::BorrowedBytes::binprot_shape(), binprot::Bytes::binprot_shape());
+    // Structs with borrowed fields have the shape of their owned counterpart.
+    assert_eq!(Frame::binprot_shape(), OwnedFrame::binprot_shape());
+    // Byte slices are arrays of ints unless using the binprot::bytes module.
+    let mut data: Vec<u8> = Vec::new();
+    [0u8, 0x80].as_slice().binprot_write(&mut data).unwrap();
+    assert_eq!(data, [2, 0, 0xfe, 0x80, 0x00]);
+}
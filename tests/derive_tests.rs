@@ -1,10 +1,10 @@
 #![allow(clippy::approx_constant)]
-use binprot::macros::{BinProtRead, BinProtWrite};
+use binprot::macros::{BinProtRead, BinProtSize, BinProtWrite};
 use binprot::{BinProtRead, BinProtSize, BinProtWrite};
 
 fn test_roundtrip<T>(t: T, sz: usize, vs: Option<&[u8]>)
 where
-    T: BinProtRead + BinProtWrite + PartialEq + std::fmt::Debug,
+    T: BinProtRead + BinProtWrite + BinProtSize + PartialEq + std::fmt::Debug,
 {
     assert_eq!(t.binprot_size(), sz);
     let mut data: Vec<u8> = Vec::new();
@@ -17,7 +17,7 @@ where
     assert_eq!(t, flipped)
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, PartialEq)]
 struct Pancakes(i64);
 
 #[test]
@@ -28,7 +28,7 @@ fn breakfast1() {
     test_roundtrip(Pancakes(12345678910111213), 9, Some(&[252, 237, 189, 242, 93, 84, 220, 43, 0]));
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, PartialEq)]
 struct MorePancakes(i64, f64, i64);
 
 #[test]
@@ -40,7 +40,7 @@ fn breakfast2() {
     );
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, PartialEq)]
 struct Breakfasts {
     pancakes: Pancakes,
     more_pancakes: MorePancakes,
@@ -64,7 +64,7 @@ fn breakfast3() {
     test_roundtrip(breakfasts, 37, Some(&expected))
 }
 
-#[derive(BinProtWrite, BinProtRead, Debug, PartialEq)]
+#[derive(BinProtWrite, BinProtRead, BinProtSize, Debug, PartialEq)]
 enum BreakfastMenu<T> {
     Any(T),
     Eggs(i64),
@@ -87,7 +87,7 @@ fn breakfast4() {
     test_roundtrip(binprot::WithLen(BreakfastMenu::<i64>::Eggs(42)), 3, Some(&[2, 1, 42]));
 }
 
-#[derive(BinProtWrite, BinProtRead, Debug, PartialEq)]
+#[derive(BinProtWrite, BinProtRead, BinProtSize, Debug, PartialEq)]
 struct BreakfastItem {
     name: String,
     quantity: f64,
@@ -119,7 +119,7 @@ fn breakfast5() {
     );
 }
 
-#[derive(BinProtWrite, BinProtRead, Debug, PartialEq)]
+#[derive(BinProtWrite, BinProtRead, BinProtSize, Debug, PartialEq)]
 #[polymorphic_variant]
 enum BreakfastPoly<T> {
     Any(T),
@@ -127,10 +127,27 @@ enum BreakfastPoly<T> {
     Pancakes(Pancakes),
     MorePancakes(MorePancakes),
     LotsOfPancakes(Pancakes, MorePancakes),
-    Everything { eggs: i64, pancakes: i64 },
+    Everything(i64, i64),
     Nothing,
 }
 
+// The derive macro computes the polymorphic variant tags with its own copy of
+// the hash function.
+#[test]
+fn poly_variant_tags() {
+    let tag = |v: BreakfastPoly<i64>| {
+        let mut data: Vec<u8> = Vec::new();
+        v.binprot_write(&mut data).unwrap();
+        i32::from_le_bytes([data[0], data[1], data[2], data[3]])
+    };
+    let variant_int = |name: &str| (binprot::hash_variant(name) << 1) | 1;
+    assert_eq!(tag(BreakfastPoly::Any(1)), variant_int("Any"));
+    assert_eq!(tag(BreakfastPoly::Eggs(1)), variant_int("Eggs"));
+    assert_eq!(tag(BreakfastPoly::Pancakes(Pancakes(1))), variant_int("Pancakes"));
+    assert_eq!(tag(BreakfastPoly::Everything(1, 2)), variant_int("Everything"));
+    assert_eq!(tag(BreakfastPoly::Nothing), variant_int("Nothing"));
+}
+
 #[test]
 fn breakfast6() {
     let breakfast: BreakfastPoly<BreakfastPoly<i64>> =
@@ -161,7 +178,7 @@ fn breakfast7() {
     test_roundtrip(price_and_quantities, 64, None);
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, PartialEq)]
 enum BreakfastRec {
     Empty,
     Cons(MorePancakes, Box<BreakfastRec>),
@@ -200,7 +217,7 @@ fn breakfast_rec() {
     test_roundtrip(breakfast_rec, 16357, None);
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, PartialEq)]
 struct BreakfastStr {
     str: String,
     bytes: binprot::Bytes,
@@ -228,7 +245,7 @@ fn breakfast_str() {
     assert!(err.is_err())
 }
 
-#[derive(BinProtRead, BinProtWrite, Debug, PartialEq)]
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, PartialEq)]
 enum BinProtResult<T, E> {
     Ok(T),
     Err(E),
@@ -244,3 +261,481 @@ fn result_same_as_derived() {
         BinProtResult::binprot_read(&mut slice).unwrap();
     assert_eq!(derived_result, BinProtResult::Err("test".to_string()));
 }
+
+#[test]
+fn read_with_size() {
+    let mut data: Vec<u8> = Vec::new();
+    binprot::binprot_write_with_size(&Pancakes(1234), &mut data).unwrap();
+    binprot::binprot_write_with_size(&Pancakes(-1), &mut data).unwrap();
+    assert_eq!(data, [3, 0, 0, 0, 0, 0, 0, 0, 254, 210, 4, 2, 0, 0, 0, 0, 0, 0, 0, 255, 255]);
+    let mut slice = data.as_slice();
+    let p: Pancakes = binprot::binprot_read_with_size(&mut slice).unwrap();
+    assert_eq!(p, Pancakes(1234));
+    let p: Pancakes = binprot::binprot_read_with_size(&mut slice).unwrap();
+    assert_eq!(p, Pancakes(-1));
+    assert!(slice.is_empty());
+
+    // The announced size is larger than what the decoder consumes, the remaining
+    // bytes are skipped so that the next frame can still be read.
+    let data = [2, 0, 0, 0, 0, 0, 0, 0, 42, 0, 1, 0, 0, 0, 0, 0, 0, 0, 12];
+    let mut slice = &data[..];
+    let err = binprot::binprot_read_with_size::<_, Pancakes>(&mut slice).unwrap_err();
+    assert!(matches!(err, binprot::Error::PayloadNotFullyConsumed { len: 2, remaining: 1 }));
+    let p: Pancakes = binprot::binprot_read_with_size(&mut slice).unwrap();
+    assert_eq!(p, Pancakes(12));
+
+    // Same when decoding fails, here because of an invalid variant tag.
+    let data = [2, 0, 0, 0, 0, 0, 0, 0, 3, 42, 1, 0, 0, 0, 0, 0, 0, 0, 12];
+    let mut slice = &data[..];
+    let err = binprot::binprot_read_with_size::<_, Result<i64, i64>>(&mut slice).unwrap_err();
+    assert!(matches!(err, binprot::Error::UnexpectedVariantIndex { index: 3, .. }));
+    let p: Pancakes = binprot::binprot_read_with_size(&mut slice).unwrap();
+    assert_eq!(p, Pancakes(12));
+
+    // The announced size is smaller than what the decoder needs.
+    let data = [2, 0, 0, 0, 0, 0, 0, 0, 254, 210, 4];
+    let err = binprot::binprot_read_with_size::<_, Pancakes>(&mut &data[..]).unwrap_err();
+    assert!(matches!(err, binprot::Error::PayloadTooShort { len: 2 }));
+
+    // The input ends before the announced size.
+    let data = [8, 0, 0, 0, 0, 0, 0, 0, 254, 210, 4];
+    let err = binprot::binprot_read_with_size::<_, Pancakes>(&mut &data[..]).unwrap_err();
+    assert!(matches!(err, binprot::Error::PayloadTooShort { len: 8 }));
+}
+
+#[test]
+fn untrusted_lengths() {
+    // The announced lengths are not trusted for allocating, here 2^48 bytes.
+    let data = [0xfcu8, 0, 0, 0, 0, 0, 1, 0, 0, b'a'];
+    let is_eof = |err: binprot::Error| matches!(err, binprot::Error::IoError(err) if err.kind() == std::io::ErrorKind::UnexpectedEof);
+    assert!(is_eof(String::binprot_read(&mut &data[..]).unwrap_err()));
+    assert!(is_eof(binprot::Bytes::binprot_read(&mut &data[..]).unwrap_err()));
+    assert!(is_eof(binprot::BufferWithLen::binprot_read(&mut &data[..]).unwrap_err()));
+    let data = [3u8, b'a', b'b', b'c', b'd'];
+    assert_eq!(String::binprot_read(&mut &data[..]).unwrap(), "abc");
+}
+
+#[test]
+fn with_len() {
+    type T = binprot::WithLen<MorePancakes>;
+    let v = binprot::WithLen(MorePancakes(12, 3.141592, 1234567890123));
+    let mut data: Vec<u8> = Vec::new();
+    v.binprot_write(&mut data).unwrap();
+    Pancakes(42).binprot_write(&mut data).unwrap();
+
+    let mut slice = data.as_slice();
+    assert_eq!(binprot::skip_with_len(&mut slice).unwrap(), 18);
+    assert_eq!(Pancakes::binprot_read(&mut slice).unwrap(), Pancakes(42));
+
+    let mut slice = data.as_slice();
+    assert_eq!(T::binprot_read(&mut slice).unwrap(), v);
+    assert_eq!(Pancakes::binprot_read(&mut slice).unwrap(), Pancakes(42));
+
+    // Decoding the payload with the wrong type does not read past the payload.
+    let mut slice = data.as_slice();
+    let err = binprot::WithLen::<Pancakes>::binprot_read(&mut slice).unwrap_err();
+    assert!(matches!(err, binprot::Error::PayloadNotFullyConsumed { len: 18, remaining: 17 }));
+    assert_eq!(Pancakes::binprot_read(&mut slice).unwrap(), Pancakes(42));
+
+    // The payload is skipped as well when it cannot be decoded.
+    let mut data: Vec<u8> = Vec::new();
+    binprot::WithLen(MorePancakes(1, 2.0, 3)).binprot_write(&mut data).unwrap();
+    binprot::WithLen(Pancakes(42)).binprot_write(&mut data).unwrap();
+    data[1] = 2;
+    let mut slice = data.as_slice();
+    let err = binprot::WithLen::<Result<i64, f64>>::binprot_read(&mut slice).unwrap_err();
+    assert!(matches!(err, binprot::Error::UnexpectedVariantIndex { index: 2, .. }));
+    assert_eq!(binprot::WithLen::<Pancakes>::binprot_read(&mut slice).unwrap().0, Pancakes(42));
+    assert!(slice.is_empty());
+
+    let err = binprot::WithLen::<(Pancakes, Pancakes)>::binprot_read(&mut &[1, 42, 0][..]);
+    assert!(matches!(err.unwrap_err(), binprot::Error::PayloadTooShort { len: 1 }));
+    let err = binprot::skip_with_len(&mut &[3, 42, 0][..]);
+    assert!(matches!(err.unwrap_err(), binprot::Error::PayloadTooShort { len: 3 }));
+}
+
+// Only the type parameters wrapped in a WithLen, or passed to a generic type that
+// is not a std container, have to implement BinProtSize.
+#[derive(BinProtWrite)]
+struct WriteOnly(i64);
+
+#[derive(BinProtWrite)]
+struct GenericWrite<T, U> {
+    t: T,
+    u: Option<binprot::WithLen<U>>,
+}
+
+#[test]
+fn generic_write_without_size() {
+    let v = GenericWrite { t: WriteOnly(42), u: Some(binprot::WithLen(Pancakes(1))) };
+    let mut data: Vec<u8> = Vec::new();
+    v.binprot_write(&mut data).unwrap();
+    assert_eq!(data, [42, 1, 1, 1]);
+    assert_eq!(binprot::binprot_size_by_writing(&v), 4);
+}
+
+fn check_size<T: BinProtWrite + BinProtSize>(t: T) {
+    let mut data: Vec<u8> = Vec::new();
+    t.binprot_write(&mut data).unwrap();
+    assert_eq!(t.binprot_size(), data.len())
+}
+
+#[test]
+fn sizes() {
+    check_size(vec![1.0f32; 200]);
+    check_size(binprot::Vec32(vec![1.0f32; 200]));
+    check_size("x".repeat(70000));
+    check_size(binprot::Bytes(vec![0; 300]));
+    check_size(binprot::BufferWithLen(vec![0; 128]));
+    check_size(binprot::WithLen(vec![(1u8, -1i32, 65536u32); 1000]));
+    check_size(&[Some(-129i16), None, Some(i16::MIN)][..]);
+    check_size(Ok::<_, String>(binprot::Nat0(1 << 40)));
+    check_size(Box::new(((), true, 3.14f64, "foo")));
+    let map: std::collections::BTreeMap<i64, String> =
+        (0..100).map(|i| (i * 1000, i.to_string())).collect();
+    check_size(map.clone());
+    check_size(map.into_iter().collect::<std::collections::HashMap<_, _>>());
+    check_size(BreakfastPoly::LotsOfPancakes::<i64>(
+        Pancakes(-1),
+        MorePancakes(1 << 20, 0., 12345),
+    ));
+}
+
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, PartialEq)]
+struct Counters {
+    count: u64,
+    index: usize,
+    offset: isize,
+}
+
+#[test]
+fn unsigned_ints() {
+    test_roundtrip(Counters { count: 0, index: 127, offset: -1 }, 4, Some(&[0, 127, 255, 255]));
+    test_roundtrip(
+        Counters { count: (1 << 62) - 1, index: 1 << 31, offset: -(1 << 62) },
+        27,
+        Some(&[
+            252, 255, 255, 255, 255, 255, 255, 255, 63, 252, 0, 0, 0, 128, 0, 0, 0, 0, 252, 0, 0,
+            0, 0, 0, 0, 0, 192,
+        ]),
+    );
+    let mut data: Vec<u8> = Vec::new();
+    for &v in [1u64 << 62, u64::MAX].iter() {
+        let err = v.binprot_write(&mut data).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(v.binprot_size(), 9);
+    }
+    assert!((-(1isize << 62) - 1).binprot_write(&mut data).is_err());
+    assert!(data.is_empty());
+    // Negative values cannot be read as unsigned integers.
+    assert!(u64::binprot_read(&mut [255u8, 255].as_slice()).is_err());
+}
+
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, PartialEq)]
+struct Grade {
+    letter: binprot::Char,
+    score: binprot::Float32,
+}
+
+#[test]
+fn char_and_f32() {
+    // Float32 values are serialized as OCaml floats.
+    test_roundtrip(
+        Grade { letter: binprot::Char(b'A'), score: binprot::Float32(0.5) },
+        9,
+        Some(&[65, 0, 0, 0, 0, 0, 0, 224, 63]),
+    );
+    let mut data: Vec<u8> = Vec::new();
+    3.14f64.binprot_write(&mut data).unwrap();
+    assert_eq!(binprot::Float32::binprot_read(&mut data.as_slice()).unwrap().0, 3.14f32);
+    // Vec<f32> and Vec32 both use the float32 bigarray encoding.
+    test_roundtrip(binprot::Vec32(vec![0.5, -1.]), 9, Some(&[2, 0, 0, 0, 63, 0, 0, 128, 191]));
+    test_roundtrip(vec![0.5f32, -1.], 9, Some(&[2, 0, 0, 0, 63, 0, 0, 128, 191]));
+    assert_eq!(char::from(binprot::Char(b'z')), 'z');
+    assert!(<binprot::Char as std::convert::TryFrom<char>>::try_from('\u{1F95E}').is_err());
+}
+
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, PartialEq)]
+struct OrderBook {
+    bids: [(i64, i64); 16],
+    asks: [(i64, i64); 16],
+}
+
+#[test]
+fn arrays() {
+    // Arrays are serialized in the same way as tuples.
+    let mut data: Vec<u8> = Vec::new();
+    (1i64, -2i64, 300i64).binprot_write(&mut data).unwrap();
+    test_roundtrip([1i64, -2, 300], 6, Some(&data));
+    test_roundtrip([(); 0], 0, Some(&[]));
+    let mut book = OrderBook { bids: [(0, 0); 16], asks: [(0, 0); 16] };
+    for i in 0..16 {
+        book.bids[i] = (1000 - i as i64, 10);
+        book.asks[i] = (1001 + i as i64, 20);
+    }
+    test_roundtrip(book, 128, None);
+    assert!(<[i64; 3]>::binprot_read(&mut [1u8, 2].as_slice()).is_err());
+}
+
+#[test]
+fn collections() {
+    use std::collections::{BTreeSet, HashMap, HashSet, LinkedList, VecDeque};
+    type Hasher = std::hash::BuildHasherDefault<std::collections::hash_map::DefaultHasher>;
+    let set: BTreeSet<i64> = [3, 1, 2].iter().copied().collect();
+    test_roundtrip(set.clone(), 4, Some(&[3, 1, 2, 3]));
+    let hash_set: HashSet<i64, Hasher> = set.iter().copied().collect();
+    test_roundtrip(hash_set, 4, None);
+    let hash_map: HashMap<i64, String, Hasher> = set.iter().map(|&i| (i, i.to_string())).collect();
+    test_roundtrip(hash_map, 10, None);
+    let queue: VecDeque<i64> = set.iter().copied().collect();
+    test_roundtrip(queue, 4, Some(&[3, 1, 2, 3]));
+    let list: LinkedList<i64> = set.iter().copied().collect();
+    test_roundtrip(list, 4, Some(&[3, 1, 2, 3]));
+    let err = BTreeSet::<i64>::binprot_read(&mut [2u8, 1, 1].as_slice()).unwrap_err();
+    assert!(matches!(err, binprot::Error::SameValueAppearsTwiceInSet));
+    let err = HashSet::<i64>::binprot_read(&mut [2u8, 1, 1].as_slice()).unwrap_err();
+    assert!(matches!(err, binprot::Error::SameValueAppearsTwiceInSet));
+}
+
+fn default_symbol() -> String {
+    "UNKNOWN".to_string()
+}
+
+#[derive(
+    BinProtRead, BinProtWrite, BinProtSize, binprot::macros::BinProtShape, Debug, PartialEq,
+)]
+struct Quote {
+    bid: i64,
+    #[binprot(skip)]
+    mid: Option<f64>,
+    ask: i64,
+    #[binprot(skip, default = "default_symbol")]
+    symbol: String,
+}
+
+#[derive(
+    BinProtRead, BinProtWrite, BinProtSize, binprot::macros::BinProtShape, Debug, PartialEq,
+)]
+struct QuoteWire {
+    bid: i64,
+    ask: i64,
+}
+
+#[derive(BinProtRead, BinProtWrite, BinProtSize, Debug, PartialEq)]
+enum QuoteUpdate {
+    Bid(i64, #[binprot(skip)] u64),
+    Ask {
+        #[binprot(skip)]
+        cached: Vec<i64>,
+        ask: i64,
+    },
+}
+
+#[test]
+fn skipped_fields() {
+    use binprot::BinProtShape;
+    let quote = Quote { bid: 100, mid: Some(100.5), ask: 101, symbol: "AAPL".to_string() };
+    let mut data: Vec<u8> = Vec::new();
+    quote.binprot_write(&mut data).unwrap();
+    assert_eq!(data, [100, 101]);
+    assert_eq!(quote.binprot_size(), 2);
+    let read = Quote::binprot_read(&mut data.as_slice()).unwrap();
+    assert_eq!(read, Quote { bid: 100, mid: None, ask: 101, symbol: "UNKNOWN".to_string() });
+    assert_eq!(Quote::binprot_shape(), QuoteWire::binprot_shape());
+
+    test_roundtrip(QuoteUpdate::Bid(12, 0), 2, Some(&[0, 12]));
+    test_roundtrip(QuoteUpdate::Ask { cached: vec![], ask: 13 }, 2, Some(&[1, 13]));
+    let update = QuoteUpdate::Ask { cached: vec![1, 2, 3], ask: 13 };
+    assert_eq!(update.binprot_size(), 2);
+}
+
+// Encodes durations as a float number of seconds, similar to Time_ns.Span.
+mod duration_as_float {
+    use binprot::{BinProtRead, BinProtShape, BinProtSize, BinProtWrite};
+    use std::time::Duration;
+
+    pub fn binprot_write<W: std::io::Write>(d: &Duration, w: &mut W) -> std::io::Result<()> {
+        d.as_secs_f64().binprot_write(w)
+    }
+
+    pub fn binprot_size(d: &Duration) -> usize {
+        d.as_secs_f64().binprot_size()
+    }
+
+    pub fn binprot_read<R: std::io::Read + ?Sized>(r: &mut R) -> Result<Duration, binprot::Error> {
+        Ok(Duration::from_secs_f64(f64::binprot_read(r)?))
+    }
+
+    pub fn binprot_shape() -> binprot::Shape {
+        f64::binprot_shape()
+    }
+}
+
+#[derive(
+    BinProtRead, BinProtWrite, BinProtSize, binprot::macros::BinProtShape, Debug, PartialEq,
+)]
+struct Timeout {
+    id: i64,
+    #[binprot(with = "duration_as_float")]
+    delay: std::time::Duration,
+}
+
+#[allow(dead_code)]
+#[derive(binprot::macros::BinProtShape)]
+struct TimeoutWire {
+    id: i64,
+    delay: f64,
+}
+
+#[derive(
+    BinProtRead, BinProtWrite, BinProtSize, binprot::macros::BinProtShape, Debug, PartialEq,
+)]
+enum Schedule {
+    Now,
+    #[binprot(with = "duration_as_float")]
+    After(std::time::Duration),
+    Every {
+        #[binprot(with = "duration_as_float")]
+        period: std::time::Duration,
+        count: i64,
+    },
+}
+
+#[test]
+fn with_modules() {
+    use binprot::BinProtShape;
+    let delay = std::time::Duration::from_millis(1500);
+    let mut expected: Vec<u8> = vec![3];
+    1.5f64.binprot_write(&mut expected).unwrap();
+    test_roundtrip(Timeout { id: 3, delay }, 9, Some(&expected));
+    assert_eq!(Timeout::binprot_shape(), TimeoutWire::binprot_shape());
+    test_roundtrip(Schedule::Now, 1, Some(&[0]));
+    expected[0] = 1;
+    test_roundtrip(Schedule::After(delay), 9, Some(&expected));
+    test_roundtrip(Schedule::Every { period: delay, count: 2 }, 10, None);
+}
+
+#[derive(
+    BinProtRead,
+    BinProtWrite,
+    BinProtSize,
+    binprot::macros::BinProtReadBorrowed,
+    binprot::macros::BinProtShape,
+    Debug,
+    PartialEq,
+)]
+struct Heartbeat;
+
+#[test]
+fn unit_structs() {
+    use binprot::{BinProtReadBorrowed, BinProtShape};
+    // Encoded in the same way as OCaml's unit.
+    test_roundtrip(Heartbeat, 1, Some(&[0]));
+    let err = Heartbeat::binprot_read(&mut [1u8].as_slice()).unwrap_err();
+    assert!(matches!(err, binprot::Error::UnexpectedValueForUnit(1)));
+    assert_eq!(Heartbeat::binprot_read_borrowed(&mut [0u8].as_slice()).unwrap(), Heartbeat);
+    assert_eq!(Heartbeat::binprot_shape(), <()>::binprot_shape());
+}
+
+#[derive(BinProtWrite, BinProtRead, BinProtSize, Debug, PartialEq)]
+#[polymorphic_variant]
+#[binprot(rename_all = "snake_case")]
+enum RenamedBreakfast {
+    #[binprot(name = "Eggs")]
+    BoiledEggs(i64),
+    #[binprot(name = "Nothing")]
+    Fasting,
+    MorePancakes(MorePancakes),
+}
+
+#[derive(BinProtWrite, BinProtRead, BinProtSize, Debug, PartialEq)]
+#[polymorphic_variant]
+#[allow(non_camel_case_types)]
+enum SnakeBreakfast {
+    more_pancakes(MorePancakes),
+}
+
+#[test]
+fn renamed_variants() {
+    // The custom names are used to compute the polymorphic variant hashes.
+    test_roundtrip(RenamedBreakfast::BoiledEggs(42), 5, Some(&[93, 118, 212, 91, 42]));
+    let mut expected = vec![];
+    BreakfastPoly::<i64>::Nothing.binprot_write(&mut expected).unwrap();
+    test_roundtrip(RenamedBreakfast::Fasting, 4, Some(&expected));
+    let pancakes = || MorePancakes(-123, 2.71828182846, 0);
+    let mut expected = vec![];
+    SnakeBreakfast::more_pancakes(pancakes()).binprot_write(&mut expected).unwrap();
+    test_roundtrip(RenamedBreakfast::MorePancakes(pancakes()), 15, Some(&expected));
+}
+
+#[derive(
+    BinProtRead,
+    BinProtWrite,
+    BinProtSize,
+    binprot::macros::BinProtReadBorrowed,
+    binprot::macros::BinProtShape,
+    Debug,
+    PartialEq,
+)]
+#[polymorphic_variant]
+enum Drink {
+    Coffee,
+    Juice(i64),
+}
+
+// Similar to [ drink | `Eggs of int64 ] in OCaml.
+#[derive(
+    BinProtRead,
+    BinProtWrite,
+    BinProtSize,
+    binprot::macros::BinProtReadBorrowed,
+    binprot::macros::BinProtShape,
+    Debug,
+    PartialEq,
+)]
+#[polymorphic_variant]
+enum Brunch {
+    #[binprot(flatten)]
+    Drink(Drink),
+    Eggs(i64),
+}
+
+#[derive(
+    BinProtRead, BinProtWrite, BinProtSize, binprot::macros::BinProtShape, Debug, PartialEq,
+)]
+#[polymorphic_variant]
+enum FlatBrunch {
+    Coffee,
+    Juice(i64),
+    Eggs(i64),
+}
+
+#[test]
+fn flattened_variants() {
+    use binprot::{BinProtReadBorrowed, BinProtShape};
+    let encode = |t: &dyn Fn(&mut Vec<u8>)| {
+        let mut data = vec![];
+        t(&mut data);
+        data
+    };
+    let pairs = [
+        (Brunch::Drink(Drink::Coffee), FlatBrunch::Coffee),
+        (Brunch::Drink(Drink::Juice(12)), FlatBrunch::Juice(12)),
+        (Brunch::Eggs(3), FlatBrunch::Eggs(3)),
+    ];
+    for (brunch, flat) in pairs {
+        // The inner tags are merged with the outer ones on the wire.
+        let expected = encode(&|w| flat.binprot_write(w).unwrap());
+        assert_eq!(brunch.binprot_size(), expected.len());
+        assert_eq!(encode(&|w| brunch.binprot_write(w).unwrap()), expected);
+        assert_eq!(Brunch::binprot_read(&mut expected.as_slice()).unwrap(), brunch);
+        assert_eq!(Brunch::binprot_read_borrowed(&mut expected.as_slice()).unwrap(), brunch);
+    }
+    let data = encode(&|w| BreakfastPoly::<i64>::Nothing.binprot_write(w).unwrap());
+    let err = Brunch::binprot_read(&mut data.as_slice()).unwrap_err();
+    assert!(matches!(
+        err,
+        binprot::Error::UnexpectedPolymorphicVariantIndex { ident: "Brunch", .. }
+    ));
+    assert_eq!(Brunch::binprot_shape(), FlatBrunch::binprot_shape());
+}
@@ -1,6 +1,15 @@
 // This test checks that binprot_derive macros do not have
 // issues with some BinProt traits not being imported
-use binprot::macros::{BinProtRead, BinProtWrite};
+use binprot::macros::{BinProtRead, BinProtShape, BinProtWrite};
 
+#[allow(dead_code)]
 #[derive(BinProtRead, BinProtWrite, Debug, PartialEq)]
 struct Pancakes(i64);
+
+// The bounds added on type parameters use the full trait paths.
+#[allow(dead_code)]
+#[derive(BinProtRead, BinProtWrite, BinProtShape, Debug, PartialEq)]
+struct Stack<T> {
+    top: T,
+    rest: Vec<T>,
+}
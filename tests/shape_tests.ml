@@ -15,6 +15,14 @@ module _ = struct
     print_digest bin_shape_string;
     print_digest bin_shape_bool;
     print_digest bin_shape_char;
+    print_digest [%bin_shape: int list];
+    print_digest [%bin_shape: Int.Set.t];
+    print_digest [%bin_shape: Int.Hash_set.t];
+    print_digest [%bin_shape: int Queue.t];
+    print_digest Bin_prot.Nat0.bin_shape_t;
+    print_digest Bin_prot.Std.bin_shape_bytes;
+    print_digest Bin_prot.Std.bin_shape_bigstring;
+    print_digest Bin_prot.Std.bin_shape_vec32;
     [%expect
       {|
     698cfa4093fe5e51523842d37b92aeac
@@ -24,7 +32,15 @@ module _ = struct
     1fd923acb2dd9c5d401ad5b08b1d40cd
     d9a8da25d5656b016fb4dbdc2e4197fb
     a25306e4c5d30d35adbb5b0462a6b1b3
-    84610d32d63dcff5c93f1033ec8cb1d5 |}]
+    84610d32d63dcff5c93f1033ec8cb1d5
+    4cd553520709511864846bda25c448d0
+    3564446b0bfa871d8c3ebf31ab342fe7
+    a6ab3112da0f561e1c0fab32a07fc914
+    8e2fb88d9c27340a0be6f26e9e23e0bb
+    c31169f9cd00deb5282bfd187df70964
+    06c5811b990697b0a0c71e285a10e7d4
+    e2d261c6c291b94bf6aa68ec2b08cb00
+    0ac181a0a1e9ca216f2b49ea245472f3 |}]
 end
 
 module _ = struct
@@ -140,3 +156,78 @@ module _ = struct
     (Exp(Application(Exp(Variant((Empty())(Cons((Exp(Base int()))(Exp(Rec_app 0())))))))()))
     2ac39052755cfe456342e727b104f34a |}]
 end
+
+module _ = struct
+  type 'a query =
+    { tag : string
+    ; data : 'a
+    }
+  [@@deriving bin_io]
+
+  type 'a tree =
+    | Leaf
+    | Node of 'a tree * 'a * 'a tree
+  [@@deriving bin_io]
+
+  type ('k, 'v) forest =
+    { key : 'k
+    ; trees : ('k * 'v) tree array
+    }
+  [@@deriving bin_io]
+
+  let%expect_test _ =
+    print_digest [%bin_shape: int query];
+    print_digest [%bin_shape: int tree];
+    print_digest [%bin_shape: string tree];
+    print_digest [%bin_shape: (int, string) forest];
+    [%expect
+      {|
+    6164456cb42b057709298f68f76326f0
+    d583ea472c5d9382fc8fb52c43a673d8
+    e6d46ee14208f46caa11e3dc826cf7ef
+    440090c35916c5f105f1173784117cf9 |}];
+    Bin_prot.Shape.eval [%bin_shape: int tree]
+    |> Bin_prot.Shape.Canonical.to_string_hum
+    |> Stdio.print_endline;
+    [%expect
+      {|
+    (Exp
+     (Application
+      (Exp
+       (Variant
+        ((Leaf ())
+         (Node
+          ((Exp (Rec_app 0 ((Exp (Var 0))))) (Exp (Var 0))
+           (Exp (Rec_app 0 ((Exp (Var 0))))))))))
+      ((Exp (Base int ()))))) |}]
+end
+
+module _ = struct
+  type expr =
+    | Const of int
+    | Block of stmt array
+
+  and stmt =
+    | Expr of expr
+    | Seq of stmt * stmt
+  [@@deriving bin_io]
+
+  type a =
+    { b : b
+    ; n : int
+    }
+
+  and b = { a : a option } [@@deriving bin_io]
+
+  let%expect_test _ =
+    print_digest bin_shape_expr;
+    print_digest bin_shape_stmt;
+    print_digest bin_shape_a;
+    print_digest bin_shape_b;
+    [%expect
+      {|
+    1d5ebe786aa762f02b13c7a427747403
+    048c5ffe79db27c4fe8f472142b3830e
+    a8b89f6a78fd48aaab8b2d4fdf3e5331
+    7f34cb94f613eda6abb3195c8ca29707 |}]
+end
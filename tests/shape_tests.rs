@@ -33,6 +33,19 @@ struct Test4 {
     v: (Test3, Test3, Test3),
 }
 
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+struct TestWireTypes {
+    a: binprot::Nat0,
+    b: binprot::Bytes,
+    c: binprot::WithLen<i16>,
+    d: binprot::BufferWithLen,
+    e: (i8, u8, u16, u32),
+    f: binprot::Vec32,
+    g: &'static str,
+    h: &'static [u8],
+}
+
 #[allow(dead_code)]
 #[derive(BinProtShape)]
 enum TestVariant {
@@ -106,20 +119,240 @@ enum TestRec4 {
     Cons((i64, Box<TestRec4>)),
 }
 
+fn shape_sexp<T: BinProtShape>() -> String {
+    T::binprot_shape().eval().to_string_mach()
+}
+
+#[test]
+fn test_shape_printing() {
+    // Same output as Sexp.to_string in tests/shape_tests.ml
+    assert_eq!(shape_sexp::<i64>(), "(Exp(Base int()))");
+    assert_eq!(
+        shape_sexp::<std::collections::LinkedList<i64>>(),
+        "(Exp(Base list((Exp(Base int())))))"
+    );
+    assert_eq!(shape_sexp::<()>(), "(Exp(Base unit()))");
+    assert_eq!(
+        shape_sexp::<TestVariant2>(),
+        "(Exp(Variant((Foo())(Bar((Exp(Base int()))))(Bar2((Exp(Base int()))(Exp(Base float()))))\
+         (Baz((Exp(Record((x(Exp(Base int())))(y(Exp(Base float())))))))))))"
+    );
+    assert_eq!(shape_sexp::<TestPolyVariant>(), "(Exp(Poly_variant((sorted((A()))))))");
+    assert_eq!(
+        shape_sexp::<TestPolyVariant3>(),
+        "(Exp(Poly_variant((sorted((A((Exp(Base int()))))(B())(C((Exp(Tuple((Exp(Base int()))\
+         (Exp(Base float())))))))(D((Exp(Base string())))))))))"
+    );
+    assert_eq!(
+        shape_sexp::<TestRec2>(),
+        "(Exp(Application(Exp(Record((foo(Exp(Base option((Exp(Rec_app 0())))))))))()))"
+    );
+    assert_eq!(
+        shape_sexp::<TestRec4>(),
+        "(Exp(Application(Exp(Variant((Empty())(Cons((Exp(Tuple((Exp(Base int()))\
+         (Exp(Rec_app 0()))))))))))()))"
+    );
+    assert_eq!(
+        shape_sexp::<TestRec3>(),
+        "(Exp(Application(Exp(Variant((Empty())(Cons((Exp(Base int()))(Exp(Rec_app 0())))))))()))"
+    );
+    assert_eq!(i64::binprot_shape().eval().to_string_hum(), "(Exp (Base int ()))");
+    let expected = [
+        "(Exp",
+        " (Variant",
+        "  ((Foo ()) (Bar ((Exp (Base int ()))))",
+        "   (Bar2 ((Exp (Base int ())) (Exp (Base float ()))))",
+        "   (Baz ((Exp (Record ((x (Exp (Base int ()))) (y (Exp (Base float ())))))))))))",
+    ];
+    assert_eq!(TestVariant2::binprot_shape().eval().to_string_hum(), expected.join("\n"));
+    let expected = [
+        "(Exp",
+        " (Application",
+        "  (Exp",
+        "   (Variant",
+        "    ((Leaf ())",
+        "     (Node",
+        "      ((Exp (Rec_app 0 ((Exp (Var 0))))) (Exp (Var 0))",
+        "       (Exp (Rec_app 0 ((Exp (Var 0))))))))))",
+        "  ((Exp (Base int ())))))",
+    ];
+    assert_eq!(Tree::<i64>::binprot_shape().eval().to_string_hum(), expected.join("\n"));
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+enum Expr {
+    Const(i64),
+    Block(Vec<Stmt>),
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+enum Stmt {
+    Expr(Expr),
+    Seq(Box<Stmt>, Box<Stmt>),
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+struct TestRecA {
+    b: TestRecB,
+    n: i64,
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+struct TestRecB {
+    a: Option<Box<TestRecA>>,
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+enum IntList {
+    Nil,
+    Cons(i64, Box<IntList>),
+}
+
+// Hand-written impls of recursive types rely on the default binprot_shape_loop.
+struct HandIntList;
+
+impl BinProtShape for HandIntList {
+    fn binprot_shape_impl(c: &mut binprot::ShapeContext) -> binprot::Shape {
+        let cons = vec![i64::binprot_shape_loop(c), HandIntList::binprot_shape_loop(c)];
+        binprot::Shape::Variant(vec![("Nil".into(), vec![]), ("Cons".into(), cons)])
+    }
+}
+
+#[test]
+fn test_mutually_recursive_shapes() {
+    assert_eq!(
+        format!("{:?}", Expr::binprot_shape()),
+        "Application(Variant([(\"Const\", [Base(Uuid(\"int\"), [])]), (\"Block\", \
+         [Base(Uuid(\"array\"), [Application(Variant([(\"Expr\", [RecApp(1, [])]), \
+         (\"Seq\", [RecApp(0, []), RecApp(0, [])])]), [])])])]), [])"
+    );
+    assert_digest::<Expr>("1d5ebe786aa762f02b13c7a427747403");
+    assert_digest::<Stmt>("048c5ffe79db27c4fe8f472142b3830e");
+    // TestRecB is not recursive on its own so it gets inlined.
+    assert_eq!(
+        format!("{:?}", TestRecA::binprot_shape()),
+        "Application(Record([(\"b\", Record([(\"a\", Base(Uuid(\"option\"), \
+         [RecApp(0, [])]))])), (\"n\", Base(Uuid(\"int\"), []))]), [])"
+    );
+    assert_digest::<TestRecA>("a8b89f6a78fd48aaab8b2d4fdf3e5331");
+    assert_digest::<TestRecB>("7f34cb94f613eda6abb3195c8ca29707");
+    // Only type definitions are tracked for recursion, not the builtin types.
+    assert_eq!(
+        Vec::<TestRec>::binprot_shape(),
+        binprot::Shape::Base("array".into(), vec![TestRec::binprot_shape()])
+    );
+    assert_eq!(HandIntList::binprot_shape(), IntList::binprot_shape());
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+struct Query<T> {
+    tag: String,
+    data: T,
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+struct QueryInt {
+    tag: String,
+    data: i64,
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+enum Tree<T> {
+    Leaf,
+    Node(Box<Tree<T>>, T, Box<Tree<T>>),
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+struct Forest<K, V> {
+    key: K,
+    trees: Vec<Tree<(K, V)>>,
+}
+
+trait Identity {
+    type Out;
+}
+
+impl<T> Identity for T {
+    type Out = T;
+}
+
+// Associated types are not replaced by type variables.
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+struct WithAssoc<T: Identity>
+where
+    T::Out: BinProtShape,
+{
+    assoc: T::Out,
+    qualified: Option<<T as Identity>::Out>,
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+struct WithAssocInt {
+    assoc: i64,
+    qualified: Option<i64>,
+}
+
+#[test]
+fn test_generic_shapes() {
+    // Non-recursive generic types are expanded.
+    assert_eq!(Query::<i64>::binprot_shape(), QueryInt::binprot_shape());
+    assert_digest::<Query<i64>>("6164456cb42b057709298f68f76326f0");
+    // Recursive generic types are applied to their parameters.
+    assert_eq!(
+        format!("{:?}", Tree::<i64>::binprot_shape()),
+        "Application(Variant([(\"Leaf\", []), (\"Node\", [RecApp(0, [Var(0)]), Var(0), \
+         RecApp(0, [Var(0)])])]), [Base(Uuid(\"int\"), [])])"
+    );
+    assert_digest::<Tree<i64>>("d583ea472c5d9382fc8fb52c43a673d8");
+    assert_digest::<Tree<String>>("e6d46ee14208f46caa11e3dc826cf7ef");
+    assert_digest::<Forest<i64, String>>("440090c35916c5f105f1173784117cf9");
+    assert_eq!(WithAssoc::<i64>::binprot_shape(), WithAssocInt::binprot_shape());
+}
+
 #[test]
 fn test_shapes() {
     assert_digest::<i64>("698cfa4093fe5e51523842d37b92aeac");
+    assert_digest::<u64>("698cfa4093fe5e51523842d37b92aeac");
+    assert_digest::<usize>("698cfa4093fe5e51523842d37b92aeac");
+    assert_digest::<isize>("698cfa4093fe5e51523842d37b92aeac");
     assert_digest::<f64>("1fd923acb2dd9c5d401ad5b08b1d40cd");
+    assert_digest::<binprot::Float32>("1fd923acb2dd9c5d401ad5b08b1d40cd");
+    assert_digest::<binprot::Char>("84610d32d63dcff5c93f1033ec8cb1d5");
     assert_digest::<String>("d9a8da25d5656b016fb4dbdc2e4197fb");
     assert_digest::<Test1>("43fa87a0bac7a0bb295f67cdc685aa26");
     assert_digest::<(Test1, Test1)>("d9aa33e00d47eb8eeb7f489b17d78d11");
     assert_digest::<(i64, Test1)>("4455e4c2995a2db383c16d4e99093686");
+    assert_digest::<[Test1; 2]>("d9aa33e00d47eb8eeb7f489b17d78d11");
     assert_digest::<Test2>("485a864ae3ab9d4e12534fd17f64a7c4");
     assert_digest::<Test3>("3a9e779c28768361e904e90f37728927");
     assert_digest::<Test4>("7a412f4ba96d992a85db1d498721b752");
     assert_digest::<Vec<i64>>("4c138035aa69ec9dd8b7a7119090f84a");
     assert_digest::<()>("86ba5df747eec837f0b391dd49f33f9e");
     assert_digest::<Option<i64>>("33fd4ff7bde530bddf13dfa739207fae");
+    assert_digest::<i32>("0892f5f3797659e9ecf8a0faa5f76829");
+    assert_digest::<u8>("698cfa4093fe5e51523842d37b92aeac");
+    assert_digest::<binprot::Nat0>("c31169f9cd00deb5282bfd187df70964");
+    assert_digest::<binprot::Bytes>("06c5811b990697b0a0c71e285a10e7d4");
+    assert_digest::<binprot::BufferWithLen>("e2d261c6c291b94bf6aa68ec2b08cb00");
+    assert_digest::<binprot::Vec32>("0ac181a0a1e9ca216f2b49ea245472f3");
+    assert_digest::<&str>("d9a8da25d5656b016fb4dbdc2e4197fb");
+    assert_digest::<&[i64]>("4c138035aa69ec9dd8b7a7119090f84a");
+    assert_digest::<binprot::WithLen<i64>>("7de9601befd614712151397f488a611a");
+    assert_digest::<std::collections::LinkedList<i64>>("4cd553520709511864846bda25c448d0");
+    assert_digest::<std::collections::BTreeSet<i64>>("3564446b0bfa871d8c3ebf31ab342fe7");
+    assert_digest::<std::collections::HashSet<i64>>("a6ab3112da0f561e1c0fab32a07fc914");
+    assert_digest::<std::collections::VecDeque<i64>>("8e2fb88d9c27340a0be6f26e9e23e0bb");
     assert_digest::<Result<i64, String>>("d90ddb29b1dc8ae4416867c01634f2de");
     assert_eq!(format!("{:?}", TestVariant::binprot_shape()), "Variant([(\"Foo\", [])])");
     assert_digest::<TestVariant>("81253431711eb0c9d669d0cf1c5ffea7");
@@ -129,8 +362,6 @@ fn test_shapes() {
     assert_digest::<TestPolyVariant2>("d82abba442a26f15f25d121e20b45083");
     assert_digest::<TestPolyVariant3>("534bd89034090512512955f635735d46");
     assert_digest::<TestPolyVariant4>("534bd89034090512512955f635735d46");
-    // Recursive types are not handled properly yet if there are multiple
-    // recursive types. However for a simple recursive type this works well.
     assert_digest::<TestRec>("4526f4c156fe4f6acde769fcb6262b23");
     assert_eq!(
         format!("{:?}", TestRec2::binprot_shape()),
@@ -139,4 +370,364 @@ fn test_shapes() {
     assert_digest::<TestRec2>("2e92d51efb901fcf492f243fc1c3601d");
     assert_digest::<TestRec4>("a0627068b62aa4530d1891cbe7f5d51e");
     assert_digest::<TestRec3>("2ac39052755cfe456342e727b104f34a");
+    assert_eq!(
+        TestWireTypes::binprot_shape(),
+        binprot::Shape::Record(vec![
+            ("a".into(), binprot::Nat0::binprot_shape()),
+            ("b".into(), binprot::Bytes::binprot_shape()),
+            ("c".into(), binprot::Shape::Base("with_len".into(), vec![i64::binprot_shape()])),
+            ("d".into(), binprot::BufferWithLen::binprot_shape()),
+            ("e".into(), <(i64, i64, i64, i64)>::binprot_shape()),
+            ("f".into(), binprot::Vec32::binprot_shape()),
+            ("g".into(), String::binprot_shape()),
+            ("h".into(), Vec::<i64>::binprot_shape()),
+        ])
+    );
+}
+
+mod v1 {
+    use binprot::macros::BinProtShape;
+
+    #[allow(dead_code)]
+    #[derive(BinProtShape)]
+    pub enum Side {
+        Buy,
+        Sell,
+    }
+
+    #[allow(dead_code)]
+    #[derive(BinProtShape)]
+    pub struct Order {
+        pub id: i64,
+        pub side: Side,
+        pub price: f64,
+        pub qty: i64,
+    }
+}
+
+mod v2 {
+    use binprot::macros::BinProtShape;
+
+    #[allow(dead_code)]
+    #[derive(BinProtShape)]
+    pub enum Side {
+        Bid,
+        Sell,
+        Short,
+    }
+
+    #[allow(dead_code)]
+    #[derive(BinProtShape)]
+    pub struct Order {
+        pub id: i32,
+        pub price: f64,
+        pub side: Side,
+        pub size: i64,
+        pub tag: String,
+    }
+
+    #[allow(dead_code)]
+    #[derive(BinProtShape)]
+    pub struct OrderRenamed {
+        pub order_id: i64,
+        pub side: (bool, bool),
+        pub price: f64,
+        pub qty: i64,
+    }
+}
+
+#[test]
+fn test_shape_diff() {
+    use binprot::{ShapeChange, ShapeChangeKind};
+    let diff = v1::Order::binprot_shape().diff(&v1::Order::binprot_shape());
+    assert!(diff.is_empty());
+    assert!(diff.wire_compatible);
+
+    let diff = v1::Order::binprot_shape().diff(&v2::Order::binprot_shape());
+    let changes: Vec<_> = diff.changes.iter().map(ShapeChange::to_string).collect();
+    assert_eq!(
+        changes,
+        [
+            "field tag added",
+            "field qty renamed to size",
+            "fields reordered",
+            "id: type changed from (Exp(Base int())) to (Exp(Base int32()))",
+            "side: constructor Short added",
+            "side: constructor Buy renamed to Bid",
+        ]
+    );
+    assert!(!diff.wire_compatible);
+
+    let diff = v1::Order::binprot_shape().diff(&v2::OrderRenamed::binprot_shape());
+    let field_renamed = |old: &str, new: &str| ShapeChangeKind::FieldRenamed {
+        old: old.to_string(),
+        new: new.to_string(),
+    };
+    assert_eq!(diff.changes[0].kind, field_renamed("id", "order_id"));
+    assert_eq!(diff.changes[1].path, ["side"]);
+    assert_eq!(diff.changes.len(), 2);
+    assert!(!diff.wire_compatible);
+
+    // Renaming fields and constructors or changing between equivalent
+    // containers does not change the encoding.
+    let diff = Test2::binprot_shape().diff(&<(i32, f64)>::binprot_shape());
+    assert!(diff.wire_compatible);
+    assert!(!diff.is_empty());
+    let diff =
+        <Vec<String>>::binprot_shape().diff(&<std::collections::BTreeSet<String>>::binprot_shape());
+    assert!(diff.wire_compatible);
+    let diff = <Vec<(i64, f64)>>::binprot_shape()
+        .diff(&<std::collections::BTreeMap<i64, f64>>::binprot_shape());
+    assert!(diff.wire_compatible);
+    let diff = <Vec<i64>>::binprot_shape().diff(&<Vec<f64>>::binprot_shape());
+    assert_eq!(diff.changes[0].path, ["0"]);
+    assert!(!diff.wire_compatible);
+
+    // Adding a length prefix changes the encoding.
+    let diff = <binprot::WithLen<i64>>::binprot_shape().diff(&i64::binprot_shape());
+    assert_eq!(diff.changes.len(), 1);
+    assert!(!diff.wire_compatible);
+}
+
+#[test]
+fn test_shape_serialization() {
+    use binprot::{BinProtRead, BinProtSize, BinProtWrite, Shape};
+    let shapes = [
+        i64::binprot_shape(),
+        TestVariant2::binprot_shape(),
+        TestPolyVariant3::binprot_shape(),
+        TestRec3::binprot_shape(),
+        Tree::<String>::binprot_shape(),
+        Expr::binprot_shape(),
+        Forest::<i64, String>::binprot_shape(),
+        Shape::Annotate("some-uuid".into(), Box::new(Shape::Var(0))),
+    ];
+    for shape in shapes.iter() {
+        let mut data = vec![];
+        shape.binprot_write(&mut data).unwrap();
+        assert_eq!(data.len(), shape.binprot_size());
+        let read = Shape::binprot_read(&mut data.as_slice()).unwrap();
+        assert_eq!(&read, shape);
+        for sexp in [shape.to_string_mach(), shape.to_string_hum()] {
+            let read: Shape = sexp.parse().unwrap();
+            assert_eq!(&read, shape);
+        }
+    }
+    // Line breaks, comments and quoted atoms are supported when parsing.
+    let sexp = "(Exp ; poly variant\n (Poly_variant\n  ((sorted ((A ((Exp (Base int ())))) (B ()) (\"C D\" ()))))))";
+    let shape: Shape = sexp.parse().unwrap();
+    assert_eq!(
+        shape.to_string_mach(),
+        "(Exp(Poly_variant((sorted((A((Exp(Base int()))))(B())(\"C D\"()))))))"
+    );
+    assert!("(Exp (Base int ())".parse::<Shape>().is_err());
+    assert!("(Exp (Base int ()) ())".parse::<Shape>().is_err());
+    assert!("(Exp (Rec_app x ()))".parse::<Shape>().is_err());
+    assert!("(Base int ())".parse::<Shape>().is_err());
+    // Deeply nested input is rejected rather than overflowing the stack.
+    let nested = format!("{}{}", "(".repeat(100_000), ")".repeat(100_000));
+    assert!(matches!(nested.parse::<Shape>(), Err(binprot::Error::InvalidShapeSexp(_))));
+    assert!(Shape::binprot_read(&mut [9u8].as_slice()).is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_shape_json() {
+    let shape = Tree::<String>::binprot_shape();
+    let json = serde_json::to_string(&shape).unwrap();
+    let read: binprot::Shape = serde_json::from_str(&json).unwrap();
+    assert_eq!(read, shape);
+    let json = serde_json::to_string(&Test2::binprot_shape()).unwrap();
+    assert_eq!(json, r#"{"Record":[["t",{"Base":["int",[]]}],["u",{"Base":["float",[]]}]]}"#);
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+#[binprot(opaque_uuid = "int")]
+struct OpaqueInt(i64);
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+#[binprot(opaque_uuid = "6a0e1a1e-5e94-4a2a-9d2c-6d5b0d36a3e1")]
+struct Timestamp {
+    nanos_since_epoch: i64,
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+#[binprot(opaque_uuid = "3d0f2c36-8a1e-4f65-a8b3-1c5ad3c0ee4f")]
+struct OpaqueQueue<T> {
+    elems: Vec<T>,
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+#[binprot(uuid = "f8c2b0ce-3a54-4bd8-9e31-2b2e25d2d7a4")]
+struct AnnotatedPair<T> {
+    fst: T,
+    snd: T,
+}
+
+// Hand-written impls can produce the same shapes.
+struct Span;
+
+impl BinProtShape for Span {
+    fn binprot_shape_impl(c: &mut binprot::ShapeContext) -> binprot::Shape {
+        binprot::Shape::annotate("8e48b10e-a5b0-4db0-9b8a-1a2b3c4d5e6f", f64::binprot_shape_loop(c))
+    }
+}
+
+#[test]
+fn test_annotated_shapes() {
+    use binprot::Shape;
+    // Opaque types only depend on their uuid.
+    assert_digest::<OpaqueInt>("698cfa4093fe5e51523842d37b92aeac");
+    assert_eq!(
+        Timestamp::binprot_shape(),
+        Shape::basetype("6a0e1a1e-5e94-4a2a-9d2c-6d5b0d36a3e1", vec![])
+    );
+    assert_eq!(
+        OpaqueQueue::<String>::binprot_shape().eval().to_string_mach(),
+        "(Exp(Base 3d0f2c36-8a1e-4f65-a8b3-1c5ad3c0ee4f((Exp(Base string())))))"
+    );
+    let int = i64::binprot_shape();
+    let pair = Shape::Record(vec![("fst".into(), int.clone()), ("snd".into(), int)]);
+    assert_eq!(
+        AnnotatedPair::<i64>::binprot_shape(),
+        Shape::annotate("f8c2b0ce-3a54-4bd8-9e31-2b2e25d2d7a4", pair)
+    );
+    assert_eq!(
+        Span::binprot_shape().eval().to_string_mach(),
+        "(Exp(Annotate 8e48b10e-a5b0-4db0-9b8a-1a2b3c4d5e6f(Exp(Base float()))))"
+    );
+    // The annotation is part of the digest.
+    assert_ne!(Span::binprot_shape().digest(), f64::binprot_shape().digest());
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+#[binprot(rename_all = "camelCase")]
+struct RenamedOrder {
+    order_id: i64,
+    #[binprot(name = "px")]
+    limit_price: f64,
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+#[binprot(rename_all = "snake_case")]
+enum RenamedSide {
+    BuyToCover {
+        order_id: i64,
+    },
+    #[binprot(name = "sell")]
+    SellShort,
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+#[polymorphic_variant]
+#[binprot(rename_all = "lowercase")]
+enum RenamedPoly {
+    Foo(i64),
+    #[binprot(name = "Bar")]
+    Baz,
+}
+
+#[test]
+fn test_renamed_shapes() {
+    use binprot::Shape;
+    let int = || i64::binprot_shape();
+    assert_eq!(
+        RenamedOrder::binprot_shape(),
+        Shape::Record(vec![("orderId".into(), int()), ("px".into(), f64::binprot_shape())])
+    );
+    // rename_all does not apply to the fields of inline records.
+    let record = Shape::Record(vec![("order_id".into(), int())]);
+    assert_eq!(
+        RenamedSide::binprot_shape(),
+        Shape::Variant(vec![("buy_to_cover".into(), vec![record]), ("sell".into(), vec![])])
+    );
+    let tags = vec![("foo".into(), Some(int())), ("Bar".into(), None)];
+    assert_eq!(RenamedPoly::binprot_shape(), Shape::PolyVariant(tags.into_iter().collect()));
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+#[polymorphic_variant]
+#[binprot(uuid = "5c2b8f0e-7d3a-4c1e-9b6f-2a4d8e0c1f3b")]
+enum AnnotatedDrink {
+    Coffee,
+    Tea,
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+#[polymorphic_variant]
+enum RecursiveDrink {
+    Water,
+    Refill(Box<RecursiveDrink>),
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+#[polymorphic_variant]
+enum Menu {
+    #[binprot(flatten)]
+    Annotated(AnnotatedDrink),
+    #[binprot(flatten)]
+    Recursive(RecursiveDrink),
+    Bread,
+}
+
+// Dessert inherits from AfterDinner which is still being defined when computing
+// the shape of AfterDinner.
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+#[polymorphic_variant]
+enum AfterDinner {
+    Digestif(Box<Dessert>),
+}
+
+#[allow(dead_code)]
+#[derive(BinProtShape)]
+#[polymorphic_variant]
+enum Dessert {
+    #[binprot(flatten)]
+    AfterDinner(AfterDinner),
+    Cake,
+}
+
+#[test]
+fn test_inherited_shapes() {
+    use binprot::Shape;
+    // Annotations and recursive definitions are expanded when inheriting.
+    let tags = vec![
+        ("Bread".into(), None),
+        ("Coffee".into(), None),
+        ("Tea".into(), None),
+        ("Water".into(), None),
+        ("Refill".into(), Some(RecursiveDrink::binprot_shape())),
+    ];
+    assert_eq!(Menu::binprot_shape(), Shape::PolyVariant(tags.into_iter().collect()));
+    assert!(matches!(RecursiveDrink::binprot_shape(), Shape::Application(_, _)));
+
+    let tags = vec![("Cake".into(), None), ("Digestif".into(), Some(Shape::RecApp(0, vec![])))];
+    let dessert =
+        Shape::Application(Box::new(Shape::PolyVariant(tags.into_iter().collect())), vec![]);
+    assert_eq!(Dessert::binprot_shape(), dessert);
+    let tags = vec![("Digestif".into(), Some(dessert))];
+    assert_eq!(AfterDinner::binprot_shape(), Shape::PolyVariant(tags.into_iter().collect()));
+
+    // Only polymorphic variants can be inherited.
+    for shape in [i64::binprot_shape(), Shape::RecApp(0, vec![])] {
+        let err = Shape::poly_variant(vec![], vec![shape]).unwrap_err();
+        assert!(matches!(err, binprot::Error::InheritedNonPolymorphicVariant(_)));
+    }
+
+    // Tags cannot appear twice, whether inherited or not.
+    let err = Shape::poly_variant(vec![("Cake".into(), None)], vec![Dessert::binprot_shape()]);
+    assert!(matches!(err, Err(binprot::Error::DuplicatePolyVariantTag(tag)) if tag == "Cake"));
+    let err = Shape::poly_variant(vec![("Tea".into(), None), ("Tea".into(), None)], vec![]);
+    assert!(matches!(err, Err(binprot::Error::DuplicatePolyVariantTag(tag)) if tag == "Tea"));
 }
@@ -0,0 +1,13 @@
+use binprot::macros::BinProtRead;
+
+fn zero() -> i64 {
+    0
+}
+
+#[derive(BinProtRead)]
+struct Order {
+    #[binprot(default = "zero")]
+    id: i64,
+}
+
+fn main() {}
@@ -0,0 +1,15 @@
+use binprot::macros::BinProtShape;
+
+#[derive(BinProtShape)]
+enum Drink {
+    Coffee,
+}
+
+#[derive(BinProtShape)]
+#[polymorphic_variant]
+enum Brunch {
+    #[binprot(flatten)]
+    Drink(Drink),
+}
+
+fn main() {}
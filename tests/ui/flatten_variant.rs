@@ -0,0 +1,21 @@
+use binprot::macros::BinProtWrite;
+
+#[derive(BinProtWrite)]
+enum Drink {
+    Coffee,
+}
+
+#[derive(BinProtWrite)]
+enum Brunch {
+    #[binprot(flatten)]
+    Drink(Drink),
+}
+
+#[derive(BinProtWrite)]
+#[polymorphic_variant]
+enum Lunch {
+    #[binprot(flatten)]
+    Drinks(Drink, Drink),
+}
+
+fn main() {}
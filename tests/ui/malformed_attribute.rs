@@ -0,0 +1,9 @@
+use binprot::macros::BinProtShape;
+
+#[derive(BinProtShape)]
+#[binprot(uuid = 42)]
+struct Order {
+    id: i64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use binprot::macros::BinProtShape;
+
+#[derive(BinProtShape)]
+enum Nested<T> {
+    Leaf(T),
+    Node(Box<Nested<(T, T)>>),
+}
+
+fn main() {}
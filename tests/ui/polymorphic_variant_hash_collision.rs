@@ -0,0 +1,18 @@
+use binprot::macros::BinProtWrite;
+
+#[derive(BinProtWrite)]
+#[polymorphic_variant]
+enum Side {
+    Dnctwrq,
+    Sbusnjd,
+}
+
+#[derive(BinProtWrite)]
+#[polymorphic_variant]
+enum Quote {
+    Bid,
+    #[binprot(name = "Bid")]
+    Offer,
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+use binprot::macros::BinProtShape;
+
+#[derive(BinProtShape)]
+#[polymorphic_variant]
+enum Drink {
+    Coffee,
+    Tea,
+}
+
+#[derive(BinProtShape)]
+#[polymorphic_variant]
+enum Breakfast {
+    #[binprot(flatten)]
+    Drink(Drink),
+    Tea,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use binprot::macros::BinProtWrite;
+
+#[derive(BinProtWrite)]
+#[polymorphic_variant]
+enum Event {
+    Tick,
+    Trade { price: f64, size: i64 },
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use binprot::macros::BinProtRead;
+
+#[derive(BinProtRead)]
+#[polymorphic_variant]
+struct Order {
+    id: i64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use binprot::macros::BinProtWrite;
+
+#[derive(BinProtWrite)]
+union Number {
+    i: i64,
+    f: f64,
+}
+
+fn main() {}
@@ -0,0 +1,10 @@
+use binprot::macros::BinProtWrite;
+
+#[derive(BinProtWrite)]
+struct Order {
+    id: i64,
+    #[binprot(skipped)]
+    cached: f64,
+}
+
+fn main() {}
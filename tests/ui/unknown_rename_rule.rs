@@ -0,0 +1,9 @@
+use binprot::macros::BinProtWrite;
+
+#[derive(BinProtWrite)]
+#[binprot(rename_all = "kebab-case")]
+struct Order {
+    order_id: i64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use binprot::macros::BinProtShape;
+
+#[derive(BinProtShape)]
+#[binprot(uuid = "a", opaque_uuid = "b")]
+struct Order {
+    id: i64,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use binprot::macros::BinProtWrite;
+
+#[derive(BinProtWrite)]
+enum Event {
+    #[binprot(with = "codec")]
+    Trade(f64, i64),
+}
+
+fn main() {}
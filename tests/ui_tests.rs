@@ -0,0 +1,6 @@
+// Checks the errors reported by the derive macros on unsupported input.
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/ui/*.rs");
+}
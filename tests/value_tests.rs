@@ -0,0 +1,198 @@
+use binprot::macros::{BinProtRead, BinProtShape, BinProtSize, BinProtWrite};
+use binprot::{BinProtShape, BinProtWrite, Value};
+use std::collections::BTreeMap;
+
+#[derive(BinProtRead, BinProtWrite, BinProtSize, BinProtShape, Debug, PartialEq)]
+enum Side {
+    Buy,
+    Sell,
+}
+
+#[derive(BinProtRead, BinProtWrite, BinProtSize, BinProtShape, Debug, PartialEq)]
+#[polymorphic_variant]
+enum Status {
+    Open,
+    Filled(i64),
+}
+
+#[derive(BinProtRead, BinProtWrite, BinProtSize, BinProtShape, Debug, PartialEq)]
+struct Order {
+    id: i64,
+    side: Side,
+    price: f64,
+    symbol: String,
+    status: Status,
+    tags: Vec<(String, bool)>,
+    parent: Option<i64>,
+    fills: BTreeMap<i64, f64>,
+}
+
+#[derive(BinProtRead, BinProtWrite, BinProtSize, BinProtShape, Debug, PartialEq)]
+enum Tree<T> {
+    Leaf,
+    Node(Box<Tree<T>>, T, Box<Tree<T>>),
+}
+
+#[derive(BinProtRead, BinProtWrite, BinProtSize, BinProtShape, Debug, PartialEq)]
+enum Chain {
+    End,
+    Link(Box<Chain>),
+}
+
+fn order() -> Order {
+    Order {
+        id: 42,
+        side: Side::Sell,
+        price: 123.5,
+        symbol: "AAPL".to_string(),
+        status: Status::Filled(100),
+        tags: vec![("urgent".to_string(), true)],
+        parent: None,
+        fills: vec![(1, 123.5), (2, 124.0)].into_iter().collect(),
+    }
+}
+
+#[test]
+fn decode_with_shape() {
+    let mut data = vec![];
+    order().binprot_write(&mut data).unwrap();
+    // Decode using the shape only.
+    let shape = Order::binprot_shape();
+    let mut slice = data.as_slice();
+    let value = Value::binprot_read_with_shape(&mut slice, &shape).unwrap();
+    assert!(slice.is_empty());
+    let field = |name: &str, v: Value| (name.to_string(), v);
+    let expected = Value::Record(vec![
+        field("id", Value::Int(42)),
+        field("side", Value::Variant("Sell".to_string(), vec![])),
+        field("price", Value::Float(123.5)),
+        field("symbol", Value::String("AAPL".to_string())),
+        field("status", Value::PolyVariant("Filled".to_string(), Some(Box::new(Value::Int(100))))),
+        field(
+            "tags",
+            Value::List(vec![Value::Tuple(vec![
+                Value::String("urgent".to_string()),
+                Value::Bool(true),
+            ])]),
+        ),
+        field("parent", Value::Option(None)),
+        field(
+            "fills",
+            Value::Map(vec![
+                (Value::Int(1), Value::Float(123.5)),
+                (Value::Int(2), Value::Float(124.0)),
+            ]),
+        ),
+    ]);
+    assert_eq!(value, expected);
+    // Encoding the value back results in the same bytes.
+    let mut data2 = vec![];
+    value.binprot_write_with_shape(&mut data2, &shape).unwrap();
+    assert_eq!(data, data2);
+    assert_eq!(binprot::from_value::<Order>(&value).unwrap(), order());
+}
+
+#[test]
+fn recursive_values() {
+    let leaf = || Box::new(Tree::Leaf);
+    let tree =
+        Tree::Node(Box::new(Tree::Node(leaf(), "a".to_string(), leaf())), "b".to_string(), leaf());
+    let value = binprot::to_value(&tree).unwrap();
+    let leaf = || Value::Variant("Leaf".to_string(), vec![]);
+    let node = |l, v: &str, r| {
+        Value::Variant("Node".to_string(), vec![l, Value::String(v.to_string()), r])
+    };
+    assert_eq!(value, node(node(leaf(), "a", leaf()), "b", leaf()));
+    assert_eq!(binprot::from_value::<Tree<String>>(&value).unwrap(), tree);
+}
+
+#[test]
+fn with_len_values() {
+    let v = binprot::WithLen((7i64, "abc".to_string()));
+    let value = binprot::to_value(&v).unwrap();
+    assert_eq!(value, Value::Tuple(vec![Value::Int(7), Value::String("abc".to_string())]));
+    let mut data = vec![];
+    v.binprot_write(&mut data).unwrap();
+    assert_eq!(data, [5, 7, 3, 97, 98, 99]);
+    let shape = <binprot::WithLen<(i64, String)>>::binprot_shape();
+    let mut data2 = vec![];
+    value.binprot_write_with_shape(&mut data2, &shape).unwrap();
+    assert_eq!(data, data2);
+    // The payload has to be fully consumed.
+    let shape = <binprot::WithLen<i64>>::binprot_shape();
+    assert!(matches!(
+        Value::binprot_read_with_shape(&mut &[2u8, 7, 0][..], &shape),
+        Err(binprot::Error::PayloadNotFullyConsumed { len: 2, remaining: 1 })
+    ));
+}
+
+#[test]
+fn nested_values() {
+    let shape = Chain::binprot_shape();
+    let chain = |depth: usize| {
+        let mut data = vec![1u8; depth];
+        data.push(0);
+        data
+    };
+    let value = Value::binprot_read_with_shape(&mut chain(200).as_slice(), &shape).unwrap();
+    let mut data = vec![];
+    value.binprot_write_with_shape(&mut data, &shape).unwrap();
+    assert_eq!(data, chain(200));
+    // Deeper values are rejected rather than overflowing the stack.
+    let err = Value::binprot_read_with_shape(&mut chain(100_000).as_slice(), &shape);
+    assert!(matches!(err, Err(binprot::Error::NestingTooDeep)));
+    let mut value = Value::Variant("End".to_string(), vec![]);
+    for _i in 0..2000 {
+        value = Value::Variant("Link".to_string(), vec![value])
+    }
+    assert!(matches!(binprot::from_value::<Chain>(&value), Err(binprot::Error::NestingTooDeep)));
+}
+
+#[test]
+fn value_errors() {
+    let shape = Order::binprot_shape();
+    // Truncated input.
+    let mut data = vec![];
+    order().binprot_write(&mut data).unwrap();
+    assert!(Value::binprot_read_with_shape(&mut &data[..10], &shape).is_err());
+    // Values that do not match the shape.
+    assert!(binprot::from_value::<Order>(&Value::Int(42)).is_err());
+    assert!(binprot::from_value::<Side>(&Value::Variant("Hold".to_string(), vec![])).is_err());
+    assert!(binprot::from_value::<Status>(&Value::PolyVariant("Open".to_string(), None)).is_ok());
+    // Unknown base types.
+    let shape = binprot::Shape::Base("unknown".into(), vec![]);
+    assert!(matches!(
+        Value::binprot_read_with_shape(&mut &data[..], &shape),
+        Err(binprot::Error::UnsupportedShape(_))
+    ));
+    // Recursive references that are not bound by a definition.
+    let shape = binprot::Shape::RecApp(0, vec![]);
+    assert!(Value::binprot_read_with_shape(&mut &data[..], &shape).is_err());
+    let shape = binprot::Shape::Application(Box::new(shape), vec![]);
+    assert!(Value::binprot_read_with_shape(&mut &data[..], &shape).is_err());
+}
+
+#[test]
+fn runtime_shapes() {
+    use binprot::{Shape, Uuid};
+    // Shapes built from owned names, e.g. loaded from a schema file, have the
+    // same digest and encoding as the derived ones.
+    let names: Vec<String> = vec!["Buy".to_string(), "Sell".to_string()];
+    let side = Shape::Variant(names.into_iter().map(|n| (n.into(), vec![])).collect());
+    assert_eq!(side, Side::binprot_shape());
+    let base = |name: &str| Shape::Base(Uuid::new(name.to_string()), vec![]);
+    let shape = Shape::Record(vec![
+        ("side".to_string().into(), side),
+        ("price".to_string().into(), base("float")),
+    ]);
+    let mut data = vec![];
+    Side::Sell.binprot_write(&mut data).unwrap();
+    1.5f64.binprot_write(&mut data).unwrap();
+    let value = Value::binprot_read_with_shape(&mut data.as_slice(), &shape).unwrap();
+    let expected = Value::Record(vec![
+        ("side".to_string(), Value::Variant("Sell".to_string(), vec![])),
+        ("price".to_string(), Value::Float(1.5)),
+    ]);
+    assert_eq!(value, expected);
+    assert_eq!(Uuid::new("float").as_str(), "float");
+}